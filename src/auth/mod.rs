@@ -0,0 +1,104 @@
+//! Authentication of incoming connections.
+//!
+//! Anything that can reach the port of a [`Listener`](crate::ucp::listener::Listener)
+//! can connect to it. With an [`Authenticator`], the accepting side runs an
+//! exchange with the peer right after creating the endpoint, and closes the
+//! endpoint if the peer fails it, before the endpoint is handed to any other
+//! code. [`accept`] and [`connect`] create endpoints this way; the RPC
+//! [`Server`](crate::rpc::Server) does it for every connection once it is
+//! given an authenticator, running the [`Exchange`]s of all its new clients
+//! side by side.
+//!
+//! The accepting side opens the exchange with the setup message of the
+//! [`AUTH`](crate::tag::AUTH) channel, on the stream of the endpoint, which
+//! only its peer receives; it comes before those of the other layers, see
+//! [`tag`](crate::tag).
+//!
+//! `PreSharedKey`, behind the `auth` cargo feature, is an HMAC-SHA256
+//! challenge/response in both directions.
+
+use crate::ucp::endpoint::Endpoint;
+use crate::ucp::listener::ConnectionRequest;
+use crate::ucp::Worker;
+use crate::Error;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::task::Poll;
+use std::time::Instant;
+use tracing::{debug, warn};
+
+#[cfg(feature = "auth")]
+mod psk;
+
+#[cfg(feature = "auth")]
+pub use psk::PreSharedKey;
+
+/// Decides whether the peer of a new endpoint may use it.
+///
+/// Both sides of a connection must use the same kind of authenticator. The
+/// exchange fails with [`Error::Authentication`] if the peer is not let in,
+/// or with [`Error::Timeout`] if it has not finished by the deadline.
+pub trait Authenticator {
+    /// Starts the exchange on the side that accepted the connection, sending
+    /// its first message; the sends of the exchange give up at `deadline`.
+    fn start(&self, ep: &Endpoint, deadline: Option<Instant>) -> Result<Box<dyn Exchange>, Error>;
+
+    /// Runs the exchange on the side that connected.
+    fn connect(&self, ep: &Endpoint, deadline: Option<Instant>) -> Result<(), Error>;
+
+    /// Runs the exchange on the side that accepted the connection, blocking
+    /// until it is over.
+    fn accept(&self, ep: &Endpoint, deadline: Option<Instant>) -> Result<(), Error> {
+        let mut exchange = self.start(ep, deadline)?;
+        loop {
+            if let Poll::Ready(result) = exchange.poll(ep) {
+                return result;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(Error::Timeout);
+            }
+            ep.worker.progress();
+        }
+    }
+}
+
+/// An exchange in progress on the accepting side, see [`Authenticator::start`].
+pub trait Exchange {
+    /// Goes on with the exchange as far as the messages of the peer that have
+    /// arrived allow, without waiting for more.
+    fn poll(&mut self, ep: &Endpoint) -> Poll<Result<(), Error>>;
+}
+
+/// Creates an endpoint for `conn_req` and authenticates its peer, closing
+/// the endpoint if that fails.
+pub fn accept(
+    worker: Rc<Worker>,
+    conn_req: ConnectionRequest,
+    authenticator: &dyn Authenticator,
+    deadline: Option<Instant>,
+) -> Result<Endpoint, Error> {
+    let peer_addr = conn_req.client_addr().ok();
+    let ep = unsafe { Endpoint::from_conn_req(worker, conn_req)? };
+    match authenticator.accept(&ep, deadline) {
+        Ok(()) => {
+            debug!(?peer_addr, "peer authenticated");
+            Ok(ep)
+        }
+        Err(e) => {
+            warn!(?peer_addr, "closing unauthenticated endpoint: {e}");
+            Err(e)
+        }
+    }
+}
+
+/// Connects to `addr` and authenticates with the peer.
+pub fn connect(
+    worker: Rc<Worker>,
+    addr: SocketAddr,
+    authenticator: &dyn Authenticator,
+    deadline: Option<Instant>,
+) -> Result<Endpoint, Error> {
+    let ep = unsafe { Endpoint::from_sockaddr(worker, addr)? };
+    authenticator.connect(&ep, deadline)?;
+    Ok(ep)
+}
@@ -0,0 +1,176 @@
+//! HMAC-SHA256 challenge/response with a pre-shared key.
+//!
+//! The setup message of the accepting side carries an inbox id and a random
+//! challenge. The rest of the exchange travels on the [`AUTH`] tag channel,
+//! to inbox ids that only the two sides know: the response of the connecting
+//! side, with an inbox and a challenge of its own, then the verdict.
+
+use super::{Authenticator, Exchange};
+use crate::tag::{self, AUTH};
+use crate::ucp::endpoint::Endpoint;
+use crate::Error;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::task::Poll;
+use std::time::Instant;
+
+const RESPONSE: u32 = 1;
+const VERDICT: u32 = 2;
+
+const CHALLENGE_LEN: usize = 32;
+const MAC_LEN: usize = 32;
+// The inbox and challenge of each side.
+const SETUP_LEN: usize = 4 + CHALLENGE_LEN;
+const RESPONSE_LEN: usize = SETUP_LEN + MAC_LEN;
+
+const ACCEPTED: u8 = 1;
+const REJECTED: u8 = 0;
+
+/// Mutual HMAC-SHA256 challenge/response with a key both sides know.
+///
+/// The key itself never travels: each side proves it knows the key by
+/// signing the random challenge of the other, so a recorded exchange cannot
+/// be replayed. Messages sent after the exchange are neither signed nor
+/// encrypted.
+#[derive(Clone)]
+pub struct PreSharedKey {
+    key: Vec<u8>,
+}
+
+impl std::fmt::Debug for PreSharedKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PreSharedKey").finish_non_exhaustive()
+    }
+}
+
+impl PreSharedKey {
+    /// Authenticates with `key`, which should have at least 32 random bytes.
+    pub fn new(key: impl Into<Vec<u8>>) -> Self {
+        PreSharedKey { key: key.into() }
+    }
+
+    // The MAC of `role` followed by the challenges.
+    fn sign(&self, role: &[u8], first: &[u8], second: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(role);
+        mac.update(first);
+        mac.update(second);
+        mac
+    }
+}
+
+impl Authenticator for PreSharedKey {
+    fn start(&self, ep: &Endpoint, deadline: Option<Instant>) -> Result<Box<dyn Exchange>, Error> {
+        let inbox = random_inbox()?;
+        let challenge = crate::random_bytes::<CHALLENGE_LEN>()?;
+        let mut message = inbox.to_le_bytes().to_vec();
+        message.extend_from_slice(&challenge);
+        tag::send_setup(ep, AUTH, &message, deadline)?;
+        Ok(Box::new(Challenge {
+            key: self.clone(),
+            inbox,
+            challenge,
+            deadline,
+        }))
+    }
+
+    fn connect(&self, ep: &Endpoint, deadline: Option<Instant>) -> Result<(), Error> {
+        let message: [u8; SETUP_LEN] = tag::recv_setup(ep, AUTH, deadline)?;
+        let (peer_inbox, peer_challenge) = split_inbox(&message)?;
+        let inbox = random_inbox()?;
+        let challenge = crate::random_bytes::<CHALLENGE_LEN>()?;
+        let mac = self
+            .sign(b"ucx-client", peer_challenge, &challenge)
+            .finalize();
+        let mut message = inbox.to_le_bytes().to_vec();
+        message.extend_from_slice(&challenge);
+        message.extend_from_slice(&mac.into_bytes());
+        ep.send_bytes_until(AUTH.tag(peer_inbox, RESPONSE), &message, deadline)?;
+
+        let (_, verdict) = ep.recv_bytes_until(AUTH.tag(inbox, VERDICT), u64::MAX, deadline)?;
+        match verdict.split_first() {
+            Some((&ACCEPTED, mac)) if mac.len() == MAC_LEN => self
+                .sign(b"ucx-server", &challenge, peer_challenge)
+                .verify_slice(mac)
+                .map_err(|_| Error::Authentication("the peer does not know the key".to_string())),
+            Some((&REJECTED, _)) => Err(Error::Authentication("rejected by the peer".to_string())),
+            _ => Err(truncated("verdict")),
+        }
+    }
+}
+
+// The accepting side, waiting for the response to its challenge.
+struct Challenge {
+    key: PreSharedKey,
+    inbox: u32,
+    challenge: [u8; CHALLENGE_LEN],
+    deadline: Option<Instant>,
+}
+
+impl Exchange for Challenge {
+    fn poll(&mut self, ep: &Endpoint) -> Poll<Result<(), Error>> {
+        match ep
+            .worker
+            .tag_probe(AUTH.tag(self.inbox, RESPONSE), u64::MAX)
+        {
+            Some(message) if message.length != RESPONSE_LEN => {
+                // Taken off the queue all the same; the peer is not let in.
+                let _ = ep.worker.recv_probed_until(message, self.deadline);
+                Poll::Ready(Err(truncated("response")))
+            }
+            Some(message) => Poll::Ready(
+                ep.worker
+                    .recv_probed_until(message, self.deadline)
+                    .and_then(|response| self.verify(ep, &response)),
+            ),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Challenge {
+    // Checks the response of the peer and sends it the verdict.
+    fn verify(&self, ep: &Endpoint, response: &[u8]) -> Result<(), Error> {
+        let (peer_inbox, rest) = split_inbox(response)?;
+        let (peer_challenge, mac) = rest.split_at(CHALLENGE_LEN);
+        let verdict = AUTH.tag(peer_inbox, VERDICT);
+        if self
+            .key
+            .sign(b"ucx-client", &self.challenge, peer_challenge)
+            .verify_slice(mac)
+            .is_err()
+        {
+            // Telling the peer spares it waiting for the deadline.
+            let _ = ep.send_bytes_until(verdict, &[REJECTED], self.deadline);
+            return Err(Error::Authentication(
+                "the peer does not know the key".to_string(),
+            ));
+        }
+        let mac = self
+            .key
+            .sign(b"ucx-server", peer_challenge, &self.challenge)
+            .finalize();
+        let mut message = vec![ACCEPTED];
+        message.extend_from_slice(&mac.into_bytes());
+        ep.send_bytes_until(verdict, &message, self.deadline)
+    }
+}
+
+// Inboxes are random so that another peer of the worker cannot guess them and
+// send a response or verdict in place of the one being authenticated.
+fn random_inbox() -> Result<u32, Error> {
+    let inbox = u32::from_le_bytes(crate::random_bytes::<4>()?);
+    Ok(inbox & tag::SOURCE.max() as u32)
+}
+
+fn split_inbox(message: &[u8]) -> Result<(u32, &[u8]), Error> {
+    let (inbox, rest) = message
+        .split_first_chunk::<4>()
+        .ok_or_else(|| truncated("message"))?;
+    Ok((u32::from_le_bytes(*inbox), rest))
+}
+
+fn truncated(what: &str) -> Error {
+    Error::Authentication(format!("malformed {what}"))
+}
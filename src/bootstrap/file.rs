@@ -0,0 +1,192 @@
+//! Address exchange through a shared directory.
+//!
+//! For jobs whose processes run on one machine, or share a file system, every
+//! process writes its worker address to `rank-<rank>.addr` in a directory and
+//! waits until the files of all ranks are there. Files are written to a
+//! temporary name and renamed, so a file that exists is complete.
+//!
+//! A file is the magic and format version, the size of the job, the address
+//! and the metadata of the process. The directory should be fresh for every
+//! job: files left over by an earlier job of the same size are not told apart.
+
+use super::{check_header, header, io_error, put_bytes, Peer, Peers, Reader, DEFAULT_TIMEOUT};
+use crate::rpc::Metadata;
+use crate::ucp::Worker;
+use crate::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Environment variables holding the rank of the process, in order of preference.
+pub const RANK_VARS: [&str; 5] = [
+    "UCX_RPC_RANK",
+    "PMI_RANK",
+    "PMIX_RANK",
+    "OMPI_COMM_WORLD_RANK",
+    "SLURM_PROCID",
+];
+
+/// Environment variables holding the size of the job, in order of preference.
+pub const SIZE_VARS: [&str; 4] = [
+    "UCX_RPC_SIZE",
+    "PMI_SIZE",
+    "OMPI_COMM_WORLD_SIZE",
+    "SLURM_NTASKS",
+];
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Reads the rank and size of the process from the variables set by the launcher.
+///
+/// See [`RANK_VARS`] and [`SIZE_VARS`]. Fails with [`Error::InvalidParam`] if
+/// either is missing or not a number, or the rank is not below the size.
+pub fn rank_from_env() -> Result<(u32, u32), Error> {
+    let rank = env_u32(&RANK_VARS)?;
+    let size = env_u32(&SIZE_VARS)?;
+    if rank >= size {
+        return Err(Error::InvalidParam);
+    }
+    Ok((rank, size))
+}
+
+fn env_u32(vars: &[&str]) -> Result<u32, Error> {
+    let (var, value) = vars
+        .iter()
+        .find_map(|&var| std::env::var(var).ok().map(|value| (var, value)))
+        .ok_or(Error::InvalidParam)?;
+    value.trim().parse().map_err(|_| {
+        debug!("bootstrap: {var}={value:?} is not a number");
+        Error::InvalidParam
+    })
+}
+
+/// Joins a job by publishing the address to a shared directory.
+#[derive(Debug, Clone)]
+pub struct FileBootstrap {
+    dir: PathBuf,
+    rank: u32,
+    size: u32,
+    metadata: Metadata,
+    timeout: Duration,
+}
+
+impl FileBootstrap {
+    /// Joins as `rank` of `size` processes, waiting up to a minute for the others.
+    pub fn new(dir: impl Into<PathBuf>, rank: u32, size: u32) -> Self {
+        FileBootstrap {
+            dir: dir.into(),
+            rank,
+            size,
+            metadata: Metadata::new(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Joins with the rank and size given by the environment, see [`rank_from_env`].
+    pub fn from_env(dir: impl Into<PathBuf>) -> Result<Self, Error> {
+        let (rank, size) = rank_from_env()?;
+        Ok(Self::new(dir, rank, size))
+    }
+
+    /// Publishes `metadata` to the other processes alongside the address.
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// How long to wait for all processes to publish their address.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Publishes the address of `worker` and returns the table of the job.
+    pub fn exchange(&self, worker: &Worker) -> Result<Peers, Error> {
+        let address = worker.address()?;
+        self.exchange_address(address.as_ref())
+    }
+
+    /// Publishes `address` and returns the table of the job.
+    ///
+    /// Fails with [`Error::Timeout`] if not all ranks published their address in time.
+    pub fn exchange_address(&self, address: &[u8]) -> Result<Peers, Error> {
+        if self.rank >= self.size {
+            return Err(Error::InvalidParam);
+        }
+        let deadline = Instant::now() + self.timeout;
+        fs::create_dir_all(&self.dir).map_err(io_error)?;
+        self.publish(address)?;
+
+        let mut peers: Vec<Option<Peer>> = vec![None; self.size as usize];
+        loop {
+            for (rank, peer) in (0..self.size).zip(peers.iter_mut()) {
+                if peer.is_none() {
+                    *peer = self.read(rank)?;
+                }
+            }
+            if peers.iter().all(Option::is_some) {
+                break;
+            }
+            if Instant::now() >= deadline {
+                let missing: Vec<u32> = (0..self.size)
+                    .filter(|&rank| peers[rank as usize].is_none())
+                    .collect();
+                debug!("bootstrap: timed out waiting for ranks {missing:?}");
+                return Err(Error::Timeout);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        debug!("bootstrap: joined as rank {} of {}", self.rank, self.size);
+        Ok(Peers::new(self.rank, peers.into_iter().flatten().collect()))
+    }
+
+    fn path(&self, rank: u32) -> PathBuf {
+        self.dir.join(format!("rank-{rank}.addr"))
+    }
+
+    fn publish(&self, address: &[u8]) -> Result<(), Error> {
+        let mut buf = header();
+        buf.extend_from_slice(&self.size.to_le_bytes());
+        put_bytes(&mut buf, address)?;
+        self.metadata.encode_into(&mut buf)?;
+        let tmp = self.dir.join(format!(
+            ".rank-{}.addr.{}.tmp",
+            self.rank,
+            std::process::id()
+        ));
+        write_atomic(&tmp, &self.path(self.rank), &buf).map_err(io_error)
+    }
+
+    // The peer of `rank`, or none if it has not published its address yet.
+    fn read(&self, rank: u32) -> Result<Option<Peer>, Error> {
+        let bytes = match fs::read(self.path(rank)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(io_error(e)),
+        };
+        let mut reader = Reader(&bytes);
+        check_header(&mut reader)?;
+        let size = reader.u32()?;
+        if size != self.size {
+            return Err(Error::Bootstrap(format!(
+                "rank {rank} joined a job of size {size}, expected {}",
+                self.size
+            )));
+        }
+        Ok(Some(Peer {
+            rank,
+            address: reader.bytes()?.to_vec(),
+            metadata: reader.metadata()?,
+        }))
+    }
+}
+
+fn write_atomic(tmp: &Path, path: &Path, bytes: &[u8]) -> io::Result<()> {
+    fs::write(tmp, bytes)?;
+    fs::rename(tmp, path).inspect_err(|_| {
+        let _ = fs::remove_file(tmp);
+    })
+}
@@ -0,0 +1,165 @@
+//! Out-of-band exchange of worker addresses.
+//!
+//! Endpoints created from a [`WorkerAddress`](crate::ucp::WorkerAddress) need
+//! the address of the remote worker, which has to reach the process some other
+//! way. A bootstrap collects the address of every process of a job, assigns
+//! each a rank, and hands every process the resulting [`Peers`] table, from
+//! which endpoints to the other ranks are created.
+//!
+//! [`tcp`] exchanges the addresses through a rendezvous server, [`file`]
+//! through a directory shared by processes on one machine.
+
+pub mod file;
+pub mod tcp;
+
+pub use self::file::{rank_from_env, FileBootstrap};
+pub use self::tcp::{RendezvousServer, TcpBootstrap};
+
+use crate::rpc::Metadata;
+use crate::ucp::endpoint::Endpoint;
+use crate::ucp::Worker;
+use crate::Error;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A process taking part in the job.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    /// The rank of the process.
+    pub rank: u32,
+    /// The address of its worker.
+    pub address: Vec<u8>,
+    /// What the process published alongside its address, e.g. its host name.
+    pub metadata: Metadata,
+}
+
+impl Peer {
+    /// Creates an endpoint to the worker of the peer.
+    pub fn connect(&self, worker: Rc<Worker>) -> Result<Endpoint, Error> {
+        // SAFETY: the address was published by the peer, from `Worker::address`.
+        unsafe { Endpoint::from_worker_address(worker, &self.address) }
+    }
+}
+
+/// The result of a bootstrap: the rank of this process and every peer, by rank.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peers {
+    rank: u32,
+    peers: Vec<Peer>,
+}
+
+impl Peers {
+    pub(crate) fn new(rank: u32, peers: Vec<Peer>) -> Self {
+        debug_assert!(peers
+            .iter()
+            .enumerate()
+            .all(|(i, peer)| peer.rank == i as u32));
+        Peers { rank, peers }
+    }
+
+    /// The rank of this process.
+    pub fn rank(&self) -> u32 {
+        self.rank
+    }
+
+    /// The number of processes.
+    pub fn size(&self) -> u32 {
+        self.peers.len() as u32
+    }
+
+    /// The peer with the given rank.
+    pub fn get(&self, rank: u32) -> Option<&Peer> {
+        self.peers.get(rank as usize)
+    }
+
+    /// All peers, including this process, indexed by rank.
+    pub fn peers(&self) -> &[Peer] {
+        &self.peers
+    }
+
+    /// Creates an endpoint to every other rank.
+    ///
+    /// The result is indexed by rank and has no endpoint at the rank of this process.
+    pub fn connect_all(&self, worker: &Rc<Worker>) -> Result<Vec<Option<Endpoint>>, Error> {
+        self.peers
+            .iter()
+            .map(|peer| {
+                if peer.rank == self.rank {
+                    Ok(None)
+                } else {
+                    peer.connect(worker.clone()).map(Some)
+                }
+            })
+            .collect()
+    }
+}
+
+pub(crate) fn io_error(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Error::Timeout,
+        _ => Error::Bootstrap(e.to_string()),
+    }
+}
+
+// Little-endian encoding of the bootstrap messages, which start with a
+// magic and the format version.
+
+const MAGIC: &[u8; 4] = b"UCXB";
+const VERSION: u32 = 1;
+
+pub(crate) fn header() -> Vec<u8> {
+    let mut buf = MAGIC.to_vec();
+    buf.extend_from_slice(&VERSION.to_le_bytes());
+    buf
+}
+
+pub(crate) fn check_header(reader: &mut Reader) -> Result<(), Error> {
+    if reader.take_slice(4)? != MAGIC {
+        return Err(Error::Bootstrap("not a bootstrap message".to_string()));
+    }
+    match reader.u32()? {
+        VERSION => Ok(()),
+        version => Err(Error::Bootstrap(format!(
+            "unsupported bootstrap version {version}"
+        ))),
+    }
+}
+
+pub(crate) fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) -> Result<(), Error> {
+    let len =
+        u32::try_from(bytes.len()).map_err(|_| Error::Bootstrap("field too large".to_string()))?;
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(crate) fn take_slice(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < len {
+            return Err(Error::Bootstrap("truncated message".to_string()));
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take_slice(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u32()? as usize;
+        self.take_slice(len)
+    }
+
+    pub(crate) fn metadata(&mut self) -> Result<Metadata, Error> {
+        let (metadata, rest) = Metadata::decode(self.0)?;
+        self.0 = rest;
+        Ok(metadata)
+    }
+}
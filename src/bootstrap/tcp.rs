@@ -0,0 +1,329 @@
+//! Address exchange through a TCP rendezvous server.
+//!
+//! One process, or a separate launcher, runs a [`RendezvousServer`] for a job
+//! of `size` processes. Every process connects to it with a [`TcpBootstrap`]
+//! and sends its worker address, optionally asking for a rank. Once all
+//! processes have joined, the server assigns the remaining ranks in the order
+//! the processes joined and sends every process the full table.
+//!
+//! Messages are a little-endian `u32` length followed by the body. A hello is
+//! the magic and protocol version, the requested rank (`u32::MAX` for any),
+//! the address and the metadata of the process. The reply is the magic, the
+//! version and a status: `0` followed by the assigned rank, the size and the
+//! address and metadata of every rank, or `1` followed by an error message.
+
+use super::{check_header, header, io_error, put_bytes, Peer, Peers, Reader, DEFAULT_TIMEOUT};
+use crate::rpc::Metadata;
+use crate::ucp::Worker;
+use crate::Error;
+use socket2::{Domain, Socket, Type};
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+const ANY_RANK: u32 = u32::MAX;
+// Bounds the allocation for a message from a misbehaving peer.
+const MAX_MESSAGE: u32 = 64 << 20;
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Collects the addresses of a job and sends every process the table.
+#[derive(Debug)]
+pub struct RendezvousServer {
+    listener: TcpListener,
+    size: u32,
+}
+
+struct Joined {
+    stream: TcpStream,
+    requested: Option<u32>,
+    address: Vec<u8>,
+    metadata: Metadata,
+}
+
+impl RendezvousServer {
+    /// Listens on `addr` for the `size` processes of a job.
+    pub fn bind(addr: SocketAddr, size: u32) -> Result<Self, Error> {
+        if size == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let socket =
+            Socket::new(Domain::for_address(addr), Type::STREAM, None).map_err(io_error)?;
+        // Lets a job restart on the same port right away.
+        socket.set_reuse_address(true).map_err(io_error)?;
+        socket.bind(&addr.into()).map_err(io_error)?;
+        socket
+            .listen(size.min(i32::MAX as u32) as i32)
+            .map_err(io_error)?;
+        Ok(RendezvousServer {
+            listener: socket.into(),
+            size,
+        })
+    }
+
+    /// The address the server listens on.
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.listener.local_addr().map_err(io_error)
+    }
+
+    /// Runs the rendezvous on a new thread.
+    pub fn spawn(self, timeout: Duration) -> JoinHandle<Result<(), Error>> {
+        thread::spawn(move || self.run(timeout))
+    }
+
+    /// Waits for all processes to join and sends them the table.
+    ///
+    /// Fails with [`Error::Timeout`] if not all processes joined within `timeout`.
+    /// Processes that ask for a rank out of range or taken by another process
+    /// are sent an error and do not count as joined.
+    pub fn run(self, timeout: Duration) -> Result<(), Error> {
+        let deadline = Instant::now() + timeout;
+        self.listener.set_nonblocking(true).map_err(io_error)?;
+        let mut joined: Vec<Joined> = Vec::with_capacity(self.size as usize);
+        while joined.len() < self.size as usize {
+            let (mut stream, peer_addr) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        return Err(Error::Timeout);
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => return Err(io_error(e)),
+            };
+            let hello = stream
+                .set_nonblocking(false)
+                .and_then(|_| stream.set_read_timeout(Some(remaining(deadline))))
+                .map_err(io_error)
+                .and_then(|_| read_message(&mut stream))
+                .and_then(|message| decode_hello(&message));
+            let (requested, address, metadata) = match hello {
+                Ok(hello) => hello,
+                Err(e) => {
+                    warn!("bootstrap: dropping {peer_addr}: {e}");
+                    continue;
+                }
+            };
+            let conflict = match requested {
+                Some(rank) if rank >= self.size => {
+                    Some(format!("rank {rank} out of range for size {}", self.size))
+                }
+                Some(rank) if joined.iter().any(|j| j.requested == Some(rank)) => {
+                    Some(format!("rank {rank} already taken"))
+                }
+                _ => None,
+            };
+            if let Some(message) = conflict {
+                warn!("bootstrap: rejecting {peer_addr}: {message}");
+                let _ = write_message(&mut stream, &encode_error(&message));
+                continue;
+            }
+            debug!(
+                "bootstrap: {peer_addr} joined ({}/{})",
+                joined.len() + 1,
+                self.size
+            );
+            joined.push(Joined {
+                stream,
+                requested,
+                address,
+                metadata,
+            });
+        }
+
+        // Requested ranks first, then the free ranks in the order processes joined.
+        let taken: HashSet<u32> = joined.iter().filter_map(|j| j.requested).collect();
+        let mut free = (0..self.size).filter(|rank| !taken.contains(rank));
+        let ranks: Vec<u32> = joined
+            .iter()
+            .map(|j| j.requested.unwrap_or_else(|| free.next().unwrap()))
+            .collect();
+        let mut peers = vec![None; self.size as usize];
+        for (j, &rank) in joined.iter().zip(&ranks) {
+            peers[rank as usize] = Some(Peer {
+                rank,
+                address: j.address.clone(),
+                metadata: j.metadata.clone(),
+            });
+        }
+        let peers: Vec<Peer> = peers.into_iter().map(Option::unwrap).collect();
+        for (mut j, rank) in joined.into_iter().zip(ranks) {
+            let table = encode_table(rank, &peers)?;
+            write_message(&mut j.stream, &table)?;
+        }
+        Ok(())
+    }
+}
+
+/// Joins a job through a [`RendezvousServer`].
+#[derive(Debug, Clone)]
+pub struct TcpBootstrap {
+    server: SocketAddr,
+    rank: Option<u32>,
+    metadata: Metadata,
+    timeout: Duration,
+}
+
+impl TcpBootstrap {
+    /// Joins through the server at `server`, waiting up to a minute for the job.
+    pub fn new(server: SocketAddr) -> Self {
+        TcpBootstrap {
+            server,
+            rank: None,
+            metadata: Metadata::new(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Asks for a rank instead of being assigned one.
+    pub fn with_rank(mut self, rank: u32) -> Self {
+        self.rank = Some(rank);
+        self
+    }
+
+    /// Publishes `metadata` to the other processes alongside the address.
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// How long to wait for the server to come up and for all processes to join.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Publishes the address of `worker` and returns the table of the job.
+    pub fn exchange(&self, worker: &Worker) -> Result<Peers, Error> {
+        let address = worker.address()?;
+        self.exchange_address(address.as_ref())
+    }
+
+    /// Publishes `address` and returns the table of the job.
+    pub fn exchange_address(&self, address: &[u8]) -> Result<Peers, Error> {
+        let deadline = Instant::now() + self.timeout;
+        let mut stream = connect(self.server, deadline)?;
+        write_message(
+            &mut stream,
+            &encode_hello(self.rank, address, &self.metadata)?,
+        )?;
+        stream
+            .set_read_timeout(Some(remaining(deadline)))
+            .map_err(io_error)?;
+        let peers = decode_table(&read_message(&mut stream)?)?;
+        debug!(
+            "bootstrap: joined as rank {} of {}",
+            peers.rank(),
+            peers.size()
+        );
+        Ok(peers)
+    }
+}
+
+// The server may not be up yet when the processes of a job start.
+fn connect(server: SocketAddr, deadline: Instant) -> Result<TcpStream, Error> {
+    loop {
+        match TcpStream::connect_timeout(&server, remaining(deadline)) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                if Instant::now() + RETRY_INTERVAL >= deadline {
+                    return Err(Error::Timeout);
+                }
+                thread::sleep(RETRY_INTERVAL);
+            }
+            Err(e) => return Err(io_error(e)),
+        }
+    }
+}
+
+// Socket timeouts must not be zero.
+fn remaining(deadline: Instant) -> Duration {
+    deadline
+        .saturating_duration_since(Instant::now())
+        .max(Duration::from_millis(1))
+}
+
+fn write_message(stream: &mut TcpStream, body: &[u8]) -> Result<(), Error> {
+    let mut message = Vec::with_capacity(4 + body.len());
+    put_bytes(&mut message, body)?;
+    stream.write_all(&message).map_err(io_error)
+}
+
+fn read_message(stream: &mut TcpStream) -> Result<Vec<u8>, Error> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).map_err(io_error)?;
+    let len = u32::from_le_bytes(len);
+    if len > MAX_MESSAGE {
+        return Err(Error::Bootstrap(format!("message of {len} bytes")));
+    }
+    let mut body = vec![0; len as usize];
+    stream.read_exact(&mut body).map_err(io_error)?;
+    Ok(body)
+}
+
+fn encode_hello(rank: Option<u32>, address: &[u8], metadata: &Metadata) -> Result<Vec<u8>, Error> {
+    let mut buf = header();
+    buf.extend_from_slice(&rank.unwrap_or(ANY_RANK).to_le_bytes());
+    put_bytes(&mut buf, address)?;
+    metadata.encode_into(&mut buf)?;
+    Ok(buf)
+}
+
+fn decode_hello(message: &[u8]) -> Result<(Option<u32>, Vec<u8>, Metadata), Error> {
+    let mut reader = Reader(message);
+    check_header(&mut reader)?;
+    let rank = Some(reader.u32()?).filter(|&rank| rank != ANY_RANK);
+    let address = reader.bytes()?.to_vec();
+    let metadata = reader.metadata()?;
+    Ok((rank, address, metadata))
+}
+
+fn encode_table(rank: u32, peers: &[Peer]) -> Result<Vec<u8>, Error> {
+    let mut buf = header();
+    buf.extend_from_slice(&0u32.to_le_bytes());
+    buf.extend_from_slice(&rank.to_le_bytes());
+    buf.extend_from_slice(&(peers.len() as u32).to_le_bytes());
+    for peer in peers {
+        put_bytes(&mut buf, &peer.address)?;
+        peer.metadata.encode_into(&mut buf)?;
+    }
+    Ok(buf)
+}
+
+fn encode_error(message: &str) -> Vec<u8> {
+    let mut buf = header();
+    buf.extend_from_slice(&1u32.to_le_bytes());
+    // A message this short always fits.
+    let _ = put_bytes(&mut buf, message.as_bytes());
+    buf
+}
+
+fn decode_table(message: &[u8]) -> Result<Peers, Error> {
+    let mut reader = Reader(message);
+    check_header(&mut reader)?;
+    if reader.u32()? != 0 {
+        let message = String::from_utf8_lossy(reader.bytes()?).into_owned();
+        return Err(Error::Bootstrap(message));
+    }
+    let rank = reader.u32()?;
+    let size = reader.u32()?;
+    if rank >= size {
+        return Err(Error::Bootstrap(format!(
+            "rank {rank} out of range for size {size}"
+        )));
+    }
+    let peers = (0..size)
+        .map(|rank| {
+            Ok(Peer {
+                rank,
+                address: reader.bytes()?.to_vec(),
+                metadata: reader.metadata()?,
+            })
+        })
+        .collect::<Result<_, Error>>()?;
+    Ok(Peers::new(rank, peers))
+}
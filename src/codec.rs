@@ -0,0 +1,86 @@
+//! Serialization of typed messages.
+//!
+//! Each codec is behind a cargo feature of the same name; [`DefaultCodec`] is
+//! the first enabled one out of `bincode`, `postcard` and `json`.
+
+use crate::Error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+#[cfg(not(any(feature = "bincode", feature = "postcard", feature = "json")))]
+compile_error!("enable at least one codec feature: `bincode`, `postcard` or `json`");
+
+/// Encodes and decodes message payloads.
+pub trait Codec {
+    /// Serializes `value` into a new buffer.
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error>;
+
+    /// Deserializes a value from `bytes`.
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error>;
+}
+
+/// [bincode](https://docs.rs/bincode) with its default options.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error> {
+        bincode::serialize(value).map_err(|e| Error::Codec(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+        bincode::deserialize(bytes).map_err(|e| Error::Codec(e.to_string()))
+    }
+}
+
+/// [postcard](https://docs.rs/postcard), a compact format for small messages.
+#[cfg(feature = "postcard")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl Codec for Postcard {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error> {
+        postcard::to_allocvec(value).map_err(|e| Error::Codec(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+        postcard::from_bytes(bytes).map_err(|e| Error::Codec(e.to_string()))
+    }
+}
+
+/// JSON through [serde_json](https://docs.rs/serde_json), handy when debugging.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error> {
+        serde_json::to_vec(value).map_err(|e| Error::Codec(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+        serde_json::from_slice(bytes).map_err(|e| Error::Codec(e.to_string()))
+    }
+}
+
+/// The codec used by [`Endpoint::send_msg`](crate::ucp::endpoint::Endpoint::send_msg)
+/// and [`Endpoint::recv_msg`](crate::ucp::endpoint::Endpoint::recv_msg).
+#[cfg(feature = "bincode")]
+pub type DefaultCodec = Bincode;
+#[cfg(all(not(feature = "bincode"), feature = "postcard"))]
+pub type DefaultCodec = Postcard;
+#[cfg(all(not(feature = "bincode"), not(feature = "postcard"), feature = "json"))]
+pub type DefaultCodec = Json;
+
+/// The name of [`DefaultCodec`], which the RPC handshake compares so that
+/// peers built with different codecs do not exchange garbled payloads.
+#[cfg(feature = "bincode")]
+pub const DEFAULT_CODEC_NAME: &str = "bincode";
+#[cfg(all(not(feature = "bincode"), feature = "postcard"))]
+pub const DEFAULT_CODEC_NAME: &str = "postcard";
+#[cfg(all(not(feature = "bincode"), not(feature = "postcard"), feature = "json"))]
+pub const DEFAULT_CODEC_NAME: &str = "json";
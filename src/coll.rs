@@ -0,0 +1,424 @@
+//! Collective operations between the processes of a job.
+//!
+//! A [`Communicator`] holds an endpoint to every other rank, usually created
+//! from the table of a [`bootstrap`](crate::bootstrap). Every rank must call
+//! the same collectives in the same order.
+//!
+//! Collectives send tag messages on the [`COLLECTIVE`] channel of the
+//! [`tag`](crate::tag) space, so they do not match RPC traffic or user
+//! receives. The source of a tag is the sending rank and its sequence holds
+//! the number of the collective call on the communicator in the upper 16 bits
+//! and the step, which numbers the messages a rank sends to the same peer in
+//! one call, in the lower 16 bits.
+//! A worker should not be shared by two communicators.
+
+use crate::bootstrap::Peers;
+use crate::tag::COLLECTIVE;
+use crate::ucp::endpoint::{Endpoint, StatusPtr};
+use crate::ucp::Worker;
+use crate::Error;
+use std::cell::Cell;
+use std::rc::{Rc, Weak};
+use ucx1_sys::ucs_status_t;
+
+// Ring allreduce takes two steps per rank.
+const MAX_SIZE: u32 = 1 << 15;
+
+/// How [`Communicator::reduce`] and [`Communicator::allreduce`] combine elements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReduceOp {
+    /// The sum; integers wrap on overflow.
+    Sum,
+    /// The product; integers wrap on overflow.
+    Prod,
+    /// The smallest element.
+    Min,
+    /// The largest element.
+    Max,
+}
+
+/// A number that can be reduced across ranks.
+pub trait Reducible: Copy {
+    /// The size of the little-endian encoding.
+    const SIZE: usize;
+
+    /// Combines two elements.
+    fn combine(self, other: Self, op: ReduceOp) -> Self;
+
+    /// Appends the little-endian encoding.
+    fn write_le(self, out: &mut Vec<u8>);
+
+    /// Decodes an element from exactly [`Reducible::SIZE`] bytes.
+    fn read_le(bytes: &[u8]) -> Self;
+}
+
+macro_rules! reducible {
+    (int: $($ty:ty),*) => {$(
+        impl Reducible for $ty {
+            const SIZE: usize = std::mem::size_of::<$ty>();
+
+            fn combine(self, other: Self, op: ReduceOp) -> Self {
+                match op {
+                    ReduceOp::Sum => self.wrapping_add(other),
+                    ReduceOp::Prod => self.wrapping_mul(other),
+                    ReduceOp::Min => self.min(other),
+                    ReduceOp::Max => self.max(other),
+                }
+            }
+
+            fn write_le(self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn read_le(bytes: &[u8]) -> Self {
+                <$ty>::from_le_bytes(bytes.try_into().unwrap())
+            }
+        }
+    )*};
+    (float: $($ty:ty),*) => {$(
+        impl Reducible for $ty {
+            const SIZE: usize = std::mem::size_of::<$ty>();
+
+            fn combine(self, other: Self, op: ReduceOp) -> Self {
+                match op {
+                    ReduceOp::Sum => self + other,
+                    ReduceOp::Prod => self * other,
+                    ReduceOp::Min => self.min(other),
+                    ReduceOp::Max => self.max(other),
+                }
+            }
+
+            fn write_le(self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn read_le(bytes: &[u8]) -> Self {
+                <$ty>::from_le_bytes(bytes.try_into().unwrap())
+            }
+        }
+    )*};
+}
+
+reducible!(int: i8, i16, i32, i64, u8, u16, u32, u64);
+reducible!(float: f32, f64);
+
+/// A group of ranks connected to each other.
+#[derive(Debug)]
+pub struct Communicator {
+    worker: Rc<Worker>,
+    rank: u32,
+    endpoints: Vec<Option<Endpoint>>,
+    seq: Cell<u16>,
+}
+
+impl Communicator {
+    /// Creates a communicator for `rank` from endpoints to the other ranks.
+    ///
+    /// `endpoints` is indexed by rank and must have an endpoint at every rank
+    /// but `rank`, as returned by [`Peers::connect_all`].
+    pub fn new(
+        worker: Rc<Worker>,
+        rank: u32,
+        endpoints: Vec<Option<Endpoint>>,
+    ) -> Result<Self, Error> {
+        let size = endpoints.len() as u32;
+        let complete = endpoints
+            .iter()
+            .enumerate()
+            .all(|(i, ep)| ep.is_some() != (i as u32 == rank));
+        if rank >= size || size > MAX_SIZE || !complete {
+            return Err(Error::InvalidParam);
+        }
+        Ok(Communicator {
+            worker,
+            rank,
+            endpoints,
+            seq: Cell::new(0),
+        })
+    }
+
+    /// Connects to every peer of a bootstrap.
+    pub fn from_peers(worker: &Rc<Worker>, peers: &Peers) -> Result<Self, Error> {
+        Self::new(worker.clone(), peers.rank(), peers.connect_all(worker)?)
+    }
+
+    /// The rank of this process.
+    pub fn rank(&self) -> u32 {
+        self.rank
+    }
+
+    /// The number of ranks.
+    pub fn size(&self) -> u32 {
+        self.endpoints.len() as u32
+    }
+
+    /// The endpoint to `rank`, none for the rank of this process.
+    pub fn endpoint(&self, rank: u32) -> Option<&Endpoint> {
+        self.endpoints.get(rank as usize)?.as_ref()
+    }
+
+    /// Waits until every rank has entered the barrier.
+    pub fn barrier(&self) -> Result<(), Error> {
+        let op = self.next_op();
+        let (rank, size) = (self.rank, self.size());
+        // Dissemination: in round k, signal the rank 2^k ahead.
+        let mut distance = 1;
+        let mut step = 0;
+        while distance < size {
+            let to = (rank + distance) % size;
+            let from = (rank + size - distance) % size;
+            op.exchange(to, &[], from, step)?;
+            distance <<= 1;
+            step += 1;
+        }
+        Ok(())
+    }
+
+    /// Sends the contents of `buf` at `root` to every rank, replacing `buf` there.
+    pub fn broadcast(&self, buf: &mut Vec<u8>, root: u32) -> Result<(), Error> {
+        let op = self.next_op();
+        self.check_rank(root)?;
+        let size = self.size();
+        let vrank = self.vrank(root);
+        // Binomial tree rooted at `root`.
+        let mut mask = 1;
+        while mask < size {
+            if vrank & mask != 0 {
+                *buf = op.recv(self.real(vrank - mask, root), 0)?;
+                break;
+            }
+            mask <<= 1;
+        }
+        mask >>= 1;
+        while mask > 0 {
+            if vrank + mask < size {
+                op.send(self.real(vrank + mask, root), buf, 0)?;
+            }
+            mask >>= 1;
+        }
+        Ok(())
+    }
+
+    /// Combines `data` element-wise across ranks, leaving the result in `data` at `root`.
+    ///
+    /// Every rank must pass a slice of the same length. The contents of `data`
+    /// on the other ranks are unspecified afterwards.
+    pub fn reduce<T: Reducible>(
+        &self,
+        data: &mut [T],
+        op: ReduceOp,
+        root: u32,
+    ) -> Result<(), Error> {
+        let coll = self.next_op();
+        self.check_rank(root)?;
+        let size = self.size();
+        let vrank = self.vrank(root);
+        let mut mask = 1;
+        while mask < size {
+            if vrank & mask == 0 {
+                let child = vrank | mask;
+                if child < size {
+                    let bytes = coll.recv(self.real(child, root), 0)?;
+                    combine_into(data, &bytes, op)?;
+                }
+            } else {
+                coll.send(self.real(vrank & !mask, root), &encode(data), 0)?;
+                break;
+            }
+            mask <<= 1;
+        }
+        Ok(())
+    }
+
+    /// Combines `data` element-wise across ranks, leaving the result in `data` everywhere.
+    ///
+    /// Every rank must pass a slice of the same length. Slices with at least
+    /// one element per rank are reduced around a ring, which spreads the
+    /// traffic evenly; shorter ones are reduced to rank 0 and broadcast.
+    pub fn allreduce<T: Reducible>(&self, data: &mut [T], op: ReduceOp) -> Result<(), Error> {
+        let size = self.size();
+        if data.len() < size as usize {
+            self.reduce(data, op, 0)?;
+            let mut bytes = encode(data);
+            self.broadcast(&mut bytes, 0)?;
+            return decode_into(data, &bytes);
+        }
+
+        let coll = self.next_op();
+        let (rank, n) = (self.rank, size as usize);
+        let right = (rank + 1) % size;
+        let left = (rank + size - 1) % size;
+        let len = data.len();
+        let chunk = |i: usize| i * len / n..(i + 1) * len / n;
+        // Reduce-scatter: afterwards rank r holds the result of chunk r + 1.
+        for s in 0..n - 1 {
+            let send = (rank as usize + n - s) % n;
+            let recv = (rank as usize + n - s - 1) % n;
+            let bytes = coll.exchange(right, &encode(&data[chunk(send)]), left, s as u32)?;
+            combine_into(&mut data[chunk(recv)], &bytes, op)?;
+        }
+        // Allgather of the reduced chunks.
+        for s in 0..n - 1 {
+            let send = (rank as usize + 1 + n - s) % n;
+            let recv = (rank as usize + n - s) % n;
+            let step = (n - 1 + s) as u32;
+            let bytes = coll.exchange(right, &encode(&data[chunk(send)]), left, step)?;
+            decode_into(&mut data[chunk(recv)], &bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Collects `data` of every rank at `root`, indexed by rank.
+    ///
+    /// Returns `None` on the other ranks.
+    pub fn gather(&self, data: &[u8], root: u32) -> Result<Option<Vec<Vec<u8>>>, Error> {
+        let op = self.next_op();
+        self.check_rank(root)?;
+        if self.rank != root {
+            op.send(root, data, 0)?;
+            return Ok(None);
+        }
+        (0..self.size())
+            .map(|rank| {
+                if rank == root {
+                    Ok(data.to_vec())
+                } else {
+                    op.recv(rank, 0)
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
+
+    /// Collects `data` of every rank at every rank, indexed by rank.
+    pub fn allgather(&self, data: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        let op = self.next_op();
+        let (rank, size) = (self.rank, self.size());
+        let right = (rank + 1) % size;
+        let left = (rank + size - 1) % size;
+        let mut blocks = vec![Vec::new(); size as usize];
+        blocks[rank as usize] = data.to_vec();
+        // Around the ring, passing on the block received in the previous step.
+        for s in 0..size - 1 {
+            let send = (rank + size - s) % size;
+            let recv = (rank + size - s - 1) % size;
+            blocks[recv as usize] = op.exchange(right, &blocks[send as usize], left, s)?;
+        }
+        Ok(blocks)
+    }
+
+    /// Sends `data[r]` to rank `r` and returns the blocks received, indexed by rank.
+    pub fn alltoall(&self, data: &[Vec<u8>]) -> Result<Vec<Vec<u8>>, Error> {
+        let op = self.next_op();
+        let (rank, size) = (self.rank, self.size());
+        if data.len() != size as usize {
+            return Err(Error::InvalidParam);
+        }
+        let mut blocks = vec![Vec::new(); size as usize];
+        blocks[rank as usize] = data[rank as usize].clone();
+        for s in 1..size {
+            let to = (rank + s) % size;
+            let from = (rank + size - s) % size;
+            blocks[from as usize] = op.exchange(to, &data[to as usize], from, 0)?;
+        }
+        Ok(blocks)
+    }
+
+    fn next_op(&self) -> Collective<'_> {
+        let seq = self.seq.get();
+        self.seq.set(seq.wrapping_add(1));
+        Collective { comm: self, seq }
+    }
+
+    fn check_rank(&self, rank: u32) -> Result<(), Error> {
+        if rank < self.size() {
+            Ok(())
+        } else {
+            Err(Error::InvalidParam)
+        }
+    }
+
+    // The rank relative to `root`, which trees are built on.
+    fn vrank(&self, root: u32) -> u32 {
+        (self.rank + self.size() - root) % self.size()
+    }
+
+    fn real(&self, vrank: u32, root: u32) -> u32 {
+        (vrank + root) % self.size()
+    }
+}
+
+// One collective call, which tags its messages with its sequence number.
+struct Collective<'a> {
+    comm: &'a Communicator,
+    seq: u16,
+}
+
+impl Collective<'_> {
+    fn tag(&self, source: u32, step: u32) -> u64 {
+        debug_assert!(step < 1 << 16);
+        COLLECTIVE.tag(source, (self.seq as u32) << 16 | step)
+    }
+
+    fn send(&self, to: u32, bytes: &[u8], step: u32) -> Result<(), Error> {
+        self.start_send(to, bytes, step).wait(&self.comm.worker)
+    }
+
+    fn recv(&self, from: u32, step: u32) -> Result<Vec<u8>, Error> {
+        let (_, bytes) = self
+            .comm
+            .worker
+            .tag_recv_bytes(self.tag(from, step), u64::MAX)?;
+        Ok(bytes)
+    }
+
+    // Sends and receives at the same time, so that two ranks sending each
+    // other large messages do not wait on each other.
+    fn exchange(&self, to: u32, bytes: &[u8], from: u32, step: u32) -> Result<Vec<u8>, Error> {
+        let send = self.start_send(to, bytes, step);
+        let received = self.recv(from, step);
+        send.wait(&self.comm.worker)?;
+        received
+    }
+
+    fn start_send(&self, to: u32, bytes: &[u8], step: u32) -> StatusPtr {
+        let tag = self.tag(self.comm.rank, step);
+        match &self.comm.endpoints[to as usize] {
+            // SAFETY: every caller waits for the send before `bytes` goes away.
+            Some(ep) => unsafe { ep.tag_send(tag, bytes, Weak::<fn(ucs_status_t)>::new()) },
+            None => unreachable!("collective sends to its own rank"),
+        }
+    }
+}
+
+fn encode<T: Reducible>(data: &[T]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len() * T::SIZE);
+    for &x in data {
+        x.write_le(&mut bytes);
+    }
+    bytes
+}
+
+fn check_len<T: Reducible>(data: &[T], bytes: &[u8]) -> Result<(), Error> {
+    if bytes.len() == data.len() * T::SIZE {
+        Ok(())
+    } else {
+        Err(Error::MessageTruncated)
+    }
+}
+
+fn decode_into<T: Reducible>(data: &mut [T], bytes: &[u8]) -> Result<(), Error> {
+    check_len(data, bytes)?;
+    for (x, chunk) in data.iter_mut().zip(bytes.chunks_exact(T::SIZE)) {
+        *x = T::read_le(chunk);
+    }
+    Ok(())
+}
+
+fn combine_into<T: Reducible>(data: &mut [T], bytes: &[u8], op: ReduceOp) -> Result<(), Error> {
+    check_len(data, bytes)?;
+    for (x, chunk) in data.iter_mut().zip(bytes.chunks_exact(T::SIZE)) {
+        *x = x.combine(T::read_le(chunk), op);
+    }
+    Ok(())
+}
@@ -0,0 +1,231 @@
+//! Credit-based flow control.
+//!
+//! UCX delivers tag messages nobody is waiting for to the unexpected queue of
+//! the receiving worker, which grows without bound when a sender outpaces its
+//! receiver. With credits, the receiver tells the sender how many messages it
+//! may send: a sender without credits waits, making progress on the worker,
+//! until the receiver has consumed enough messages to grant more.
+//!
+//! [`Credits`] does the accounting for both directions of a link; RPC streams
+//! use it for their items. [`FlowControlled`] applies it to the messages sent
+//! over an endpoint, on the [`FLOW`] tag channel: both sides pick an inbox id
+//! and advertise it with their receive window when the link is set up, then
+//! send messages and credit grants to the inbox of the peer.
+
+use crate::tag::{self, FLOW};
+use crate::ucp::endpoint::Endpoint;
+use crate::Error;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Instant;
+use tracing::debug;
+
+/// The default number of messages a receiver lets a sender have in flight.
+pub const DEFAULT_WINDOW: u32 = 64;
+
+const HELLO: u32 = 0;
+const DATA: u32 = 1;
+const CREDIT: u32 = 2;
+
+// Inboxes only need to be unique per worker, but a process-wide counter is
+// simpler, like the call ids of the RPC client.
+static NEXT_INBOX: AtomicU32 = AtomicU32::new(1);
+
+/// Credit accounting for one link.
+///
+/// The sending half counts the credits granted by the peer; the receiving
+/// half counts the messages consumed since credits were last granted and
+/// decides when to grant them, once half of its window is consumed.
+#[derive(Debug)]
+pub struct Credits {
+    window: u32,
+    available: Cell<u32>,
+    unacked: Cell<u32>,
+}
+
+impl Credits {
+    /// Accounting for a link whose receiving window is `window` and whose
+    /// peer starts with `initial` credits to spend.
+    pub fn new(window: u32, initial: u32) -> Self {
+        Credits {
+            window: window.max(1),
+            available: Cell::new(initial),
+            unacked: Cell::new(0),
+        }
+    }
+
+    /// The receiving window.
+    pub fn window(&self) -> u32 {
+        self.window
+    }
+
+    /// The credits left to send with.
+    pub fn available(&self) -> u32 {
+        self.available.get()
+    }
+
+    /// Spends a credit, if there is one.
+    pub fn try_acquire(&self) -> bool {
+        match self.available.get() {
+            0 => false,
+            n => {
+                self.available.set(n - 1);
+                true
+            }
+        }
+    }
+
+    /// Adds credits granted by the peer.
+    pub fn add(&self, credits: u32) {
+        self.available
+            .set(self.available.get().saturating_add(credits));
+    }
+
+    /// Records that a received message was consumed and returns the credits
+    /// to grant the peer, if it is time to.
+    pub fn consume(&self) -> Option<u32> {
+        let unacked = self.unacked.get() + 1;
+        if unacked >= (self.window / 2).max(1) {
+            self.unacked.set(0);
+            Some(unacked)
+        } else {
+            self.unacked.set(unacked);
+            None
+        }
+    }
+}
+
+/// Messages over an endpoint, limited to the window of the receiver.
+///
+/// Both sides of the endpoint must set up the link. Links are told apart by
+/// their inbox ids once set up, but the hellos exchanged while setting them up
+/// are not, so links on one worker should be set up one at a time.
+#[derive(Debug)]
+pub struct FlowControlled {
+    ep: Rc<Endpoint>,
+    inbox: u32,
+    peer_inbox: u32,
+    credits: Credits,
+}
+
+impl FlowControlled {
+    /// Sets up the link with the default window.
+    pub fn connect(ep: Rc<Endpoint>) -> Result<Self, Error> {
+        Self::connect_with_window(ep, DEFAULT_WINDOW)
+    }
+
+    /// Sets up the link, letting the peer have up to `window` messages in flight.
+    pub fn connect_with_window(ep: Rc<Endpoint>, window: u32) -> Result<Self, Error> {
+        let window = window.max(1);
+        let inbox = NEXT_INBOX.fetch_add(1, Ordering::Relaxed) & tag::SOURCE.max() as u32;
+        ep.send_msg(FLOW.tag(0, HELLO), &(inbox, window))?;
+        let (peer_inbox, peer_window): (u32, u32) = ep.recv_msg(
+            FLOW.tag(0, HELLO),
+            tag::CHANNEL.mask() | tag::SEQUENCE.mask(),
+        )?;
+        debug!(
+            inbox,
+            peer_inbox, window, peer_window, "flow-controlled link set up"
+        );
+        Ok(FlowControlled {
+            ep,
+            inbox,
+            peer_inbox,
+            credits: Credits::new(window, peer_window),
+        })
+    }
+
+    /// The endpoint the messages are sent on.
+    pub fn endpoint(&self) -> &Rc<Endpoint> {
+        &self.ep
+    }
+
+    /// The messages that can be sent before the peer grants more credits.
+    pub fn credits(&self) -> u32 {
+        self.poll_credits();
+        self.credits.available()
+    }
+
+    /// Sends `bytes` if there is a credit for it, returning whether it was sent.
+    pub fn try_send(&self, bytes: &[u8]) -> Result<bool, Error> {
+        self.poll_credits();
+        if !self.credits.try_acquire() {
+            return Ok(false);
+        }
+        self.ep.send_bytes(FLOW.tag(self.peer_inbox, DATA), bytes)?;
+        Ok(true)
+    }
+
+    /// Sends `bytes`, waiting for a credit first.
+    pub fn send(&self, bytes: &[u8]) -> Result<(), Error> {
+        self.send_until(bytes, None)
+    }
+
+    /// Like [`FlowControlled::send`], failing with [`Error::Timeout`] if no
+    /// credit arrives or the send does not complete by `deadline`.
+    pub fn send_until(&self, bytes: &[u8], deadline: Option<Instant>) -> Result<(), Error> {
+        loop {
+            self.poll_credits();
+            if self.credits.try_acquire() {
+                break;
+            }
+            if *self.ep.closed.borrow() {
+                return Err(Error::ConnectionReset);
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(Error::Timeout);
+            }
+        }
+        self.ep
+            .send_bytes_until(FLOW.tag(self.peer_inbox, DATA), bytes, deadline)
+    }
+
+    /// Takes the next message if one has arrived.
+    pub fn try_recv(&self) -> Result<Option<Vec<u8>>, Error> {
+        let worker = &self.ep.worker;
+        worker.progress();
+        match worker.tag_probe(FLOW.tag(self.inbox, DATA), u64::MAX) {
+            Some(message) => self.take(worker.recv_probed(message)?).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Waits for the next message.
+    pub fn recv(&self) -> Result<Vec<u8>, Error> {
+        self.recv_until(None)
+    }
+
+    /// Like [`FlowControlled::recv`], failing with [`Error::Timeout`] at `deadline`.
+    pub fn recv_until(&self, deadline: Option<Instant>) -> Result<Vec<u8>, Error> {
+        let (_, bytes) =
+            self.ep
+                .worker
+                .tag_recv_bytes_until(FLOW.tag(self.inbox, DATA), u64::MAX, deadline)?;
+        self.take(bytes)
+    }
+
+    fn take(&self, bytes: Vec<u8>) -> Result<Vec<u8>, Error> {
+        self.ep.metrics().bytes_received.add(bytes.len() as u64);
+        if let Some(credits) = self.credits.consume() {
+            self.ep
+                .send_bytes(FLOW.tag(self.peer_inbox, CREDIT), &credits.to_le_bytes())?;
+        }
+        Ok(bytes)
+    }
+
+    // Adds the credits the peer has granted.
+    fn poll_credits(&self) {
+        let worker = &self.ep.worker;
+        worker.progress();
+        while let Some(message) = worker.tag_probe(FLOW.tag(self.inbox, CREDIT), u64::MAX) {
+            match worker.recv_probed(message) {
+                Ok(bytes) => match bytes.first_chunk::<4>() {
+                    Some(credits) => self.credits.add(u32::from_le_bytes(*credits)),
+                    None => debug!("dropping truncated credit grant"),
+                },
+                Err(e) => debug!("failed to receive credit grant: {e}"),
+            }
+        }
+    }
+}
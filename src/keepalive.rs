@@ -0,0 +1,322 @@
+//! Application-level keepalive for endpoints.
+//!
+//! UCX notices a dead peer through transport timeouts, which can take
+//! minutes. A [`Keepalive`] pings the peer at a fixed interval and declares
+//! the connection failed once the peer has been silent for a number of
+//! intervals, force-closing the endpoint so that everything waiting on it
+//! fails with [`Error::ConnectionReset`].
+//!
+//! Both sides of the endpoint run a keepalive and answer each other's pings.
+//! Pings travel on the [`KEEPALIVE`] tag channel, to an inbox id each side
+//! picks and sends the other on the stream of the endpoint when the
+//! keepalive is set up. Every frame also carries the inbox of its sender.
+//! Nothing runs in the background: [`Keepalive::poll`] sends the pings,
+//! answers those of the peer and checks for silence, and must be called at
+//! least once per interval. A gap between two polls longer than an interval
+//! is taken for a stall of this side, not silence of the peer, which gets
+//! another chance to answer.
+//!
+//! RPC connections run keepalives of their own, see
+//! [`Server::set_keepalive`](crate::rpc::Server::set_keepalive) and
+//! [`Client::set_keepalive`](crate::rpc::Client::set_keepalive).
+
+use crate::tag::{self, KEEPALIVE};
+use crate::ucp::endpoint::Endpoint;
+use crate::ucp::TagMessage;
+use crate::Error;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+const FRAME: u32 = 1;
+// The kind, the sequence number and the inbox of the sender.
+const FRAME_LEN: usize = 9;
+
+const PING: u8 = 1;
+const PONG: u8 = 2;
+
+// Inboxes only need to be unique per worker, but a process-wide counter is
+// simpler, like the call ids of the RPC client.
+static NEXT_INBOX: AtomicU32 = AtomicU32::new(1);
+
+/// How often a [`Keepalive`] pings and how much silence it tolerates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveOptions {
+    /// The time between two pings.
+    pub interval: Duration,
+    /// The number of intervals without hearing from the peer after which
+    /// the connection has failed.
+    pub missed: u32,
+}
+
+impl Default for KeepaliveOptions {
+    /// Pings every 300ms and fails after a second of silence.
+    fn default() -> Self {
+        KeepaliveOptions {
+            interval: Duration::from_millis(300),
+            missed: 3,
+        }
+    }
+}
+
+type FailureCallback = Box<dyn Fn(&Error)>;
+
+/// Pings the peer of an endpoint and detects its failure.
+pub struct Keepalive {
+    ep: Rc<Endpoint>,
+    options: KeepaliveOptions,
+    inbox: u32,
+    // Unknown until the peer's first frame on the server side of RPC
+    // connections, which does not check for silence until then.
+    peer_inbox: Cell<Option<u32>>,
+    seq: Cell<u32>,
+    // The last ping sent and when, to measure the round trip.
+    last_ping: Cell<(u32, Instant)>,
+    last_heard: Cell<Instant>,
+    last_poll: Cell<Instant>,
+    round_trip: Cell<Option<Duration>>,
+    failed: Cell<bool>,
+    on_failure: RefCell<Option<FailureCallback>>,
+}
+
+impl std::fmt::Debug for Keepalive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keepalive")
+            .field("options", &self.options)
+            .field("inbox", &self.inbox)
+            .field("peer_inbox", &self.peer_inbox.get())
+            .field("round_trip", &self.round_trip.get())
+            .field("failed", &self.failed.get())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Keepalive {
+    /// Sets up the keepalive with the peer of `ep`, which must do the same.
+    pub fn connect(ep: Rc<Endpoint>, options: KeepaliveOptions) -> Result<Self, Error> {
+        Self::connect_until(ep, options, None)
+    }
+
+    /// Like [`Keepalive::connect`], but fails with [`Error::Timeout`] if the
+    /// peer has not sent its inbox by `deadline`.
+    pub fn connect_until(
+        ep: Rc<Endpoint>,
+        options: KeepaliveOptions,
+        deadline: Option<Instant>,
+    ) -> Result<Self, Error> {
+        let inbox = next_inbox();
+        tag::send_setup(&ep, KEEPALIVE, &inbox.to_le_bytes(), deadline)?;
+        let peer_inbox = u32::from_le_bytes(tag::recv_setup(&ep, KEEPALIVE, deadline)?);
+        debug!(inbox, peer_inbox, "keepalive set up");
+        Ok(Self::new(ep, options, inbox, Some(peer_inbox)))
+    }
+
+    // A keepalive receiving on `inbox`, whose peer advertised `peer_inbox`
+    // some other way or will in its first frame.
+    pub(crate) fn new(
+        ep: Rc<Endpoint>,
+        options: KeepaliveOptions,
+        inbox: u32,
+        peer_inbox: Option<u32>,
+    ) -> Self {
+        let now = Instant::now();
+        Keepalive {
+            ep,
+            options: KeepaliveOptions {
+                missed: options.missed.max(1),
+                ..options
+            },
+            inbox,
+            peer_inbox: Cell::new(peer_inbox),
+            seq: Cell::new(0),
+            last_ping: Cell::new((0, now)),
+            last_heard: Cell::new(now),
+            last_poll: Cell::new(now),
+            round_trip: Cell::new(None),
+            failed: Cell::new(false),
+            on_failure: RefCell::new(None),
+        }
+    }
+
+    /// Calls `callback` with the error once the connection has failed.
+    pub fn on_failure(&self, callback: impl Fn(&Error) + 'static) {
+        *self.on_failure.borrow_mut() = Some(Box::new(callback));
+    }
+
+    /// The tag source the keepalive receives the frames of the peer on.
+    pub fn inbox(&self) -> u32 {
+        self.inbox
+    }
+
+    /// The endpoint kept alive.
+    pub fn endpoint(&self) -> &Rc<Endpoint> {
+        &self.ep
+    }
+
+    /// Returns false once the connection has failed.
+    pub fn is_alive(&self) -> bool {
+        !self.failed.get()
+    }
+
+    /// The time since the peer was last heard from.
+    pub fn silence(&self) -> Duration {
+        self.last_heard.get().elapsed()
+    }
+
+    /// How often the keepalive pings and how much silence it tolerates.
+    pub fn options(&self) -> KeepaliveOptions {
+        self.options
+    }
+
+    /// The round-trip time of the last answered ping.
+    pub fn round_trip(&self) -> Option<Duration> {
+        self.round_trip.get()
+    }
+
+    /// Answers the pings of the peer, sends a ping if one is due and checks
+    /// whether the peer has been silent for too long.
+    ///
+    /// Fails with [`Error::EndpointTimeout`] when the peer has gone silent, in
+    /// which case the endpoint is closed, and with [`Error::ConnectionReset`]
+    /// once the endpoint is closed.
+    pub fn poll(&self) -> Result<(), Error> {
+        self.ep.worker.progress();
+        self.service()
+    }
+
+    // `poll` without making progress on the worker, for callers that just did.
+    pub(crate) fn service(&self) -> Result<(), Error> {
+        if self.failed.get() {
+            return Err(Error::ConnectionReset);
+        }
+        let now = Instant::now();
+        if now - self.last_poll.replace(now) > self.options.interval {
+            let heard = self.last_heard.get().max(now - self.options.interval);
+            self.last_heard.set(heard);
+        }
+        let worker = &self.ep.worker;
+        while let Some(message) = worker.tag_probe(KEEPALIVE.tag(self.inbox, FRAME), u64::MAX) {
+            let bytes = worker.recv_probed(message)?;
+            self.handle(&bytes);
+        }
+
+        if *self.ep.closed.borrow() {
+            return Err(self.fail(Error::ConnectionReset));
+        }
+        let Some(peer_inbox) = self.peer_inbox.get() else {
+            return Ok(());
+        };
+        let timeout = self.options.interval * self.options.missed;
+        if self.silence() > timeout {
+            warn!(
+                peer_inbox,
+                "peer silent for {:?}, closing the endpoint",
+                self.silence()
+            );
+            self.ep.force_close();
+            return Err(self.fail(Error::EndpointTimeout));
+        }
+        let (_, sent) = self.last_ping.get();
+        if sent.elapsed() >= self.options.interval {
+            let seq = self.seq.get().wrapping_add(1);
+            self.seq.set(seq);
+            self.last_ping.set((seq, Instant::now()));
+            self.send(peer_inbox, PING, seq);
+        }
+        Ok(())
+    }
+
+    fn handle(&self, bytes: &[u8]) {
+        let Some(frame) = bytes.first_chunk::<FRAME_LEN>() else {
+            return;
+        };
+        let kind = frame[0];
+        let seq = u32::from_le_bytes(frame[1..5].try_into().unwrap());
+        let sender = u32::from_le_bytes(frame[5..9].try_into().unwrap());
+        let peer_inbox = match self.peer_inbox.get() {
+            Some(peer_inbox) => peer_inbox,
+            None => {
+                debug!(
+                    inbox = self.inbox,
+                    peer_inbox = sender,
+                    "keepalive peer heard"
+                );
+                self.peer_inbox.set(Some(sender));
+                sender
+            }
+        };
+        self.last_heard.set(Instant::now());
+        match kind {
+            PING => self.send(peer_inbox, PONG, seq),
+            PONG => {
+                let (last, sent) = self.last_ping.get();
+                if seq == last {
+                    self.round_trip.set(Some(sent.elapsed()));
+                }
+            }
+            kind => debug!(kind, "dropping unknown keepalive frame"),
+        }
+    }
+
+    // A send that does not complete within an interval counts as a missed
+    // ping rather than an error.
+    fn send(&self, peer_inbox: u32, kind: u8, seq: u32) {
+        let mut frame = vec![kind];
+        frame.extend_from_slice(&seq.to_le_bytes());
+        frame.extend_from_slice(&self.inbox.to_le_bytes());
+        let deadline = Some(Instant::now() + self.options.interval);
+        if let Err(e) = self
+            .ep
+            .send_bytes_until(KEEPALIVE.tag(peer_inbox, FRAME), &frame, deadline)
+        {
+            debug!("failed to send keepalive frame: {e}");
+        }
+    }
+
+    fn fail(&self, error: Error) -> Error {
+        self.failed.set(true);
+        if let Some(callback) = self.on_failure.borrow().as_ref() {
+            callback(&error);
+        }
+        error
+    }
+}
+
+// Waits for a message matching `tag` on the worker of `ep`, polling
+// `keepalive` meanwhile. Fails once the endpoint is closed.
+pub(crate) fn probe_until(
+    keepalive: Option<&Keepalive>,
+    ep: &Endpoint,
+    tag: u64,
+    deadline: Option<Instant>,
+) -> Result<TagMessage, Error> {
+    loop {
+        if let Some(message) = ep.worker.tag_probe(tag, u64::MAX) {
+            return Ok(message);
+        }
+        if *ep.closed.borrow() {
+            return Err(Error::ConnectionReset);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(Error::Timeout);
+        }
+        match keepalive {
+            Some(keepalive) => keepalive.poll()?,
+            None => {
+                ep.worker.progress();
+            }
+        }
+    }
+}
+
+// A new inbox id; 0 is left out so that it can stand for none.
+pub(crate) fn next_inbox() -> u32 {
+    loop {
+        let inbox = NEXT_INBOX.fetch_add(1, Ordering::Relaxed) & tag::SOURCE.max() as u32;
+        if inbox != 0 {
+            return inbox;
+        }
+    }
+}
@@ -3,12 +3,29 @@ use ucx1_sys::ucs_status_t;
 use ucx1_sys::UCS_PTR_IS_ERR;
 use ucx1_sys::UCS_PTR_RAW_STATUS;
 
+pub mod auth;
+pub mod bootstrap;
+pub mod codec;
+pub mod coll;
+pub mod flow;
+pub mod keepalive;
+pub mod metrics;
+pub mod pubsub;
+pub mod reconnect;
+pub mod rpc;
+pub mod tag;
 pub mod ucp;
 
+pub use ucx_rpc_macros::service;
+
+// Lets the tests use the code generated by `service`, which names this crate.
+#[cfg(test)]
+extern crate self as ucx_rpc;
+
 /// UCX error code.
 #[allow(missing_docs)]
 #[repr(i8)]
-#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[derive(thiserror::Error, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum Error {
     #[error("Operation in progress")]
     Inprogress,
@@ -76,6 +93,23 @@ pub enum Error {
 
     #[error("Unknown error")]
     Unknown,
+
+    #[error("Failed to encode or decode message: {0}")]
+    Codec(String),
+    #[error("Remote call failed: {0}")]
+    Remote(String),
+    #[error("Bootstrap failed: {0}")]
+    Bootstrap(String),
+    #[error("Handshake failed: {0}")]
+    Handshake(String),
+    #[error("Authentication failed: {0}")]
+    Authentication(String),
+    #[error("Checksum mismatch in a {len}-byte message with tag {tag:#018x} from {endpoint}")]
+    ChecksumMismatch {
+        tag: u64,
+        endpoint: String,
+        len: usize,
+    },
 }
 
 impl Error {
@@ -121,6 +155,52 @@ impl Error {
         }
     }
 
+    // Inverse of `from_error`, for reporting errors back to UCX from callbacks.
+    pub(crate) fn status(&self) -> ucs_status_t {
+        match self {
+            Self::Inprogress => ucs_status_t::UCS_INPROGRESS,
+            Self::NoMessage => ucs_status_t::UCS_ERR_NO_MESSAGE,
+            Self::NoReource => ucs_status_t::UCS_ERR_NO_RESOURCE,
+            Self::IoError => ucs_status_t::UCS_ERR_IO_ERROR,
+            Self::NoMemory => ucs_status_t::UCS_ERR_NO_MEMORY,
+            Self::InvalidParam => ucs_status_t::UCS_ERR_INVALID_PARAM,
+            Self::Unreachable => ucs_status_t::UCS_ERR_UNREACHABLE,
+            Self::InvalidAddr => ucs_status_t::UCS_ERR_INVALID_ADDR,
+            Self::NotImplemented => ucs_status_t::UCS_ERR_NOT_IMPLEMENTED,
+            Self::MessageTruncated => ucs_status_t::UCS_ERR_MESSAGE_TRUNCATED,
+            Self::NoProgress => ucs_status_t::UCS_ERR_NO_PROGRESS,
+            Self::BufferTooSmall => ucs_status_t::UCS_ERR_BUFFER_TOO_SMALL,
+            Self::NoElem => ucs_status_t::UCS_ERR_NO_ELEM,
+            Self::SomeConnectsFailed => ucs_status_t::UCS_ERR_SOME_CONNECTS_FAILED,
+            Self::NoDevice => ucs_status_t::UCS_ERR_NO_DEVICE,
+            Self::Busy => ucs_status_t::UCS_ERR_BUSY,
+            Self::Canceled => ucs_status_t::UCS_ERR_CANCELED,
+            Self::ShmemSegment => ucs_status_t::UCS_ERR_SHMEM_SEGMENT,
+            Self::AlreadyExists => ucs_status_t::UCS_ERR_ALREADY_EXISTS,
+            Self::OutOfRange => ucs_status_t::UCS_ERR_OUT_OF_RANGE,
+            Self::Timeout => ucs_status_t::UCS_ERR_TIMED_OUT,
+            Self::ExceedsLimit => ucs_status_t::UCS_ERR_EXCEEDS_LIMIT,
+            Self::Unsupported => ucs_status_t::UCS_ERR_UNSUPPORTED,
+            Self::Rejected => ucs_status_t::UCS_ERR_REJECTED,
+            Self::NotConnected => ucs_status_t::UCS_ERR_NOT_CONNECTED,
+            Self::ConnectionReset => ucs_status_t::UCS_ERR_CONNECTION_RESET,
+
+            Self::FirstLinkFailure => ucs_status_t::UCS_ERR_FIRST_LINK_FAILURE,
+            Self::LastLinkFailure => ucs_status_t::UCS_ERR_LAST_LINK_FAILURE,
+            Self::FirstEndpointFailure => ucs_status_t::UCS_ERR_FIRST_ENDPOINT_FAILURE,
+            Self::EndpointTimeout => ucs_status_t::UCS_ERR_ENDPOINT_TIMEOUT,
+            Self::LastEndpointFailure => ucs_status_t::UCS_ERR_LAST_ENDPOINT_FAILURE,
+
+            Self::Unknown => ucs_status_t::UCS_ERR_IO_ERROR,
+            Self::Codec(_) => ucs_status_t::UCS_ERR_INVALID_PARAM,
+            Self::Remote(_) => ucs_status_t::UCS_ERR_IO_ERROR,
+            Self::Bootstrap(_) => ucs_status_t::UCS_ERR_IO_ERROR,
+            Self::Handshake(_) => ucs_status_t::UCS_ERR_REJECTED,
+            Self::Authentication(_) => ucs_status_t::UCS_ERR_REJECTED,
+            Self::ChecksumMismatch { .. } => ucs_status_t::UCS_ERR_IO_ERROR,
+        }
+    }
+
     #[inline]
     pub fn from_status(status: ucs_status_t) -> Result<(), Self> {
         if status == ucs_status_t::UCS_OK {
@@ -139,4 +219,14 @@ impl Error {
             Ok(())
         }
     }
-}
\ No newline at end of file
+}
+// Bytes that must be unpredictable, like authentication challenges and
+// connection keys, come from the kernel.
+pub(crate) fn random_bytes<const N: usize>() -> Result<[u8; N], Error> {
+    use std::io::Read;
+    let mut bytes = [0; N];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .map_err(|_| Error::IoError)?;
+    Ok(bytes)
+}
@@ -12,9 +12,12 @@ use tracing::{info, warn};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use ucx_rpc::Error;
+use ucx_rpc::tag::TagChannel;
 use ucx_rpc::ucp::*;
 
 const MESSAGE: &str = "Hello, World!";
+// Application messages use a channel of their own.
+const HELLO: TagChannel = TagChannel::new(0x01);
 
 fn main() -> anyhow::Result<()> {
   tracing_subscriber::registry()
@@ -70,8 +73,6 @@ fn main() -> anyhow::Result<()> {
 unsafe fn client_server_do_work(ep: Endpoint, is_server: bool) -> Result<(), Error> {
   info!("client_server_do_work ep: {:?}", ep);
   if is_server {
-      let mut buffer = [MaybeUninit::uninit(); MESSAGE.len()];
-      let rx_cb = Rc::new(|_| {});
       // let tx_cb = Rc::new(|_| {});
 
       // for _ in 0..50 {
@@ -83,17 +84,13 @@ unsafe fn client_server_do_work(ep: Endpoint, is_server: bool) -> Result<(), Err
       //         status.wait(&ep.worker)?;
       //     }
       // }
-      let status = ep.tag_recv(&mut buffer, 99, 0, Rc::downgrade(&rx_cb));
-      info!("server_do_work tag_recv: {:?}", status.status());
-      status.wait(&ep.worker)?;
-      let buffer: [u8; MESSAGE.len()] = std::mem::transmute(buffer);
-      info!("received message: {:?}", std::str::from_utf8(&buffer).unwrap());
+      let message: String = ep.recv_msg(HELLO.tag(0, 99), u64::MAX)?;
+      info!("received message: {:?}", message);
       info!("received all messages");
 
       Ok(())
   } else {
       // let rx_cb = Rc::new(|_| {});
-      let tx_cb = Rc::new(|_| {});
 
       // for _ in 0..50 {
       //     let now = Instant::now();
@@ -110,10 +107,9 @@ unsafe fn client_server_do_work(ep: Endpoint, is_server: bool) -> Result<(), Err
       //     info!(iops = (100000.0 / elapsed as f64 * 1000.0 * 1000.0))
       // }
 
-      let status = ep.tag_send(99, MESSAGE.as_bytes(), Rc::downgrade(&tx_cb));
-      info!("client_do_work tag_send: {:?}", status.status());
+      ep.send_msg(HELLO.tag(0, 99), MESSAGE)?;
+      info!("client_do_work send_msg done");
       // ep.print_to_stderr();
-      status.wait(&ep.worker)?;
 
       Ok(())
   }
@@ -0,0 +1,555 @@
+//! Built-in metrics of workers, endpoints and RPC methods.
+//!
+//! Metrics are kept in process-wide atomics and read on demand with
+//! [`snapshot`]. A [`Snapshot`] can be inspected directly or rendered in the
+//! Prometheus text exposition format:
+//!
+//! ```no_run
+//! let text = ucx_rpc::metrics::snapshot().to_prometheus();
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+/// Upper bounds of the histogram buckets, in seconds.
+const BUCKETS: [f64; 15] = [
+    1e-6, 5e-6, 1e-5, 5e-5, 1e-4, 5e-4, 1e-3, 5e-3, 1e-2, 5e-2, 0.1, 0.5, 1.0, 5.0, 10.0,
+];
+
+/// A monotonically increasing count.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that goes up and down.
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub const fn new() -> Self {
+        Gauge(AtomicI64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A distribution of durations over fixed buckets.
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Histogram {
+            buckets: [ZERO; BUCKETS.len() + 1],
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut count = 0;
+        let mut buckets = Vec::with_capacity(BUCKETS.len() + 1);
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            buckets.push((BUCKETS.get(i).copied().unwrap_or(f64::INFINITY), count));
+        }
+        HistogramSnapshot {
+            buckets,
+            sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)).as_secs_f64(),
+            count,
+        }
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Kinds of UCX requests whose latency is recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    TagSend,
+    TagRecv,
+    StreamSend,
+    StreamRecv,
+    EpClose,
+}
+
+impl Operation {
+    const ALL: [Operation; 5] = [
+        Operation::TagSend,
+        Operation::TagRecv,
+        Operation::StreamSend,
+        Operation::StreamRecv,
+        Operation::EpClose,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::TagSend => "tag_send",
+            Operation::TagRecv => "tag_recv",
+            Operation::StreamSend => "stream_send",
+            Operation::StreamRecv => "stream_recv",
+            Operation::EpClose => "ep_close",
+        }
+    }
+}
+
+/// Traffic counters of one endpoint.
+#[derive(Debug)]
+pub struct EndpointMetrics {
+    id: u64,
+    peer: Option<String>,
+    pub bytes_sent: Counter,
+    pub bytes_received: Counter,
+}
+
+impl EndpointMetrics {
+    /// Creates and registers the counters of a new endpoint.
+    pub(crate) fn register(peer: Option<String>) -> Arc<Self> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        let metrics = Arc::new(EndpointMetrics {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            peer,
+            bytes_sent: Counter::new(),
+            bytes_received: Counter::new(),
+        });
+        let mut endpoints = REGISTRY.endpoints.lock().unwrap();
+        endpoints.retain(|endpoint| endpoint.strong_count() > 0);
+        endpoints.push(Arc::downgrade(&metrics));
+        metrics
+    }
+}
+
+#[derive(Debug, Default)]
+struct RpcMetrics {
+    latency: Histogram,
+    errors: Counter,
+}
+
+struct Registry {
+    in_flight: [Gauge; Operation::ALL.len()],
+    latency: [Histogram; Operation::ALL.len()],
+    progress_busy: Counter,
+    progress_idle: Counter,
+    accepted: Counter,
+    rejected: Counter,
+    endpoints: Mutex<Vec<Weak<EndpointMetrics>>>,
+    // Keyed by side and method.
+    rpc: Mutex<BTreeMap<(&'static str, String), Arc<RpcMetrics>>>,
+}
+
+static REGISTRY: Registry = Registry {
+    in_flight: [const { Gauge::new() }; Operation::ALL.len()],
+    latency: [const { Histogram::new() }; Operation::ALL.len()],
+    progress_busy: Counter::new(),
+    progress_idle: Counter::new(),
+    accepted: Counter::new(),
+    rejected: Counter::new(),
+    endpoints: Mutex::new(Vec::new()),
+    rpc: Mutex::new(BTreeMap::new()),
+};
+
+pub(crate) fn operation_started(op: Operation) {
+    REGISTRY.in_flight[op as usize].inc();
+}
+
+pub(crate) fn operation_finished(op: Operation) {
+    REGISTRY.in_flight[op as usize].dec();
+}
+
+pub(crate) fn operation_completed(op: Operation, latency: Duration) {
+    REGISTRY.latency[op as usize].observe(latency);
+}
+
+pub(crate) fn worker_progressed(events: u32) {
+    if events > 0 {
+        REGISTRY.progress_busy.inc();
+    } else {
+        REGISTRY.progress_idle.inc();
+    }
+}
+
+pub(crate) fn connection_accepted() {
+    REGISTRY.accepted.inc();
+}
+
+pub(crate) fn connection_rejected() {
+    REGISTRY.rejected.inc();
+}
+
+/// The method label of calls to methods whose name is not known.
+pub(crate) const UNKNOWN_METHOD: &str = "unknown";
+
+/// Records an RPC seen from `side` ("client" or "server").
+pub(crate) fn rpc_completed(side: &'static str, method: &str, latency: Duration, ok: bool) {
+    let metrics = REGISTRY
+        .rpc
+        .lock()
+        .unwrap()
+        .entry((side, method.to_string()))
+        .or_default()
+        .clone();
+    metrics.latency.observe(latency);
+    if !ok {
+        metrics.errors.inc();
+    }
+}
+
+/// Reads the current value of every metric.
+pub fn snapshot() -> Snapshot {
+    let mut samples = Vec::new();
+    let mut push = |name, help, labels: Vec<(&'static str, String)>, value| {
+        samples.push(Sample {
+            name,
+            help,
+            labels,
+            value,
+        })
+    };
+
+    let endpoints = REGISTRY
+        .endpoints
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect::<Vec<_>>();
+    let endpoint_labels = |endpoint: &EndpointMetrics| {
+        let mut labels = vec![("endpoint", endpoint.id.to_string())];
+        if let Some(peer) = &endpoint.peer {
+            labels.push(("peer", peer.clone()));
+        }
+        labels
+    };
+    for endpoint in &endpoints {
+        push(
+            "ucx_endpoint_sent_bytes_total",
+            "Bytes sent on the endpoint.",
+            endpoint_labels(endpoint),
+            Value::Counter(endpoint.bytes_sent.get()),
+        );
+    }
+    for endpoint in &endpoints {
+        push(
+            "ucx_endpoint_received_bytes_total",
+            "Bytes received through the endpoint.",
+            endpoint_labels(endpoint),
+            Value::Counter(endpoint.bytes_received.get()),
+        );
+    }
+
+    for op in Operation::ALL {
+        push(
+            "ucx_operations_in_flight",
+            "UCX requests that have not been released yet.",
+            vec![("op", op.name().to_string())],
+            Value::Gauge(REGISTRY.in_flight[op as usize].get()),
+        );
+    }
+    for op in Operation::ALL {
+        push(
+            "ucx_operation_duration_seconds",
+            "Time from posting a UCX request to its completion.",
+            vec![("op", op.name().to_string())],
+            Value::Histogram(REGISTRY.latency[op as usize].snapshot()),
+        );
+    }
+
+    for (result, counter) in [
+        ("busy", &REGISTRY.progress_busy),
+        ("idle", &REGISTRY.progress_idle),
+    ] {
+        push(
+            "ucx_worker_progress_total",
+            "Calls of Worker::progress, by whether they made progress.",
+            vec![("result", result.to_string())],
+            Value::Counter(counter.get()),
+        );
+    }
+    for (result, counter) in [
+        ("accepted", &REGISTRY.accepted),
+        ("rejected", &REGISTRY.rejected),
+    ] {
+        push(
+            "ucx_connections_total",
+            "Connection requests, by outcome.",
+            vec![("result", result.to_string())],
+            Value::Counter(counter.get()),
+        );
+    }
+
+    let rpc = REGISTRY.rpc.lock().unwrap().clone();
+    for ((side, method), metrics) in &rpc {
+        push(
+            "ucx_rpc_duration_seconds",
+            "Latency of RPC calls.",
+            vec![("side", side.to_string()), ("method", method.clone())],
+            Value::Histogram(metrics.latency.snapshot()),
+        );
+    }
+    for ((side, method), metrics) in &rpc {
+        push(
+            "ucx_rpc_errors_total",
+            "RPC calls that failed.",
+            vec![("side", side.to_string()), ("method", method.clone())],
+            Value::Counter(metrics.errors.get()),
+        );
+    }
+
+    Snapshot { samples }
+}
+
+/// The values of all metrics at one point in time.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// The samples, grouped by metric name.
+    pub samples: Vec<Sample>,
+}
+
+/// One labelled value of a metric.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub labels: Vec<(&'static str, String)>,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Counter(u64),
+    Gauge(i64),
+    Histogram(HistogramSnapshot),
+}
+
+/// The state of a [`Histogram`].
+#[derive(Debug, Clone)]
+pub struct HistogramSnapshot {
+    /// Upper bounds in seconds with the cumulative count of observations.
+    pub buckets: Vec<(f64, u64)>,
+    /// The sum of all observations in seconds.
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Snapshot {
+    /// Renders the snapshot in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut last = "";
+        for sample in &self.samples {
+            if sample.name != last {
+                let kind = match sample.value {
+                    Value::Counter(_) => "counter",
+                    Value::Gauge(_) => "gauge",
+                    Value::Histogram(_) => "histogram",
+                };
+                let _ = writeln!(out, "# HELP {} {}", sample.name, sample.help);
+                let _ = writeln!(out, "# TYPE {} {}", sample.name, kind);
+                last = sample.name;
+            }
+            let labels = format_labels(&sample.labels, None);
+            match &sample.value {
+                Value::Counter(value) => {
+                    let _ = writeln!(out, "{}{} {}", sample.name, labels, value);
+                }
+                Value::Gauge(value) => {
+                    let _ = writeln!(out, "{}{} {}", sample.name, labels, value);
+                }
+                Value::Histogram(histogram) => {
+                    for (bound, count) in &histogram.buckets {
+                        let le = if bound.is_infinite() {
+                            "+Inf".to_string()
+                        } else {
+                            bound.to_string()
+                        };
+                        let labels = format_labels(&sample.labels, Some(&le));
+                        let _ = writeln!(out, "{}_bucket{} {}", sample.name, labels, count);
+                    }
+                    let _ = writeln!(out, "{}_sum{} {}", sample.name, labels, histogram.sum);
+                    let _ = writeln!(out, "{}_count{} {}", sample.name, labels, histogram.count);
+                }
+            }
+        }
+        out
+    }
+
+    /// Writes [`Snapshot::to_prometheus`] into `w`.
+    pub fn write_prometheus(&self, w: &mut impl io::Write) -> io::Result<()> {
+        w.write_all(self.to_prometheus().as_bytes())
+    }
+}
+
+fn format_labels(labels: &[(&'static str, String)], le: Option<&str>) -> String {
+    let mut pairs = labels
+        .iter()
+        .map(|(key, value)| format!("{key}=\"{}\"", escape(value)))
+        .collect::<Vec<_>>();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{le}\""));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(name: &'static str, labels: Vec<(&'static str, String)>, value: Value) -> Sample {
+        Sample {
+            name,
+            help: "Help text.",
+            labels,
+            value,
+        }
+    }
+
+    #[test]
+    fn escape_quotes_backslashes_and_newlines() {
+        assert_eq!(escape("plain"), "plain");
+        assert_eq!(escape(r#"a"b"#), r#"a\"b"#);
+        assert_eq!(escape(r"a\b"), r"a\\b");
+        assert_eq!(escape("a\nb"), r"a\nb");
+        assert_eq!(escape("\\\"\n"), r#"\\\"\n"#);
+    }
+
+    #[test]
+    fn format_labels_joins_pairs_and_appends_le() {
+        assert_eq!(format_labels(&[], None), "");
+        assert_eq!(format_labels(&[], Some("+Inf")), r#"{le="+Inf"}"#);
+        let labels = [
+            ("side", "client".to_string()),
+            ("method", "Svc/\"m\"".to_string()),
+        ];
+        assert_eq!(
+            format_labels(&labels, None),
+            r#"{side="client",method="Svc/\"m\""}"#
+        );
+        assert_eq!(
+            format_labels(&labels, Some("0.5")),
+            r#"{side="client",method="Svc/\"m\"",le="0.5"}"#
+        );
+    }
+
+    #[test]
+    fn to_prometheus_writes_help_and_type_once_per_metric() {
+        let snapshot = Snapshot {
+            samples: vec![
+                sample(
+                    "requests_total",
+                    vec![("result", "ok".to_string())],
+                    Value::Counter(3),
+                ),
+                sample(
+                    "requests_total",
+                    vec![("result", "error".to_string())],
+                    Value::Counter(1),
+                ),
+                sample("in_flight", Vec::new(), Value::Gauge(-2)),
+            ],
+        };
+        assert_eq!(
+            snapshot.to_prometheus(),
+            "# HELP requests_total Help text.\n\
+             # TYPE requests_total counter\n\
+             requests_total{result=\"ok\"} 3\n\
+             requests_total{result=\"error\"} 1\n\
+             # HELP in_flight Help text.\n\
+             # TYPE in_flight gauge\n\
+             in_flight -2\n"
+        );
+    }
+
+    #[test]
+    fn to_prometheus_writes_cumulative_histogram_buckets() {
+        let histogram = Histogram::new();
+        histogram.observe(Duration::from_micros(1));
+        histogram.observe(Duration::from_millis(2));
+        histogram.observe(Duration::from_secs(60));
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 3);
+        assert_eq!(snapshot.buckets.len(), BUCKETS.len() + 1);
+        assert_eq!(snapshot.buckets[0], (1e-6, 1));
+        assert_eq!(snapshot.buckets[7], (5e-3, 2));
+        assert_eq!(snapshot.buckets[BUCKETS.len()], (f64::INFINITY, 3));
+
+        let text = Snapshot {
+            samples: vec![sample(
+                "latency_seconds",
+                vec![("op", "send".to_string())],
+                Value::Histogram(snapshot),
+            )],
+        }
+        .to_prometheus();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[1], "# TYPE latency_seconds histogram");
+        assert_eq!(
+            lines[2],
+            r#"latency_seconds_bucket{op="send",le="0.000001"} 1"#
+        );
+        assert!(lines.contains(&r#"latency_seconds_bucket{op="send",le="0.005"} 2"#));
+        assert!(lines.contains(&r#"latency_seconds_bucket{op="send",le="+Inf"} 3"#));
+        assert!(lines.contains(&r#"latency_seconds_sum{op="send"} 60.002001"#));
+        assert_eq!(lines.last(), Some(&r#"latency_seconds_count{op="send"} 3"#));
+    }
+}
@@ -0,0 +1,135 @@
+//! Wire format of pub/sub frames.
+//!
+//! A frame is a kind byte followed by its little-endian fields. Topics are a
+//! `u16` length and the UTF-8 name; payloads take the rest of the frame.
+
+use crate::Error;
+
+const ATTACH: u8 = 1;
+const SUBSCRIBE: u8 = 2;
+const UNSUBSCRIBE: u8 = 3;
+const PUBLISH: u8 = 4;
+const CREDIT: u8 = 5;
+const DETACH: u8 = 6;
+const MESSAGE: u8 = 7;
+const CLOSED: u8 = 8;
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Frame<'a> {
+    /// Sent by a subscriber once connected: where to send its messages and
+    /// how many it takes before granting more credits.
+    Attach {
+        inbox: u32,
+        window: u32,
+    },
+    Subscribe(&'a str),
+    Unsubscribe(&'a str),
+    /// A message a subscriber asks a broker to publish.
+    Publish {
+        topic: &'a str,
+        payload: &'a [u8],
+    },
+    /// Grants the publisher more messages.
+    Credit(u32),
+    /// The subscriber is going away.
+    Detach,
+    /// A message for a subscriber.
+    Message {
+        topic: &'a str,
+        payload: &'a [u8],
+    },
+    /// The publisher disconnected the subscriber, whose queue overflowed.
+    Closed,
+}
+
+impl<'a> Frame<'a> {
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        match *self {
+            Frame::Attach { inbox, window } => {
+                bytes.push(ATTACH);
+                bytes.extend_from_slice(&inbox.to_le_bytes());
+                bytes.extend_from_slice(&window.to_le_bytes());
+            }
+            Frame::Subscribe(topic) => {
+                bytes.push(SUBSCRIBE);
+                put_topic(&mut bytes, topic)?;
+            }
+            Frame::Unsubscribe(topic) => {
+                bytes.push(UNSUBSCRIBE);
+                put_topic(&mut bytes, topic)?;
+            }
+            Frame::Publish { topic, payload } => {
+                bytes.push(PUBLISH);
+                put_topic(&mut bytes, topic)?;
+                bytes.extend_from_slice(payload);
+            }
+            Frame::Credit(credits) => {
+                bytes.push(CREDIT);
+                bytes.extend_from_slice(&credits.to_le_bytes());
+            }
+            Frame::Detach => bytes.push(DETACH),
+            Frame::Message { topic, payload } => {
+                bytes.push(MESSAGE);
+                put_topic(&mut bytes, topic)?;
+                bytes.extend_from_slice(payload);
+            }
+            Frame::Closed => bytes.push(CLOSED),
+        }
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &'a [u8]) -> Result<Self, Error> {
+        let (&kind, rest) = bytes.split_first().ok_or_else(truncated)?;
+        let frame = match kind {
+            ATTACH => Frame::Attach {
+                inbox: get_u32(rest, 0)?,
+                window: get_u32(rest, 4)?,
+            },
+            SUBSCRIBE => Frame::Subscribe(get_topic(rest)?.0),
+            UNSUBSCRIBE => Frame::Unsubscribe(get_topic(rest)?.0),
+            PUBLISH => {
+                let (topic, payload) = get_topic(rest)?;
+                Frame::Publish { topic, payload }
+            }
+            CREDIT => Frame::Credit(get_u32(rest, 0)?),
+            DETACH => Frame::Detach,
+            MESSAGE => {
+                let (topic, payload) = get_topic(rest)?;
+                Frame::Message { topic, payload }
+            }
+            CLOSED => Frame::Closed,
+            kind => return Err(Error::Codec(format!("unknown pubsub frame kind {kind}"))),
+        };
+        Ok(frame)
+    }
+}
+
+fn truncated() -> Error {
+    Error::Codec("truncated pubsub frame".to_string())
+}
+
+fn put_topic(bytes: &mut Vec<u8>, topic: &str) -> Result<(), Error> {
+    let len = u16::try_from(topic.len()).map_err(|_| Error::Codec("topic too long".to_string()))?;
+    bytes.extend_from_slice(&len.to_le_bytes());
+    bytes.extend_from_slice(topic.as_bytes());
+    Ok(())
+}
+
+fn get_u32(bytes: &[u8], offset: usize) -> Result<u32, Error> {
+    let bytes = bytes.get(offset..offset + 4).ok_or_else(truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+// Splits the topic off the front of `bytes`.
+fn get_topic(bytes: &[u8]) -> Result<(&str, &[u8]), Error> {
+    let (len, rest) = bytes.split_first_chunk::<2>().ok_or_else(truncated)?;
+    let len = u16::from_le_bytes(*len) as usize;
+    if rest.len() < len {
+        return Err(truncated());
+    }
+    let (topic, rest) = rest.split_at(len);
+    let topic =
+        std::str::from_utf8(topic).map_err(|_| Error::Codec("topic is not UTF-8".to_string()))?;
+    Ok((topic, rest))
+}
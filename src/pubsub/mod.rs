@@ -0,0 +1,69 @@
+//! Publish/subscribe messaging over UCX endpoints.
+//!
+//! A [`Publisher`] accepts connections from [`Subscriber`]s and sends every
+//! message published on a topic to the subscribers of that topic. Run by the
+//! process producing the messages, it connects publishers and subscribers
+//! directly; run as a broker with [`Publisher::broker`], it also relays the
+//! messages its subscribers publish, so any number of processes can publish
+//! and subscribe through one well-known address.
+//!
+//! Subscribers grant the publisher credits for the messages they are ready to
+//! take. Messages for a subscriber without credits wait in a bounded queue at
+//! the publisher, and the [`OverflowPolicy`] decides what happens when it is
+//! full.
+//!
+//! Frames travel on the [`PUBSUB`](crate::tag::PUBSUB) tag channel. The
+//! sequence field tells the direction: the publisher sends the connection id
+//! it assigned on [`HELLO`], subscribers send on [`TO_PUBLISHER`] with their
+//! connection id as the source, and the publisher sends messages on
+//! [`TO_SUBSCRIBER`] with the inbox id the subscriber picked as the source.
+
+mod frame;
+pub mod publisher;
+pub mod subscriber;
+
+pub use self::publisher::Publisher;
+pub use self::subscriber::Subscriber;
+
+const HELLO: u32 = 0;
+const TO_PUBLISHER: u32 = 1;
+const TO_SUBSCRIBER: u32 = 2;
+
+/// A message published on a topic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// The topic the message was published on.
+    pub topic: String,
+    /// The published bytes.
+    pub payload: Vec<u8>,
+}
+
+/// What a publisher does with a message for a subscriber whose queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Drops the oldest queued message to make room.
+    #[default]
+    DropOldest,
+    /// Makes [`Publisher::publish`] wait until the subscriber grants credits.
+    Block,
+    /// Disconnects the subscriber.
+    Disconnect,
+}
+
+/// Bounds the messages a publisher keeps for each subscriber.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueOptions {
+    /// The number of messages queued for a subscriber without credits.
+    pub capacity: usize,
+    /// What happens when the queue is full.
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        QueueOptions {
+            capacity: 1024,
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
@@ -0,0 +1,310 @@
+//! Fan-out side of pub/sub connections.
+
+use super::frame::Frame;
+use super::*;
+use crate::tag::{self, PUBSUB};
+use crate::ucp::endpoint::Endpoint;
+use crate::ucp::listener::{ConnectionRequest, Listener};
+use crate::ucp::{TagMessage, Worker};
+use crate::Error;
+use derivative::Derivative;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use tracing::{debug, info, warn};
+
+/// Sends published messages to the subscribers of their topic.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct Publisher {
+    worker: Rc<Worker>,
+    options: QueueOptions,
+    relay: bool,
+    subscribers: RefCell<HashMap<u32, Subscription>>,
+    #[derivative(Debug = "ignore")]
+    pending: RefCell<Vec<ConnectionRequest>>,
+    // Messages subscribers asked a broker to publish, published by `progress`.
+    relayed: RefCell<VecDeque<Message>>,
+    next_conn_id: Cell<u32>,
+}
+
+#[derive(Debug)]
+struct Subscription {
+    ep: Rc<Endpoint>,
+    // Where messages go, known once the subscriber has attached.
+    inbox: Option<u32>,
+    topics: HashSet<String>,
+    credits: u32,
+    queue: VecDeque<Rc<Message>>,
+}
+
+impl Publisher {
+    /// Creates a publisher that sends the messages of this process, driven by `worker`.
+    pub fn new(worker: &Rc<Worker>, options: QueueOptions) -> Rc<Self> {
+        Self::with_relay(worker, options, false)
+    }
+
+    /// Creates a broker, which also publishes the messages its subscribers send it.
+    pub fn broker(worker: &Rc<Worker>, options: QueueOptions) -> Rc<Self> {
+        Self::with_relay(worker, options, true)
+    }
+
+    fn with_relay(worker: &Rc<Worker>, options: QueueOptions, relay: bool) -> Rc<Self> {
+        Rc::new(Publisher {
+            worker: worker.clone(),
+            options: QueueOptions {
+                capacity: options.capacity.max(1),
+                ..options
+            },
+            relay,
+            subscribers: RefCell::new(HashMap::new()),
+            pending: RefCell::new(Vec::new()),
+            relayed: RefCell::new(VecDeque::new()),
+            next_conn_id: Cell::new(1),
+        })
+    }
+
+    /// Listens for subscribers on `addr`.
+    ///
+    /// Connection requests are accepted by [`Publisher::progress`]. The
+    /// publisher stops listening when the returned listener is dropped.
+    pub fn listen(self: &Rc<Self>, addr: SocketAddr) -> Result<Rc<Listener<Rc<Publisher>>>, Error> {
+        unsafe { Listener::create(&self.worker, addr, Self::on_conn_request, self.clone()) }
+    }
+
+    // Called from inside `Worker::progress`, so the request is only queued here.
+    unsafe fn on_conn_request(conn_req: ConnectionRequest, _: Rc<Worker>, publisher: Rc<Self>) {
+        publisher.pending.borrow_mut().push(conn_req);
+    }
+
+    /// Accepts `conn_req` from a subscriber and returns its connection id.
+    pub fn accept(&self, conn_req: ConnectionRequest) -> Result<u32, Error> {
+        let ep = unsafe { Endpoint::from_conn_req(self.worker.clone(), conn_req)? };
+        let conn_id = self.next_conn_id.get();
+        self.next_conn_id
+            .set(match (conn_id + 1) & tag::SOURCE.max() as u32 {
+                0 => 1,
+                next => next,
+            });
+        ep.send_msg(PUBSUB.tag(0, HELLO), &conn_id)?;
+        self.subscribers.borrow_mut().insert(
+            conn_id,
+            Subscription {
+                ep: Rc::new(ep),
+                inbox: None,
+                topics: HashSet::new(),
+                credits: 0,
+                queue: VecDeque::new(),
+            },
+        );
+        info!(conn_id, "subscriber connected");
+        Ok(conn_id)
+    }
+
+    /// The number of subscribers of `topic`.
+    pub fn subscriber_count(&self, topic: &str) -> usize {
+        self.subscribers
+            .borrow()
+            .values()
+            .filter(|sub| sub.topics.contains(topic))
+            .count()
+    }
+
+    /// Sends `payload` to every subscriber of `topic` and returns their number.
+    ///
+    /// Subscribers without credits get the message once they grant more; if
+    /// their queue is full, the [`OverflowPolicy`] applies.
+    pub fn publish(&self, topic: &str, payload: &[u8]) -> Result<usize, Error> {
+        let message = Rc::new(Message {
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+        });
+        let conn_ids: Vec<u32> = self
+            .subscribers
+            .borrow()
+            .iter()
+            .filter(|(_, sub)| sub.topics.contains(topic))
+            .map(|(&conn_id, _)| conn_id)
+            .collect();
+        for &conn_id in &conn_ids {
+            self.deliver(conn_id, &message);
+        }
+        Ok(conn_ids.len())
+    }
+
+    /// Makes progress on the worker, accepts pending connections, handles
+    /// the frames subscribers sent and, on a broker, publishes the messages
+    /// they sent.
+    ///
+    /// Returns the number of events processed.
+    pub fn progress(&self) -> usize {
+        let mut events = self.pump();
+
+        let pending = std::mem::take(&mut *self.pending.borrow_mut());
+        for conn_req in pending {
+            events += 1;
+            if let Err(e) = self.accept(conn_req) {
+                warn!("failed to accept subscriber: {e}");
+            }
+        }
+        loop {
+            let Some(message) = self.relayed.borrow_mut().pop_front() else {
+                break;
+            };
+            events += 1;
+            if let Err(e) = self.publish(&message.topic, &message.payload) {
+                warn!("failed to relay message on {}: {e}", message.topic);
+            }
+        }
+        events
+    }
+
+    /// Calls [`Publisher::progress`] until `stop` returns true.
+    pub fn run_until(&self, stop: impl Fn() -> bool) {
+        while !stop() {
+            self.progress();
+        }
+    }
+
+    // Progresses the worker and handles subscriber frames, except that
+    // messages to relay are only queued, as this runs inside `publish`.
+    fn pump(&self) -> usize {
+        let mut events = self.worker.progress() as usize;
+        self.subscribers.borrow_mut().retain(|conn_id, sub| {
+            let closed = *sub.ep.closed.borrow();
+            if closed {
+                info!(conn_id, "subscriber disconnected");
+            }
+            !closed
+        });
+        while let Some(message) = self.worker.tag_probe(
+            PUBSUB.tag(0, TO_PUBLISHER),
+            tag::CHANNEL.mask() | tag::SEQUENCE.mask(),
+        ) {
+            events += 1;
+            if let Err(e) = self.handle(message) {
+                warn!("failed to handle subscriber frame: {e}");
+            }
+        }
+        events
+    }
+
+    fn handle(&self, message: TagMessage) -> Result<(), Error> {
+        let (conn_id, _) = tag::TagChannel::split(message.sender_tag);
+        let bytes = self.worker.recv_probed(message)?;
+        let frame = Frame::decode(&bytes)?;
+        let mut subscribers = self.subscribers.borrow_mut();
+        let sub = subscribers.get_mut(&conn_id).ok_or(Error::NotConnected)?;
+        sub.ep.metrics().bytes_received.add(bytes.len() as u64);
+        match frame {
+            Frame::Attach { inbox, window } => {
+                sub.inbox = Some(inbox);
+                sub.credits = window;
+            }
+            Frame::Subscribe(topic) => {
+                debug!(conn_id, topic, "subscribed");
+                sub.topics.insert(topic.to_string());
+            }
+            Frame::Unsubscribe(topic) => {
+                debug!(conn_id, topic, "unsubscribed");
+                sub.topics.remove(topic);
+            }
+            Frame::Publish { topic, payload } if self.relay => {
+                self.relayed.borrow_mut().push_back(Message {
+                    topic: topic.to_string(),
+                    payload: payload.to_vec(),
+                });
+            }
+            Frame::Publish { topic, .. } => {
+                warn!(
+                    conn_id,
+                    topic, "dropping message sent to a publisher that is not a broker"
+                );
+            }
+            Frame::Credit(credits) => sub.credits = sub.credits.saturating_add(credits),
+            Frame::Detach => {
+                info!(conn_id, "subscriber detached");
+                subscribers.remove(&conn_id);
+                return Ok(());
+            }
+            frame => {
+                return Err(Error::Codec(format!(
+                    "unexpected frame from subscriber: {frame:?}"
+                )));
+            }
+        }
+        drop(subscribers);
+        self.flush(conn_id);
+        Ok(())
+    }
+
+    fn deliver(&self, conn_id: u32, message: &Rc<Message>) {
+        loop {
+            let mut subscribers = self.subscribers.borrow_mut();
+            let Some(sub) = subscribers.get_mut(&conn_id) else {
+                return;
+            };
+            if sub.queue.len() < self.options.capacity {
+                sub.queue.push_back(message.clone());
+                drop(subscribers);
+                self.flush(conn_id);
+                return;
+            }
+            match self.options.overflow {
+                OverflowPolicy::DropOldest => {
+                    debug!(
+                        conn_id,
+                        "subscriber queue full, dropping the oldest message"
+                    );
+                    sub.queue.pop_front();
+                    sub.queue.push_back(message.clone());
+                    return;
+                }
+                OverflowPolicy::Disconnect => {
+                    let sub = subscribers.remove(&conn_id).unwrap();
+                    drop(subscribers);
+                    warn!(conn_id, "subscriber queue full, disconnecting");
+                    if let (Some(inbox), Ok(frame)) = (sub.inbox, Frame::Closed.encode()) {
+                        let _ = sub.ep.send_bytes(PUBSUB.tag(inbox, TO_SUBSCRIBER), &frame);
+                    }
+                    return;
+                }
+                OverflowPolicy::Block => {
+                    drop(subscribers);
+                    self.pump();
+                }
+            }
+        }
+    }
+
+    // Sends queued messages while the subscriber has credits.
+    fn flush(&self, conn_id: u32) {
+        loop {
+            let mut subscribers = self.subscribers.borrow_mut();
+            let Some(sub) = subscribers.get_mut(&conn_id) else {
+                return;
+            };
+            let Some(inbox) = sub.inbox.filter(|_| sub.credits > 0) else {
+                return;
+            };
+            let Some(message) = sub.queue.pop_front() else {
+                return;
+            };
+            sub.credits -= 1;
+            let ep = sub.ep.clone();
+            drop(subscribers);
+            let frame = Frame::Message {
+                topic: &message.topic,
+                payload: &message.payload,
+            }
+            .encode()
+            .and_then(|frame| ep.send_bytes(PUBSUB.tag(inbox, TO_SUBSCRIBER), &frame));
+            if let Err(e) = frame {
+                warn!(conn_id, "failed to send to subscriber, disconnecting: {e}");
+                self.subscribers.borrow_mut().remove(&conn_id);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Receiving side of pub/sub connections.
+
+use super::frame::Frame;
+use super::*;
+use crate::tag::{self, PUBSUB};
+use crate::ucp::endpoint::Endpoint;
+use crate::Error;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// The number of messages a subscriber takes before granting more credits.
+pub const DEFAULT_WINDOW: u32 = 64;
+
+// Inboxes only need to be unique per worker, but a process-wide counter is
+// simpler, like the call ids of the RPC client.
+static NEXT_INBOX: AtomicU32 = AtomicU32::new(1);
+
+/// A connection to a [`Publisher`] receiving the messages of the subscribed topics.
+#[derive(Debug)]
+pub struct Subscriber {
+    ep: Rc<Endpoint>,
+    conn_id: u32,
+    inbox: u32,
+    window: u32,
+    // Messages taken since credits were last granted.
+    consumed: Cell<u32>,
+    queue: RefCell<VecDeque<Message>>,
+    closed: Cell<bool>,
+}
+
+impl Subscriber {
+    /// Waits for the publisher to accept `ep` and attaches to it.
+    pub fn connect(ep: Rc<Endpoint>) -> Result<Self, Error> {
+        Self::connect_with_window(ep, DEFAULT_WINDOW)
+    }
+
+    /// Like [`Subscriber::connect`], letting the publisher send up to
+    /// `window` messages ahead of the ones taken.
+    pub fn connect_with_window(ep: Rc<Endpoint>, window: u32) -> Result<Self, Error> {
+        let conn_id: u32 = ep.recv_msg(
+            PUBSUB.tag(0, HELLO),
+            tag::CHANNEL.mask() | tag::SEQUENCE.mask(),
+        )?;
+        let inbox = NEXT_INBOX.fetch_add(1, Ordering::Relaxed) & tag::SOURCE.max() as u32;
+        let window = window.max(1);
+        let subscriber = Subscriber {
+            ep,
+            conn_id,
+            inbox,
+            window,
+            consumed: Cell::new(0),
+            queue: RefCell::new(VecDeque::new()),
+            closed: Cell::new(false),
+        };
+        subscriber.send(Frame::Attach { inbox, window })?;
+        debug!(conn_id, inbox, "subscriber attached");
+        Ok(subscriber)
+    }
+
+    /// The endpoint connected to the publisher.
+    pub fn endpoint(&self) -> &Rc<Endpoint> {
+        &self.ep
+    }
+
+    /// Starts receiving the messages published on `topic`.
+    pub fn subscribe(&self, topic: &str) -> Result<(), Error> {
+        self.send(Frame::Subscribe(topic))
+    }
+
+    /// Stops receiving the messages published on `topic`.
+    ///
+    /// Messages already sent are still received.
+    pub fn unsubscribe(&self, topic: &str) -> Result<(), Error> {
+        self.send(Frame::Unsubscribe(topic))
+    }
+
+    /// Publishes a message through a broker, see [`Publisher::broker`].
+    ///
+    /// Other publishers drop the message.
+    pub fn publish(&self, topic: &str, payload: &[u8]) -> Result<(), Error> {
+        self.send(Frame::Publish { topic, payload })
+    }
+
+    /// Takes the next message if one has arrived.
+    ///
+    /// Fails with [`Error::ConnectionReset`] once the publisher has
+    /// disconnected the subscriber and all received messages are taken.
+    pub fn try_recv(&self) -> Result<Option<Message>, Error> {
+        self.poll()?;
+        let message = self.queue.borrow_mut().pop_front();
+        match message {
+            Some(message) => {
+                self.consumed.set(self.consumed.get() + 1);
+                // Credits go back in batches to save messages.
+                if self.consumed.get() >= (self.window / 2).max(1) && !self.closed.get() {
+                    self.send(Frame::Credit(self.consumed.take()))?;
+                }
+                Ok(Some(message))
+            }
+            None if self.closed.get() => Err(Error::ConnectionReset),
+            None => Ok(None),
+        }
+    }
+
+    /// Waits for the next message.
+    pub fn recv(&self) -> Result<Message, Error> {
+        self.recv_until(None)
+    }
+
+    /// Waits up to `timeout` for the next message.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Message, Error> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    /// Waits for the next message until `deadline`, failing with [`Error::Timeout`].
+    pub fn recv_until(&self, deadline: Option<Instant>) -> Result<Message, Error> {
+        loop {
+            if let Some(message) = self.try_recv()? {
+                return Ok(message);
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(Error::Timeout);
+            }
+        }
+    }
+
+    // Moves the frames that have arrived into the queue.
+    fn poll(&self) -> Result<(), Error> {
+        let worker = &self.ep.worker;
+        worker.progress();
+        if *self.ep.closed.borrow() {
+            self.closed.set(true);
+        }
+        let tag = PUBSUB.tag(self.inbox, TO_SUBSCRIBER);
+        while let Some(message) = worker.tag_probe(tag, u64::MAX) {
+            let bytes = worker.recv_probed(message)?;
+            self.ep.metrics().bytes_received.add(bytes.len() as u64);
+            match Frame::decode(&bytes)? {
+                Frame::Message { topic, payload } => self.queue.borrow_mut().push_back(Message {
+                    topic: topic.to_string(),
+                    payload: payload.to_vec(),
+                }),
+                Frame::Closed => {
+                    warn!(conn_id = self.conn_id, "disconnected by the publisher");
+                    self.closed.set(true);
+                }
+                frame => debug!(conn_id = self.conn_id, ?frame, "dropping unexpected frame"),
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&self, frame: Frame) -> Result<(), Error> {
+        if self.closed.get() {
+            return Err(Error::ConnectionReset);
+        }
+        let tag = PUBSUB.tag(self.conn_id, TO_PUBLISHER);
+        self.ep.send_bytes(tag, &frame.encode()?)
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        if let Err(e) = self.send(Frame::Detach) {
+            debug!(conn_id = self.conn_id, "failed to detach: {e}");
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Endpoints that reconnect after failures.
+//!
+//! An [`Endpoint`] whose peer goes away is closed for good. A
+//! [`ReconnectingEndpoint`] remembers the address it connected to and creates
+//! a new endpoint when the current one is closed, waiting between failed
+//! attempts as its [`Backoff`] says.
+
+use crate::rpc::trace::random_u64;
+use crate::ucp::endpoint::Endpoint;
+use crate::ucp::Worker;
+use crate::Error;
+use std::cell::{Cell, RefCell};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// Exponential backoff with jitter between reconnection attempts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    /// The delay before the second attempt; the first one is immediate.
+    pub initial: Duration,
+    /// The longest delay between two attempts.
+    pub max: Duration,
+    /// The factor the delay grows by after each failed attempt.
+    pub multiplier: f64,
+    /// The fraction of the delay that is randomized, between 0 and 1, so
+    /// that clients of a restarted server do not all reconnect at once.
+    pub jitter: f64,
+    /// The number of attempts before giving up; `None` keeps trying.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay before attempt number `attempt`, counting from 0.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        if attempt == 0 {
+            return Duration::ZERO;
+        }
+        let exp = self.multiplier.max(1.0).powi(attempt as i32 - 1);
+        let delay = self.initial.as_secs_f64() * exp;
+        let delay = delay.min(self.max.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        // Uniform in [1 - jitter, 1].
+        let factor = 1.0 - jitter * (random_u64() as f64 / u64::MAX as f64);
+        Duration::from_secs_f64(delay * factor)
+    }
+
+    /// Returns true if attempt number `attempt` may be made.
+    pub fn allows(&self, attempt: u32) -> bool {
+        self.max_attempts.is_none_or(|max| attempt < max)
+    }
+
+    // Makes progress on `worker` until attempt number `attempt` is due, so
+    // that its other users carry on meanwhile, failing with `Error::Timeout`
+    // if it would start after `deadline`.
+    pub(crate) fn wait(
+        &self,
+        worker: &Worker,
+        attempt: u32,
+        deadline: Option<Instant>,
+    ) -> Result<(), Error> {
+        let due = Instant::now() + self.delay(attempt);
+        if deadline.is_some_and(|deadline| due >= deadline) {
+            return Err(Error::Timeout);
+        }
+        while Instant::now() < due {
+            worker.progress();
+        }
+        Ok(())
+    }
+}
+
+/// An endpoint to a socket address that is recreated when it is closed.
+#[derive(Debug)]
+pub struct ReconnectingEndpoint {
+    worker: Rc<Worker>,
+    addr: SocketAddr,
+    backoff: Backoff,
+    current: RefCell<Option<Rc<Endpoint>>>,
+    reconnects: Cell<u64>,
+}
+
+impl ReconnectingEndpoint {
+    /// Creates a handle to `addr` that connects on first use.
+    pub fn new(worker: &Rc<Worker>, addr: SocketAddr, backoff: Backoff) -> Self {
+        ReconnectingEndpoint {
+            worker: worker.clone(),
+            addr,
+            backoff,
+            current: RefCell::new(None),
+            reconnects: Cell::new(0),
+        }
+    }
+
+    /// The address connected to.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The policy of the reconnection attempts.
+    pub fn backoff(&self) -> &Backoff {
+        &self.backoff
+    }
+
+    /// The number of times the endpoint was recreated after the first connection.
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.get()
+    }
+
+    /// Returns true if there is an endpoint that is not closed.
+    pub fn is_connected(&self) -> bool {
+        self.current
+            .borrow()
+            .as_ref()
+            .is_some_and(|ep| !*ep.closed.borrow())
+    }
+
+    /// Returns the current endpoint, connecting first if there is none or it is closed.
+    pub fn endpoint(&self) -> Result<Rc<Endpoint>, Error> {
+        if let Some(ep) = self.current.borrow().as_ref() {
+            if !*ep.closed.borrow() {
+                return Ok(ep.clone());
+            }
+        }
+        self.reconnect()
+    }
+
+    /// Replaces the current endpoint with a new one.
+    pub fn reconnect(&self) -> Result<Rc<Endpoint>, Error> {
+        self.reconnect_with(None, |_| Ok(())).map(|(ep, ())| ep)
+    }
+
+    /// Replaces the current endpoint with a new one on which `setup`
+    /// succeeds, e.g. the handshake of a protocol, and returns both results.
+    ///
+    /// Failed attempts are retried as the backoff allows, failing with the
+    /// last error once it gives up or with [`Error::Timeout`] at `deadline`.
+    pub fn reconnect_with<T>(
+        &self,
+        deadline: Option<Instant>,
+        mut setup: impl FnMut(&Rc<Endpoint>) -> Result<T, Error>,
+    ) -> Result<(Rc<Endpoint>, T), Error> {
+        let reconnecting = self.current.borrow_mut().take().is_some();
+        let mut attempt = 0;
+        loop {
+            self.backoff.wait(&self.worker, attempt, deadline)?;
+            let result = unsafe { Endpoint::from_sockaddr(self.worker.clone(), self.addr) }
+                .map(Rc::new)
+                .and_then(|ep| Ok((ep.clone(), setup(&ep)?)));
+            match result {
+                Ok((ep, value)) => {
+                    if reconnecting {
+                        self.reconnects.set(self.reconnects.get() + 1);
+                        info!(addr = %self.addr, attempt, "reconnected");
+                    }
+                    *self.current.borrow_mut() = Some(ep.clone());
+                    return Ok((ep, value));
+                }
+                Err(e) => {
+                    debug!(addr = %self.addr, attempt, "failed to connect: {e}");
+                    attempt += 1;
+                    if !self.backoff.allows(attempt) {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,364 @@
+//! Client-side load balancing across the replicas of a service.
+
+use super::retry::is_connection_error;
+use super::*;
+use crate::codec::{Codec, DefaultCodec};
+use crate::reconnect::Backoff;
+use crate::ucp::endpoint::Endpoint;
+use crate::ucp::Worker;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use tracing::{debug, info, warn};
+
+/// The number of points each replica has on the hash ring.
+const RING_POINTS: u32 = 64;
+
+/// How a [`Balancer`] picks the replica of a call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Pick {
+    /// Each healthy replica in turn.
+    #[default]
+    RoundRobin,
+    /// The healthy replica with the fewest calls in progress.
+    LeastOutstanding,
+    /// The healthy replica owning the hash of a key on a ring, so calls with
+    /// the same key go to the same replica while it stays healthy.
+    ConsistentHash,
+}
+
+/// Options of a [`Balancer`].
+#[derive(Debug, Clone)]
+pub struct BalancerOptions {
+    /// How replicas are picked.
+    pub pick: Pick,
+    /// When a replica that failed is checked again.
+    pub recheck: Backoff,
+    /// How long connecting to a replica and checking it may take.
+    pub check_timeout: Duration,
+}
+
+impl Default for BalancerOptions {
+    fn default() -> Self {
+        BalancerOptions {
+            pick: Pick::default(),
+            recheck: Backoff {
+                initial: Duration::from_secs(1),
+                max: Duration::from_secs(30),
+                ..Backoff::default()
+            },
+            check_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+type Resolver = Box<dyn Fn() -> Vec<SocketAddr>>;
+type HealthCheck = Box<dyn Fn(&Client) -> Result<(), Error>>;
+
+/// Spreads calls over connections to the replicas of a service.
+///
+/// Replicas whose connection fails are taken out of rotation and connected
+/// to again once their recheck delay has passed; they are back in rotation
+/// once connected and, if one is set, the health check passes.
+pub struct Balancer {
+    worker: Rc<Worker>,
+    options: BalancerOptions,
+    resolver: Resolver,
+    health_check: RefCell<Option<HealthCheck>>,
+    replicas: RefCell<Vec<Rc<Replica>>>,
+    // Hashes of the ring points and the addresses owning them.
+    ring: RefCell<Vec<(u64, SocketAddr)>>,
+    next: Cell<usize>,
+}
+
+#[derive(Debug)]
+struct Replica {
+    addr: SocketAddr,
+    client: RefCell<Option<Rc<Client>>>,
+    outstanding: Cell<u32>,
+    failures: Cell<u32>,
+    next_check: Cell<Instant>,
+}
+
+/// A replica picked for calls, counted as outstanding until dropped.
+///
+/// Holding it while a stream opened on [`Picked::client`] is in use keeps
+/// [`Pick::LeastOutstanding`] aware of the stream.
+#[derive(Debug)]
+pub struct Picked {
+    replica: Rc<Replica>,
+    client: Rc<Client>,
+}
+
+impl Picked {
+    /// The address of the replica.
+    pub fn addr(&self) -> SocketAddr {
+        self.replica.addr
+    }
+
+    /// The connection to the replica.
+    pub fn client(&self) -> &Rc<Client> {
+        &self.client
+    }
+}
+
+impl Drop for Picked {
+    fn drop(&mut self) {
+        let outstanding = &self.replica.outstanding;
+        outstanding.set(outstanding.get().saturating_sub(1));
+    }
+}
+
+impl std::fmt::Debug for Balancer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Balancer")
+            .field("options", &self.options)
+            .field("replicas", &self.replicas)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Balancer {
+    /// Balances calls over the replicas at `addrs`.
+    pub fn new(worker: &Rc<Worker>, addrs: Vec<SocketAddr>, options: BalancerOptions) -> Self {
+        Self::with_resolver(worker, move || addrs.clone(), options)
+    }
+
+    /// Balances calls over the replicas `resolver` returns, which is called
+    /// again by [`Balancer::refresh`].
+    pub fn with_resolver(
+        worker: &Rc<Worker>,
+        resolver: impl Fn() -> Vec<SocketAddr> + 'static,
+        options: BalancerOptions,
+    ) -> Self {
+        let balancer = Balancer {
+            worker: worker.clone(),
+            options,
+            resolver: Box::new(resolver),
+            health_check: RefCell::new(None),
+            replicas: RefCell::new(Vec::new()),
+            ring: RefCell::new(Vec::new()),
+            next: Cell::new(0),
+        };
+        balancer.refresh();
+        balancer
+    }
+
+    /// Sets a check a replica must pass, after connecting to it, to be put
+    /// in rotation, e.g. calling a health-check method.
+    pub fn set_health_check(&self, check: impl Fn(&Client) -> Result<(), Error> + 'static) {
+        *self.health_check.borrow_mut() = Some(Box::new(check));
+    }
+
+    /// Calls the resolver, adding the replicas it returns that are new and
+    /// dropping those it no longer returns.
+    ///
+    /// New replicas are connected to when next picked.
+    pub fn refresh(&self) {
+        let addrs = (self.resolver)();
+        let now = Instant::now();
+        let mut replicas = self.replicas.borrow_mut();
+        replicas.retain(|replica| {
+            let keep = addrs.contains(&replica.addr);
+            if !keep {
+                info!(addr = %replica.addr, "replica removed");
+            }
+            keep
+        });
+        for addr in addrs {
+            if replicas.iter().all(|replica| replica.addr != addr) {
+                info!(%addr, "replica added");
+                replicas.push(Rc::new(Replica {
+                    addr,
+                    client: RefCell::new(None),
+                    outstanding: Cell::new(0),
+                    failures: Cell::new(0),
+                    next_check: Cell::new(now),
+                }));
+            }
+        }
+        let mut ring: Vec<_> = replicas
+            .iter()
+            .flat_map(|replica| {
+                let addr = replica.addr;
+                (0..RING_POINTS)
+                    .map(move |point| (hash(format!("{addr}#{point}").as_bytes()), addr))
+            })
+            .collect();
+        ring.sort_unstable();
+        *self.ring.borrow_mut() = ring;
+    }
+
+    /// The replicas and whether each is in rotation.
+    pub fn replicas(&self) -> Vec<(SocketAddr, bool)> {
+        self.replicas
+            .borrow()
+            .iter()
+            .map(|replica| (replica.addr, replica.is_healthy()))
+            .collect()
+    }
+
+    /// Picks a healthy replica with the pick policy, using `key` for
+    /// [`Pick::ConsistentHash`].
+    ///
+    /// Fails with [`Error::Unreachable`] if no replica is healthy.
+    pub fn pick(&self, key: &[u8]) -> Result<Picked, Error> {
+        self.check_replicas();
+        // The replicas that have a client, which makes them healthy.
+        let healthy: Vec<(Rc<Replica>, Rc<Client>)> = self
+            .replicas
+            .borrow()
+            .iter()
+            .filter_map(|replica| Some((replica.clone(), replica.client.borrow().clone()?)))
+            .collect();
+        if healthy.is_empty() {
+            return Err(Error::Unreachable);
+        }
+        let (replica, client) = match self.options.pick {
+            Pick::RoundRobin => {
+                let next = self.next.get();
+                self.next.set(next.wrapping_add(1));
+                healthy[next % healthy.len()].clone()
+            }
+            Pick::LeastOutstanding => {
+                // Ties go round-robin, so idle replicas share the calls.
+                let next = self.next.get();
+                self.next.set(next.wrapping_add(1));
+                let start = next % healthy.len();
+                healthy
+                    .iter()
+                    .cycle()
+                    .skip(start)
+                    .take(healthy.len())
+                    .min_by_key(|(replica, _)| replica.outstanding.get())
+                    .unwrap()
+                    .clone()
+            }
+            Pick::ConsistentHash => {
+                let ring = self.ring.borrow();
+                let key = hash(key);
+                let point = ring.partition_point(|&(point, _)| point < key);
+                ring[point..]
+                    .iter()
+                    .chain(&ring[..point])
+                    .find_map(|&(_, addr)| healthy.iter().find(|(r, _)| r.addr == addr))
+                    .unwrap()
+                    .clone()
+            }
+        };
+        replica.outstanding.set(replica.outstanding.get() + 1);
+        Ok(Picked { replica, client })
+    }
+
+    /// Calls `method_id` with `request` on a replica and waits for the response.
+    pub fn call<Req, Resp>(&self, method_id: u32, request: &Req) -> Result<Resp, Error>
+    where
+        Req: Serialize + ?Sized,
+        Resp: DeserializeOwned,
+    {
+        self.call_with(method_id, request, &CallOptions::default())
+    }
+
+    /// Like [`Balancer::call`] with explicit options.
+    pub fn call_with<Req, Resp>(
+        &self,
+        method_id: u32,
+        request: &Req,
+        options: &CallOptions,
+    ) -> Result<Resp, Error>
+    where
+        Req: Serialize + ?Sized,
+        Resp: DeserializeOwned,
+    {
+        let payload = DefaultCodec::encode(request)?;
+        DefaultCodec::decode(&self.call_raw_with(method_id, &payload, options)?)
+    }
+
+    /// Calls `method_id` with an already encoded request on a replica.
+    ///
+    /// With [`Pick::ConsistentHash`], the encoded request is the key.
+    pub fn call_raw_with(
+        &self,
+        method_id: u32,
+        payload: &[u8],
+        options: &CallOptions,
+    ) -> Result<Vec<u8>, Error> {
+        let picked = self.pick(payload)?;
+        let result = picked.client.call_raw_with(method_id, payload, options);
+        if let Err(e) = &result {
+            if is_connection_error(e) {
+                self.mark_down(&picked.replica, e);
+            }
+        }
+        result
+    }
+
+    // Takes replicas whose endpoint closed out of rotation and connects to
+    // those due for a check.
+    fn check_replicas(&self) {
+        let now = Instant::now();
+        let replicas = self.replicas.borrow().clone();
+        for replica in replicas {
+            let closed = replica
+                .client
+                .borrow()
+                .as_ref()
+                .is_some_and(|client| *client.endpoint().closed.borrow());
+            if closed {
+                self.mark_down(&replica, &Error::ConnectionReset);
+            }
+            if replica.client.borrow().is_none() && replica.next_check.get() <= now {
+                self.connect(&replica);
+            }
+        }
+    }
+
+    fn connect(&self, replica: &Replica) {
+        let deadline = Some(Instant::now() + self.options.check_timeout);
+        let result = unsafe { Endpoint::from_sockaddr(self.worker.clone(), replica.addr) }
+            .and_then(|ep| Client::connect_until(Rc::new(ep), deadline))
+            .and_then(|client| {
+                client.set_timeout(Some(self.options.check_timeout));
+                if let Some(check) = self.health_check.borrow().as_ref() {
+                    check(&client)?;
+                }
+                client.set_timeout(None);
+                Ok(client)
+            });
+        match result {
+            Ok(client) => {
+                info!(addr = %replica.addr, "replica healthy");
+                replica.failures.set(0);
+                *replica.client.borrow_mut() = Some(Rc::new(client));
+            }
+            Err(e) => self.mark_down(replica, &e),
+        }
+    }
+
+    fn mark_down(&self, replica: &Replica, error: &Error) {
+        if replica.client.borrow_mut().take().is_some() {
+            warn!(addr = %replica.addr, "replica out of rotation: {error}");
+        } else {
+            debug!(addr = %replica.addr, "replica check failed: {error}");
+        }
+        let failures = replica.failures.get() + 1;
+        replica.failures.set(failures);
+        let delay = self.options.recheck.delay(failures);
+        replica.next_check.set(Instant::now() + delay);
+    }
+}
+
+impl Replica {
+    fn is_healthy(&self) -> bool {
+        self.client.borrow().is_some()
+    }
+}
+
+// FNV-1a, which spreads the points of the ring well enough.
+fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
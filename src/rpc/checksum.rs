@@ -0,0 +1,122 @@
+//! Integrity checksums of RPC frames.
+//!
+//! Meant for tracking down data corruption, e.g. with experimental
+//! transports: a sender with a [`Checksum`] appends one to every frame it
+//! sends, covering the header, the metadata and the payload as sent, and the
+//! receiver checks it, failing with
+//! [`Error::ChecksumMismatch`](crate::Error::ChecksumMismatch) if it does
+//! not match.
+//!
+//! A server with a checksum requires it of the clients that complete the
+//! [handshake](super::handshake): both sides then append it to every frame
+//! they send and reject the frames that arrive without it. On connections
+//! without a handshake, each side decides whether the frames it sends carry
+//! one, and checks those of the frames it receives that do.
+//!
+//! Each algorithm is behind a cargo feature, `crc32c` and `xxhash`; both are
+//! cheap enough to leave on in staging.
+
+/// A checksum algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    /// [CRC32C](https://docs.rs/crc32c), hardware-accelerated on most CPUs.
+    #[cfg(feature = "crc32c")]
+    Crc32c,
+    /// The 64-bit [XXH3](https://docs.rs/xxhash-rust) hash.
+    #[cfg(feature = "xxhash")]
+    Xxh3,
+}
+
+/// The checksum algorithms of this build.
+pub const SUPPORTED: &[Checksum] = &[
+    #[cfg(feature = "crc32c")]
+    Checksum::Crc32c,
+    #[cfg(feature = "xxhash")]
+    Checksum::Xxh3,
+];
+
+impl Checksum {
+    /// The name of the algorithm in the handshake.
+    pub fn name(self) -> &'static str {
+        match self {
+            #[cfg(feature = "crc32c")]
+            Self::Crc32c => "crc32c",
+            #[cfg(feature = "xxhash")]
+            Self::Xxh3 => "xxh3",
+        }
+    }
+
+    /// The supported algorithm called `name`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        SUPPORTED
+            .iter()
+            .copied()
+            .find(|checksum| checksum.name() == name)
+    }
+
+    // The id of the algorithm in the frame header; 0 means no checksum.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            #[cfg(feature = "crc32c")]
+            Self::Crc32c => 1,
+            #[cfg(feature = "xxhash")]
+            Self::Xxh3 => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        SUPPORTED
+            .iter()
+            .copied()
+            .find(|checksum| checksum.id() == id)
+    }
+
+    // The length of the checksum in bytes.
+    pub(crate) fn len(self) -> usize {
+        match self {
+            #[cfg(feature = "crc32c")]
+            Self::Crc32c => 4,
+            #[cfg(feature = "xxhash")]
+            Self::Xxh3 => 8,
+        }
+    }
+
+    /// Appends the checksum of `bytes` to them.
+    #[cfg_attr(
+        not(any(feature = "crc32c", feature = "xxhash")),
+        allow(unused_variables, clippy::ptr_arg)
+    )]
+    pub(crate) fn append(self, bytes: &mut Vec<u8>) {
+        match self {
+            #[cfg(feature = "crc32c")]
+            Self::Crc32c => {
+                let checksum = crc32c::crc32c(bytes);
+                bytes.extend_from_slice(&checksum.to_le_bytes());
+            }
+            #[cfg(feature = "xxhash")]
+            Self::Xxh3 => {
+                let checksum = xxhash_rust::xxh3::xxh3_64(bytes);
+                bytes.extend_from_slice(&checksum.to_le_bytes());
+            }
+        }
+    }
+
+    // Returns true if the last `len` bytes of `bytes` are the checksum of
+    // the others.
+    #[cfg_attr(
+        not(any(feature = "crc32c", feature = "xxhash")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn verify(self, bytes: &[u8]) -> bool {
+        let Some((data, checksum)) = bytes.split_at_checked(bytes.len().wrapping_sub(self.len()))
+        else {
+            return false;
+        };
+        match self {
+            #[cfg(feature = "crc32c")]
+            Self::Crc32c => checksum == crc32c::crc32c(data).to_le_bytes(),
+            #[cfg(feature = "xxhash")]
+            Self::Xxh3 => checksum == xxhash_rust::xxh3::xxh3_64(data).to_le_bytes(),
+        }
+    }
+}
@@ -0,0 +1,690 @@
+//! Client side of an RPC connection.
+
+use super::checksum::Checksum;
+use super::compression::{self, Compression, Policy};
+use super::context::TIMEOUT_HEADER;
+use super::frame::{Accepted, Encoding, Frame, FrameKind, FLAG_STREAMING};
+use super::handshake::{self, Grant, Hello, Negotiated, CONN_KEY_HEADER};
+use super::retry::{self, RetryPolicy};
+use super::stream::{Channel, DEFAULT_WINDOW};
+use super::trace::{self, TraceContext};
+use super::*;
+use crate::codec::{Codec, DefaultCodec};
+use crate::keepalive::{self, Keepalive, KeepaliveOptions};
+use crate::metrics;
+use crate::reconnect::{Backoff, ReconnectingEndpoint};
+use crate::ucp::endpoint::Endpoint;
+use crate::ucp::Worker;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+// Call ids only need to be unique per worker, but a process-wide counter is
+// simpler and keeps them unique across clients sharing a worker.
+static NEXT_CALL_ID: AtomicU32 = AtomicU32::new(1);
+
+// The most timed-out calls whose late responses a client waits for, and for
+// how long; a response still missing by then is not coming, or is left on the
+// unexpected queue of the worker.
+const MAX_ABANDONED: usize = 1024;
+const ABANDONED_TTL: Duration = Duration::from_secs(60);
+
+/// A response together with the trailers the handler set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply<T> {
+    /// The response.
+    pub value: T,
+    /// The trailers set through the [`CallContext`] of the handler.
+    pub trailers: Metadata,
+}
+
+/// A connection to an RPC [`Server`].
+#[derive(Debug)]
+pub struct Client {
+    ep: RefCell<Rc<Endpoint>>,
+    grant: Cell<Grant>,
+    // Set for clients that reconnect, see `Client::connect_to`.
+    target: Option<ReconnectingEndpoint>,
+    // Set when a call fails because the connection did, which UCX may not
+    // have noticed yet.
+    failed: Cell<bool>,
+    retry: RefCell<RetryPolicy>,
+    // Sent again after each reconnection.
+    hello: Option<Hello>,
+    negotiated: RefCell<Option<Negotiated>>,
+    compression_threshold: Cell<usize>,
+    checksum: Cell<Option<Checksum>>,
+    stream_window: Cell<u32>,
+    timeout: Cell<Option<Duration>>,
+    headers: RefCell<Metadata>,
+    // Response tags of timed-out calls whose late responses are discarded,
+    // oldest first, with when they were abandoned.
+    abandoned: RefCell<VecDeque<(u64, Instant)>>,
+    // Started again after each reconnection.
+    keepalive_options: Cell<Option<KeepaliveOptions>>,
+    keepalive: RefCell<Option<Rc<Keepalive>>>,
+}
+
+impl Client {
+    /// Waits for the connection id the server sends after accepting `ep`.
+    pub fn connect(ep: Rc<Endpoint>) -> Result<Self, Error> {
+        Self::connect_until(ep, None)
+    }
+
+    /// Like [`Client::connect`], failing with [`Error::Timeout`] if the
+    /// connection id has not arrived by `deadline`.
+    pub fn connect_until(ep: Rc<Endpoint>, deadline: Option<Instant>) -> Result<Self, Error> {
+        let (grant, _) = Self::handshake(&ep, None, deadline)?;
+        Ok(Self::new(ep, grant, None, None))
+    }
+
+    /// Like [`Client::connect_until`], then sends `hello` and waits for the
+    /// server to accept it, see [`handshake`](super::handshake).
+    ///
+    /// Fails with [`Error::Handshake`] if the server rejects the client or
+    /// picks something the client does not support.
+    pub fn connect_with(
+        ep: Rc<Endpoint>,
+        hello: Hello,
+        deadline: Option<Instant>,
+    ) -> Result<Self, Error> {
+        let (grant, negotiated) = Self::handshake(&ep, Some(&hello), deadline)?;
+        let client = Self::new(ep, grant, None, Some(hello));
+        *client.negotiated.borrow_mut() = negotiated;
+        Ok(client)
+    }
+
+    /// Connects to the server listening on `addr`, and connects again when
+    /// the connection fails, waiting between attempts as `backoff` says.
+    ///
+    /// Calls made while the connection is down wait for the reconnection.
+    /// Calls that fail because the connection did are replayed as the
+    /// [`RetryPolicy`] set with [`Client::set_retry_policy`] says.
+    pub fn connect_to(
+        worker: &Rc<Worker>,
+        addr: SocketAddr,
+        backoff: Backoff,
+    ) -> Result<Self, Error> {
+        Self::reconnecting(worker, addr, backoff, None)
+    }
+
+    /// Like [`Client::connect_to`], sending `hello` on every connection as
+    /// [`Client::connect_with`] does.
+    pub fn connect_to_with(
+        worker: &Rc<Worker>,
+        addr: SocketAddr,
+        backoff: Backoff,
+        hello: Hello,
+    ) -> Result<Self, Error> {
+        Self::reconnecting(worker, addr, backoff, Some(hello))
+    }
+
+    fn reconnecting(
+        worker: &Rc<Worker>,
+        addr: SocketAddr,
+        backoff: Backoff,
+        hello: Option<Hello>,
+    ) -> Result<Self, Error> {
+        let target = ReconnectingEndpoint::new(worker, addr, backoff);
+        let (ep, (grant, negotiated)) =
+            target.reconnect_with(None, |ep| Self::handshake(ep, hello.as_ref(), None))?;
+        let client = Self::new(ep, grant, Some(target), hello);
+        *client.negotiated.borrow_mut() = negotiated;
+        Ok(client)
+    }
+
+    fn new(
+        ep: Rc<Endpoint>,
+        grant: Grant,
+        target: Option<ReconnectingEndpoint>,
+        hello: Option<Hello>,
+    ) -> Self {
+        Client {
+            ep: RefCell::new(ep),
+            grant: Cell::new(grant),
+            target,
+            failed: Cell::new(false),
+            retry: RefCell::new(RetryPolicy::default()),
+            hello,
+            negotiated: RefCell::new(None),
+            compression_threshold: Cell::new(compression::DEFAULT_THRESHOLD),
+            checksum: Cell::new(None),
+            stream_window: Cell::new(DEFAULT_WINDOW),
+            timeout: Cell::new(None),
+            headers: RefCell::new(Metadata::new()),
+            abandoned: RefCell::new(VecDeque::new()),
+            keepalive_options: Cell::new(None),
+            keepalive: RefCell::new(None),
+        }
+    }
+
+    // Waits for the connection id and key and, with a hello, negotiates
+    // with the server.
+    fn handshake(
+        ep: &Endpoint,
+        hello: Option<&Hello>,
+        deadline: Option<Instant>,
+    ) -> Result<(Grant, Option<Negotiated>), Error> {
+        let grant = Grant::recv(ep, deadline)?;
+        debug!(conn_id = grant.conn_id, "rpc client connected");
+        let negotiated = match hello {
+            Some(hello) => Some(handshake::exchange(ep, grant, hello, deadline)?),
+            None => None,
+        };
+        Ok((grant, negotiated))
+    }
+
+    /// What was negotiated with the server, for clients that sent a hello.
+    pub fn negotiated(&self) -> Option<Negotiated> {
+        self.negotiated.borrow().clone()
+    }
+
+    /// The endpoint the calls are sent on.
+    ///
+    /// Reconnecting clients replace it when the connection fails.
+    pub fn endpoint(&self) -> Rc<Endpoint> {
+        self.ep.borrow().clone()
+    }
+
+    /// Sets which calls are replayed after the connection fails.
+    ///
+    /// Only clients created with [`Client::connect_to`] reconnect; the calls
+    /// of the others fail with the error.
+    pub fn set_retry_policy(&self, policy: RetryPolicy) {
+        *self.retry.borrow_mut() = policy;
+    }
+
+    /// Replaces the connection of a client created with
+    /// [`Client::connect_to`] with a new one.
+    ///
+    /// Fails with [`Error::Unsupported`] for other clients.
+    pub fn reconnect(&self) -> Result<(), Error> {
+        self.reconnect_until(None)
+    }
+
+    fn reconnect_until(&self, deadline: Option<Instant>) -> Result<(), Error> {
+        let target = self.target.as_ref().ok_or(Error::Unsupported)?;
+        let (ep, (grant, negotiated)) = target.reconnect_with(deadline, |ep| {
+            Self::handshake(ep, self.hello.as_ref(), deadline)
+        })?;
+        *self.ep.borrow_mut() = ep;
+        self.failed.set(false);
+        self.grant.set(grant);
+        *self.negotiated.borrow_mut() = negotiated;
+        // Responses to calls on the old connection will never arrive.
+        self.abandoned.borrow_mut().clear();
+        if let Err(e) = self.start_keepalive() {
+            debug!("no keepalive on the new rpc connection: {e}");
+        }
+        Ok(())
+    }
+
+    /// Pings the server as `options` say, so that calls fail with
+    /// [`Error::EndpointTimeout`] once it stops answering, and clients
+    /// created with [`Client::connect_to`] reconnect. `None` stops pinging.
+    ///
+    /// The keepalive is polled while the client waits for responses and
+    /// stream frames. The server closes the connection once the client stops
+    /// pinging, so an idle client has to poll [`Client::keepalive`] itself.
+    ///
+    /// Fails with [`Error::Unsupported`] if the server runs no keepalive on
+    /// the connection, see [`Server::set_keepalive`].
+    pub fn set_keepalive(&self, options: Option<KeepaliveOptions>) -> Result<(), Error> {
+        self.keepalive_options.set(options);
+        self.start_keepalive()
+    }
+
+    /// The keepalive of the current connection, if any.
+    pub fn keepalive(&self) -> Option<Rc<Keepalive>> {
+        self.keepalive.borrow().clone()
+    }
+
+    fn start_keepalive(&self) -> Result<(), Error> {
+        *self.keepalive.borrow_mut() = None;
+        let Some(options) = self.keepalive_options.get() else {
+            return Ok(());
+        };
+        let peer_inbox = match self.grant.get().keepalive {
+            0 => return Err(Error::Unsupported),
+            peer_inbox => peer_inbox,
+        };
+        let ep = self.ep.borrow().clone();
+        let keepalive = Keepalive::new(ep, options, keepalive::next_inbox(), Some(peer_inbox));
+        *self.keepalive.borrow_mut() = Some(Rc::new(keepalive));
+        Ok(())
+    }
+
+    // The endpoint and connection to call on, reconnecting first if the
+    // connection is down.
+    fn connection(&self, deadline: Option<Instant>) -> Result<(Rc<Endpoint>, Grant), Error> {
+        if let Some(keepalive) = self.keepalive() {
+            if let Err(e) = keepalive.poll() {
+                debug!("rpc connection failed: {e}");
+                self.failed.set(true);
+            }
+        }
+        let down = self.failed.get() || *self.ep.borrow().closed.borrow();
+        if down && self.target.is_some() {
+            self.reconnect_until(deadline)?;
+        }
+        Ok((self.ep.borrow().clone(), self.grant.get()))
+    }
+
+    // Notes that the connection is down if `result` failed because it did.
+    fn check_connection<T>(&self, result: Result<T, Error>) -> Result<T, Error> {
+        if let Err(e) = &result {
+            if retry::is_connection_error(e) {
+                self.failed.set(true);
+            }
+        }
+        result
+    }
+
+    /// Sets the size from which the payloads the client sends are compressed,
+    /// if the handshake negotiated a compression algorithm.
+    pub fn set_compression_threshold(&self, threshold: usize) {
+        self.compression_threshold.set(threshold);
+    }
+
+    /// Sets the checksum appended to the frames the client sends, see
+    /// [`checksum`](super::checksum), unless the handshake negotiated one.
+    /// `None` sends no checksum.
+    pub fn set_checksum(&self, checksum: Option<Checksum>) {
+        self.checksum.set(checksum);
+    }
+
+    fn encoding(&self) -> Encoding {
+        let negotiated = self.negotiated.borrow();
+        let name = negotiated
+            .as_ref()
+            .and_then(|negotiated| negotiated.compression.as_deref());
+        Encoding {
+            compression: Policy::negotiated(name, self.compression_threshold.get()),
+            checksum: self.required_checksum().or(self.checksum.get()),
+        }
+    }
+
+    // The checksum the frames of the server must carry.
+    fn required_checksum(&self) -> Option<Checksum> {
+        self.negotiated
+            .borrow()
+            .as_ref()
+            .and_then(Negotiated::checksum_algorithm)
+    }
+
+    // What the frames of the server must be encoded with.
+    fn accepted(&self) -> Accepted {
+        let negotiated = self.negotiated.borrow();
+        Accepted {
+            checksum: self.required_checksum(),
+            compression: negotiated
+                .as_ref()
+                .and_then(|negotiated| Compression::from_name(negotiated.compression.as_deref()?)),
+        }
+    }
+
+    /// Sets the number of stream items that may be in flight in each
+    /// direction of the streaming calls opened afterwards.
+    pub fn set_stream_window(&self, window: u32) {
+        self.stream_window.set(window.max(1));
+    }
+
+    /// Sets the timeout of calls made without a deadline, including the
+    /// calls of generated client stubs. `None` waits forever.
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        self.timeout.set(timeout);
+    }
+
+    /// Sets headers sent with every call, including the calls of generated
+    /// client stubs. The headers of [`CallOptions`] take precedence.
+    pub fn set_headers(&self, headers: Metadata) {
+        *self.headers.borrow_mut() = headers;
+    }
+
+    /// Calls `method_id` with `request` and waits for the response.
+    pub fn call<Req, Resp>(&self, method_id: u32, request: &Req) -> Result<Resp, Error>
+    where
+        Req: Serialize + ?Sized,
+        Resp: DeserializeOwned,
+    {
+        self.call_with(method_id, request, &CallOptions::default())
+    }
+
+    /// Like [`Client::call`] with explicit options.
+    pub fn call_with<Req, Resp>(
+        &self,
+        method_id: u32,
+        request: &Req,
+        options: &CallOptions,
+    ) -> Result<Resp, Error>
+    where
+        Req: Serialize + ?Sized,
+        Resp: DeserializeOwned,
+    {
+        self.invoke(method_id, request, options)
+            .map(|reply| reply.value)
+    }
+
+    /// Like [`Client::call_with`], also returning the response trailers.
+    pub fn invoke<Req, Resp>(
+        &self,
+        method_id: u32,
+        request: &Req,
+        options: &CallOptions,
+    ) -> Result<Reply<Resp>, Error>
+    where
+        Req: Serialize + ?Sized,
+        Resp: DeserializeOwned,
+    {
+        let payload = DefaultCodec::encode(request)?;
+        let reply = self.invoke_raw(method_id, &payload, options)?;
+        Ok(Reply {
+            value: DefaultCodec::decode(&reply.value)?,
+            trailers: reply.trailers,
+        })
+    }
+
+    /// Calls `method_id` with an already encoded request and returns the encoded response.
+    pub fn call_raw(&self, method_id: u32, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        self.call_raw_with(method_id, payload, &CallOptions::default())
+    }
+
+    /// Like [`Client::call_raw`] with explicit options.
+    pub fn call_raw_with(
+        &self,
+        method_id: u32,
+        payload: &[u8],
+        options: &CallOptions,
+    ) -> Result<Vec<u8>, Error> {
+        self.invoke_raw(method_id, payload, options)
+            .map(|reply| reply.value)
+    }
+
+    /// Calls `method_id` with an already encoded request and returns the
+    /// encoded response with its trailers.
+    ///
+    /// When the deadline passes, the call fails with [`Error::Timeout`] and
+    /// the server is told to abandon it.
+    pub fn invoke_raw(
+        &self,
+        method_id: u32,
+        payload: &[u8],
+        options: &CallOptions,
+    ) -> Result<Reply<Vec<u8>>, Error> {
+        let parent = TraceContext::current();
+        let trace = parent.map_or_else(TraceContext::new_root, |parent| parent.child());
+        let span = trace::client_span(method_id, &trace, parent, payload.len());
+        let _span = span.enter();
+        let started = Instant::now();
+        let result = self.call_with_retry(method_id, payload, options, &trace);
+        metrics::rpc_completed(
+            "client",
+            options.method.unwrap_or(metrics::UNKNOWN_METHOD),
+            started.elapsed(),
+            result.is_ok(),
+        );
+        trace::record_outcome(&span, result.as_ref().map(|reply| reply.value.len()));
+        result
+    }
+
+    // Replays calls whose connection failed as the retry policy allows.
+    fn call_with_retry(
+        &self,
+        method_id: u32,
+        payload: &[u8],
+        options: &CallOptions,
+        trace: &TraceContext,
+    ) -> Result<Reply<Vec<u8>>, Error> {
+        let deadline = self.deadline(options);
+        let mut attempt = 0;
+        loop {
+            match self
+                .check_connection(self.send_call(method_id, payload, options, deadline, trace))
+            {
+                Err(e)
+                    if self.target.is_some()
+                        && self.retry.borrow().should_retry(method_id, &e, attempt) =>
+                {
+                    debug!(method_id, attempt, "replaying rpc call: {e}");
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn send_call(
+        &self,
+        method_id: u32,
+        payload: &[u8],
+        options: &CallOptions,
+        deadline: Option<Instant>,
+        trace: &TraceContext,
+    ) -> Result<Reply<Vec<u8>>, Error> {
+        self.discard_abandoned();
+        let (ep, grant) = self.connection(deadline)?;
+        let call_id = NEXT_CALL_ID.fetch_add(1, Ordering::Relaxed);
+        let request_tag = REQUEST_CHANNEL.tag(grant.conn_id, call_id);
+        let response_tag = RESPONSE_CHANNEL.tag(grant.conn_id, call_id);
+        let request = Frame::new(FrameKind::Request, method_id, payload)
+            .with_metadata(self.request_metadata(grant, options, deadline, trace))
+            .with_encoding(self.encoding())
+            .encode()?;
+
+        ep.send_bytes_until(request_tag, &request, deadline)?;
+        let keepalive = self.keepalive();
+        let received = keepalive::probe_until(keepalive.as_deref(), &ep, response_tag, deadline)
+            .and_then(|message| ep.worker.recv_probed_until(message, deadline));
+        let bytes = match received {
+            Ok(bytes) => {
+                ep.metrics().bytes_received.add(bytes.len() as u64);
+                bytes
+            }
+            Err(Error::Timeout) => {
+                debug!(method_id, call_id, "rpc call timed out");
+                let cancel = Frame::new(FrameKind::Cancel, method_id, &[])
+                    .with_metadata(Metadata::new().with(CONN_KEY_HEADER, grant.key.to_le_bytes()))
+                    .with_encoding(self.encoding())
+                    .encode()?;
+                if let Err(e) = ep.send_bytes(request_tag, &cancel) {
+                    debug!("failed to send cancel notice: {e}");
+                }
+                self.abandon(response_tag);
+                return Err(Error::Timeout);
+            }
+            Err(e) => return Err(e),
+        };
+        let frame = Frame::decode(&bytes, response_tag, &ep, self.accepted())?;
+        match frame.kind {
+            FrameKind::Response => Ok(Reply {
+                value: frame.payload.to_vec(),
+                trailers: frame.metadata,
+            }),
+            FrameKind::Error => Err(Error::Remote(
+                String::from_utf8_lossy(&frame.payload).into_owned(),
+            )),
+            kind => Err(Error::Codec(format!("unexpected {kind:?} frame"))),
+        }
+    }
+
+    /// Opens a server-streaming call of `method_id` with `request`.
+    pub fn server_streaming<Req, Resp, E>(
+        &self,
+        method_id: u32,
+        request: &Req,
+    ) -> Result<ResponseStream<Resp, E>, Error>
+    where
+        Req: Serialize + ?Sized,
+    {
+        self.server_streaming_with(method_id, request, &CallOptions::default())
+    }
+
+    /// Like [`Client::server_streaming`] with explicit options.
+    pub fn server_streaming_with<Req, Resp, E>(
+        &self,
+        method_id: u32,
+        request: &Req,
+        options: &CallOptions,
+    ) -> Result<ResponseStream<Resp, E>, Error>
+    where
+        Req: Serialize + ?Sized,
+    {
+        let channel = self.open_stream(method_id, &DefaultCodec::encode(request)?, options)?;
+        Ok(ResponseStream::new(channel))
+    }
+
+    /// Opens a client-streaming call of `method_id`.
+    pub fn client_streaming<Req, Resp, E>(
+        &self,
+        method_id: u32,
+    ) -> Result<ClientStreaming<Req, Resp, E>, Error>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+        E: From<Error> + DeserializeOwned,
+    {
+        self.client_streaming_with(method_id, &CallOptions::default())
+    }
+
+    /// Like [`Client::client_streaming`] with explicit options.
+    pub fn client_streaming_with<Req, Resp, E>(
+        &self,
+        method_id: u32,
+        options: &CallOptions,
+    ) -> Result<ClientStreaming<Req, Resp, E>, Error>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+        E: From<Error> + DeserializeOwned,
+    {
+        Ok(ClientStreaming::new(self.open_stream(
+            method_id,
+            &[],
+            options,
+        )?))
+    }
+
+    /// Opens a bidirectional streaming call of `method_id`.
+    pub fn bidi_streaming<Req, Resp, E>(
+        &self,
+        method_id: u32,
+    ) -> Result<BidiStreaming<Req, Resp, E>, Error>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+        E: From<Error> + DeserializeOwned,
+    {
+        self.bidi_streaming_with(method_id, &CallOptions::default())
+    }
+
+    /// Like [`Client::bidi_streaming`] with explicit options.
+    pub fn bidi_streaming_with<Req, Resp, E>(
+        &self,
+        method_id: u32,
+        options: &CallOptions,
+    ) -> Result<BidiStreaming<Req, Resp, E>, Error>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+        E: From<Error> + DeserializeOwned,
+    {
+        Ok(BidiStreaming::new(self.open_stream(
+            method_id,
+            &[],
+            options,
+        )?))
+    }
+
+    fn open_stream(
+        &self,
+        method_id: u32,
+        initial: &[u8],
+        options: &CallOptions,
+    ) -> Result<Rc<Channel>, Error> {
+        let parent = TraceContext::current();
+        let trace = parent.map_or_else(TraceContext::new_root, |parent| parent.child());
+        let span = trace::client_span(method_id, &trace, parent, initial.len());
+        let _span = span.enter();
+        let deadline = self.deadline(options);
+        let (ep, grant) = self.connection(deadline)?;
+        let call_id = NEXT_CALL_ID.fetch_add(1, Ordering::Relaxed);
+        let window = self.stream_window.get();
+        let mut payload = window.to_le_bytes().to_vec();
+        payload.extend_from_slice(initial);
+        let request = Frame::new(FrameKind::Request, method_id, &payload)
+            .with_flags(FLAG_STREAMING)
+            .with_metadata(self.request_metadata(grant, options, deadline, &trace))
+            .with_encoding(self.encoding())
+            .encode()?;
+        let request_tag = REQUEST_CHANNEL.tag(grant.conn_id, call_id);
+        self.check_connection(ep.send_bytes_until(request_tag, &request, deadline))?;
+
+        let channel = Channel::new(
+            ep,
+            method_id,
+            request_tag,
+            RESPONSE_CHANNEL.tag(grant.conn_id, call_id),
+            window,
+            false,
+        );
+        channel.set_deadline(deadline);
+        channel.set_encoding(self.encoding());
+        channel.set_accepted(self.accepted());
+        channel.set_conn_key(grant.key);
+        channel.set_keepalive(self.keepalive());
+        Ok(channel)
+    }
+
+    fn deadline(&self, options: &CallOptions) -> Option<Instant> {
+        options
+            .deadline
+            .or_else(|| self.timeout.get().map(|timeout| Instant::now() + timeout))
+    }
+
+    fn request_metadata(
+        &self,
+        grant: Grant,
+        options: &CallOptions,
+        deadline: Option<Instant>,
+        trace: &TraceContext,
+    ) -> Metadata {
+        let mut metadata = self.headers.borrow().clone();
+        metadata.extend(options.metadata.clone());
+        trace.inject(&mut metadata);
+        metadata.insert(CONN_KEY_HEADER, grant.key.to_le_bytes());
+        if let Some(deadline) = deadline {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            metadata.insert(TIMEOUT_HEADER, timeout.as_micros().to_string());
+        }
+        metadata
+    }
+
+    // The server answers every call, so the responses of timed-out calls
+    // eventually arrive and have to be taken off the unexpected queue.
+    fn discard_abandoned(&self) {
+        let worker = self.ep.borrow().worker.clone();
+        self.abandoned.borrow_mut().retain(|&(tag, abandoned_at)| {
+            match worker.tag_probe(tag, u64::MAX) {
+                Some(message) => {
+                    let _ = worker.recv_probed(message);
+                    false
+                }
+                None => abandoned_at.elapsed() < ABANDONED_TTL,
+            }
+        });
+    }
+
+    fn abandon(&self, response_tag: u64) {
+        let mut abandoned = self.abandoned.borrow_mut();
+        if abandoned.len() == MAX_ABANDONED {
+            abandoned.pop_front();
+        }
+        abandoned.push_back((response_tag, Instant::now()));
+    }
+}
@@ -0,0 +1,243 @@
+//! Compression of RPC payloads.
+//!
+//! Each algorithm is behind a cargo feature of the same name, `lz4` and
+//! `zstd`. The algorithm of a connection is picked by the
+//! [`handshake`](super::handshake): the client offers those it was built
+//! with and the server picks the first one it supports, so connections
+//! without a handshake are never compressed. Payloads shorter than the
+//! threshold of the sender are sent as they are, as are those that do not
+//! shrink; the frame header says which algorithm, if any, a payload was
+//! compressed with.
+
+use crate::Error;
+
+/// The size from which payloads are compressed by default.
+pub const DEFAULT_THRESHOLD: usize = 1024;
+
+/// The largest payload [`Compression::decompress`] inflates a frame to, so
+/// that a small frame cannot make the receiver allocate without bound.
+pub const MAX_DECOMPRESSED_LEN: usize = 64 << 20;
+
+/// The algorithms this build supports, most preferred first.
+pub const SUPPORTED: &[Compression] = &[
+    #[cfg(feature = "lz4")]
+    Compression::Lz4,
+    #[cfg(feature = "zstd")]
+    Compression::Zstd,
+];
+
+/// A compression algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// [LZ4](https://docs.rs/lz4_flex), fast with a moderate ratio.
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// [Zstandard](https://docs.rs/zstd) at its default level, slower with a
+    /// better ratio.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    /// The name of the algorithm in the handshake.
+    pub fn name(self) -> &'static str {
+        match self {
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => "lz4",
+            #[cfg(feature = "zstd")]
+            Self::Zstd => "zstd",
+        }
+    }
+
+    /// The supported algorithm called `name`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        SUPPORTED
+            .iter()
+            .copied()
+            .find(|compression| compression.name() == name)
+    }
+
+    // The id of the algorithm in the frame header; 0 means uncompressed.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => 1,
+            #[cfg(feature = "zstd")]
+            Self::Zstd => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        SUPPORTED
+            .iter()
+            .copied()
+            .find(|compression| compression.id() == id)
+    }
+
+    /// Compresses `bytes`.
+    #[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    pub fn compress(self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => Ok(lz4_flex::compress_prepend_size(bytes)),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::bulk::compress(bytes, 0)
+                .map_err(|e| Error::Codec(format!("zstd compression failed: {e}"))),
+        }
+    }
+
+    /// Decompresses `bytes`, compressed with [`Compression::compress`].
+    ///
+    /// Fails if they decompress to more than [`MAX_DECOMPRESSED_LEN`] bytes.
+    #[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    pub fn decompress(self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => {
+                // The size is prepended by `compress_prepend_size`.
+                let len = bytes
+                    .first_chunk::<4>()
+                    .map(|len| u32::from_le_bytes(*len) as usize)
+                    .ok_or_else(|| Error::Codec("truncated lz4 payload".to_string()))?;
+                if len > MAX_DECOMPRESSED_LEN {
+                    return Err(too_large());
+                }
+                lz4_flex::decompress_size_prepended(bytes)
+                    .map_err(|e| Error::Codec(format!("lz4 decompression failed: {e}")))
+            }
+            #[cfg(feature = "zstd")]
+            Self::Zstd => {
+                use std::io::Read;
+                let failed =
+                    |e: std::io::Error| Error::Codec(format!("zstd decompression failed: {e}"));
+                let mut decompressed = Vec::new();
+                zstd::stream::read::Decoder::new(bytes)
+                    .map_err(failed)?
+                    .take(MAX_DECOMPRESSED_LEN as u64 + 1)
+                    .read_to_end(&mut decompressed)
+                    .map_err(failed)?;
+                if decompressed.len() > MAX_DECOMPRESSED_LEN {
+                    return Err(too_large());
+                }
+                Ok(decompressed)
+            }
+        }
+    }
+}
+
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+fn too_large() -> Error {
+    Error::Codec(format!(
+        "compressed payload inflates to more than {MAX_DECOMPRESSED_LEN} bytes"
+    ))
+}
+
+/// How the payloads sent on a connection are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Policy {
+    pub algorithm: Compression,
+    pub threshold: usize,
+}
+
+impl Policy {
+    // The policy of a connection that negotiated `name`, if this build
+    // supports it.
+    pub fn negotiated(name: Option<&str>, threshold: usize) -> Option<Self> {
+        let algorithm = Compression::from_name(name?)?;
+        Some(Policy {
+            algorithm,
+            threshold,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads_round_trip() {
+        let text = b"the quick brown fox jumps over the lazy dog ".repeat(64);
+        for &compression in SUPPORTED {
+            assert_eq!(
+                Compression::from_name(compression.name()),
+                Some(compression)
+            );
+            assert_eq!(Compression::from_id(compression.id()), Some(compression));
+            for payload in [&b""[..], b"x", &text] {
+                let compressed = compression.compress(payload).unwrap();
+                assert_eq!(compression.decompress(&compressed).unwrap(), payload);
+            }
+            assert!(compression.compress(&text).unwrap().len() < text.len());
+        }
+        assert_eq!(Compression::from_name("gzip"), None);
+        assert_eq!(Compression::from_id(0), None);
+        assert_eq!(Policy::negotiated(None, DEFAULT_THRESHOLD), None);
+        assert_eq!(Policy::negotiated(Some("gzip"), DEFAULT_THRESHOLD), None);
+    }
+
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    #[test]
+    fn payloads_shorter_than_the_threshold_are_sent_as_they_are() {
+        use super::super::frame::{Encoding, Frame, FrameKind};
+
+        let algorithm = SUPPORTED[0];
+        let policy = Policy::negotiated(Some(algorithm.name()), 64).unwrap();
+        let encoding = Encoding {
+            compression: Some(policy),
+            ..Encoding::default()
+        };
+        // The compression byte of the encoded frame.
+        let compressed_with = |payload: &[u8]| {
+            let bytes = Frame::new(FrameKind::Request, 7, payload)
+                .with_encoding(encoding)
+                .encode()
+                .unwrap();
+            let frame = Frame::decode_unverified(&bytes, Some(algorithm)).unwrap();
+            assert_eq!(&*frame.payload, payload);
+            bytes[2]
+        };
+        assert_eq!(compressed_with(&[0; 63]), 0);
+        assert_eq!(compressed_with(&[0; 64]), algorithm.id());
+
+        // Bytes that do not shrink are sent as they are whatever their size.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        assert_eq!(compressed_with(&noise), 0);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_payloads_announcing_too_much_are_rejected() {
+        let mut compressed = Compression::Lz4.compress(&[0; 256]).unwrap();
+        compressed[..4].copy_from_slice(&(MAX_DECOMPRESSED_LEN as u32 + 1).to_le_bytes());
+        assert!(matches!(
+            Compression::Lz4.decompress(&compressed),
+            Err(Error::Codec(_))
+        ));
+        assert!(matches!(
+            Compression::Lz4.decompress(&[0; 3]),
+            Err(Error::Codec(_))
+        ));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_payloads_inflating_past_the_limit_are_rejected() {
+        let compressed = Compression::Zstd
+            .compress(&vec![0; MAX_DECOMPRESSED_LEN + 1])
+            .unwrap();
+        assert!(compressed.len() < 64 << 10);
+        assert!(matches!(
+            Compression::Zstd.decompress(&compressed),
+            Err(Error::Codec(_))
+        ));
+    }
+}
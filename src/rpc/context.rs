@@ -0,0 +1,178 @@
+//! Server-side view of the call being handled.
+
+use super::frame::{Frame, FrameKind};
+use super::handshake;
+use super::stream::Channel;
+use super::Metadata;
+use crate::ucp::endpoint::Endpoint;
+use crate::ucp::Worker;
+use std::cell::{Cell, RefCell};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Reserved header carrying the time left until the deadline of the call,
+/// in decimal microseconds.
+pub(crate) const TIMEOUT_HEADER: &str = "ucx-timeout";
+
+thread_local! {
+    // The call whose handler is running on this thread.
+    static CURRENT_CALL: RefCell<Option<Rc<CallContext>>> = const { RefCell::new(None) };
+}
+
+/// Whether the client has abandoned the call whose handler is running on this thread.
+///
+/// Long-running handlers can check it to stop early; the client is no longer
+/// waiting for their result. Outside of a handler it returns false.
+pub fn cancelled() -> bool {
+    CallContext::current().is_some_and(|ctx| ctx.is_cancelled())
+}
+
+/// The call a handler is running for.
+///
+/// Handlers get it with [`CallContext::current`].
+#[derive(Debug)]
+pub struct CallContext {
+    method_id: u32,
+    conn_id: u32,
+    call_id: u32,
+    peer_addr: Option<SocketAddr>,
+    endpoint: Rc<Endpoint>,
+    headers: Metadata,
+    deadline: Option<Instant>,
+    trailers: RefCell<Metadata>,
+    state: CallState,
+}
+
+#[derive(Debug)]
+pub(crate) enum CallState {
+    Unary {
+        worker: Rc<Worker>,
+        request_tag: u64,
+        // The key of the connection, which the cancel notice carries.
+        conn_key: u64,
+        cancelled: Cell<bool>,
+    },
+    Streaming(Rc<Channel>),
+}
+
+impl CallContext {
+    pub(crate) fn new(
+        method_id: u32,
+        (conn_id, call_id): (u32, u32),
+        peer_addr: Option<SocketAddr>,
+        endpoint: Rc<Endpoint>,
+        mut headers: Metadata,
+        state: CallState,
+    ) -> Rc<Self> {
+        let reserved = headers.take_reserved();
+        let deadline = reserved
+            .get_str(TIMEOUT_HEADER)
+            .and_then(|micros| micros.parse().ok())
+            .map(|micros| Instant::now() + Duration::from_micros(micros));
+        if let CallState::Streaming(channel) = &state {
+            channel.set_deadline(deadline);
+        }
+        Rc::new(CallContext {
+            method_id,
+            conn_id,
+            call_id,
+            peer_addr,
+            endpoint,
+            headers,
+            deadline,
+            trailers: RefCell::new(Metadata::new()),
+            state,
+        })
+    }
+
+    /// The context of the call whose handler is running on this thread.
+    pub fn current() -> Option<Rc<CallContext>> {
+        CURRENT_CALL.with(|call| call.borrow().clone())
+    }
+
+    /// The id of the called method.
+    pub fn method_id(&self) -> u32 {
+        self.method_id
+    }
+
+    /// The id the server assigned to the connection of the call.
+    pub fn conn_id(&self) -> u32 {
+        self.conn_id
+    }
+
+    /// The id of the call on its connection.
+    pub fn call_id(&self) -> u32 {
+        self.call_id
+    }
+
+    /// The address of the client, if UCX reported it when the connection was accepted.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// The endpoint connected to the client.
+    pub fn endpoint(&self) -> &Rc<Endpoint> {
+        &self.endpoint
+    }
+
+    /// The headers the client sent with the call.
+    pub fn headers(&self) -> &Metadata {
+        &self.headers
+    }
+
+    /// When the client gives up on the call, if it set a deadline.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Sets a trailer sent back with the response.
+    pub fn set_trailer(&self, key: impl AsRef<str>, value: impl Into<Vec<u8>>) {
+        self.trailers.borrow_mut().insert(key, value);
+    }
+
+    pub(crate) fn take_trailers(&self) -> Metadata {
+        self.trailers.take()
+    }
+
+    /// Whether the client has abandoned the call.
+    pub fn is_cancelled(&self) -> bool {
+        match &self.state {
+            CallState::Unary {
+                worker,
+                request_tag,
+                conn_key,
+                cancelled,
+            } => {
+                worker.progress();
+                // Nothing but the request and a cancel notice travels on the tag of a unary call.
+                while let Some(message) = worker.tag_probe(*request_tag, u64::MAX) {
+                    let is_cancel = worker.recv_probed(message).is_ok_and(|bytes| {
+                        Frame::decode_header(&bytes).is_ok_and(|frame| {
+                            frame.kind == FrameKind::Cancel
+                                && handshake::conn_key(&frame.metadata) == Some(*conn_key)
+                        })
+                    });
+                    if is_cancel {
+                        cancelled.set(true);
+                    }
+                }
+                cancelled.get()
+            }
+            CallState::Streaming(channel) => channel.poll_cancelled(),
+        }
+    }
+
+    /// Makes the call current until the guard is dropped.
+    pub(crate) fn enter(self: &Rc<Self>) -> ContextGuard {
+        ContextGuard(CURRENT_CALL.replace(Some(self.clone())))
+    }
+}
+
+pub(crate) struct ContextGuard(Option<Rc<CallContext>>);
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        CURRENT_CALL.set(self.0.take());
+    }
+}
@@ -0,0 +1,447 @@
+//! Wire format of RPC messages.
+//!
+//! Every message starts with a fixed little-endian header, followed by the
+//! [`Metadata`] if the metadata flag is set, and the payload encoded with the
+//! [`DefaultCodec`](crate::codec::DefaultCodec):
+//!
+//! | offset | size | field       |
+//! |--------|------|-------------|
+//! | 0      | 1    | kind        |
+//! | 1      | 1    | flags       |
+//! | 2      | 1    | compression |
+//! | 3      | 1    | checksum    |
+//! | 4      | 4    | method id   |
+//!
+//! The compression byte is the id of the algorithm the payload is compressed
+//! with, or 0; the metadata is never compressed. The checksum byte is the id
+//! of the algorithm of the [checksum](super::checksum) that ends the frame, or 0.
+
+use super::checksum::Checksum;
+use super::compression::{Compression, Policy};
+use super::Metadata;
+use crate::ucp::endpoint::Endpoint;
+use crate::Error;
+use std::borrow::Cow;
+
+pub(crate) const HEADER_LEN: usize = 8;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FrameKind {
+    /// A call from the client.
+    Request = 1,
+    /// The encoded return value of the handler.
+    Response = 2,
+    /// The server failed to run the handler; the payload is the error text.
+    Error = 3,
+    /// One message of a streaming call.
+    StreamItem = 4,
+    /// The client has no more messages to send on a streaming call.
+    StreamEnd = 5,
+    /// Grants the peer more stream items; the payload is a little-endian `u32` count.
+    Credit = 6,
+    /// The sender is no longer interested in the call.
+    Cancel = 7,
+}
+
+impl FrameKind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            1 => Some(Self::Request),
+            2 => Some(Self::Response),
+            3 => Some(Self::Error),
+            4 => Some(Self::StreamItem),
+            5 => Some(Self::StreamEnd),
+            6 => Some(Self::Credit),
+            7 => Some(Self::Cancel),
+            _ => None,
+        }
+    }
+}
+
+/// Set on a request that opens a streaming call. Its payload starts with the
+/// stream window as a little-endian `u32`, followed by the initial request.
+pub(crate) const FLAG_STREAMING: u8 = 1;
+/// Set when the header is followed by metadata.
+pub(crate) const FLAG_METADATA: u8 = 2;
+
+/// What the frames received on a connection must be encoded with, as
+/// negotiated at handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Accepted {
+    /// The checksum every frame must carry.
+    pub checksum: Option<Checksum>,
+    /// The algorithm payloads may be compressed with.
+    pub compression: Option<Compression>,
+}
+
+/// How the frames sent on a connection are encoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Encoding {
+    pub compression: Option<Policy>,
+    pub checksum: Option<Checksum>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Frame<'a> {
+    pub kind: FrameKind,
+    pub flags: u8,
+    pub method_id: u32,
+    pub metadata: Metadata,
+    // Decompressed when decoding.
+    pub payload: Cow<'a, [u8]>,
+    encoding: Encoding,
+}
+
+impl<'a> Frame<'a> {
+    pub fn new(kind: FrameKind, method_id: u32, payload: &'a [u8]) -> Self {
+        Frame {
+            kind,
+            flags: 0,
+            method_id,
+            metadata: Metadata::new(),
+            payload: Cow::Borrowed(payload),
+            encoding: Encoding::default(),
+        }
+    }
+
+    pub fn with_flags(mut self, flags: u8) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    // Compresses the payload when encoding if it is at least as long as the
+    // threshold of the compression policy, and appends the checksum.
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut flags = self.flags & !FLAG_METADATA;
+        if !self.metadata.is_empty() {
+            flags |= FLAG_METADATA;
+        }
+        let compressed = match self.encoding.compression {
+            Some(policy) if self.payload.len() >= policy.threshold => {
+                let compressed = policy.algorithm.compress(&self.payload)?;
+                // Incompressible payloads are sent as they are.
+                (compressed.len() < self.payload.len()).then_some((policy.algorithm, compressed))
+            }
+            _ => None,
+        };
+        let (compression, payload) = match &compressed {
+            Some((algorithm, compressed)) => (algorithm.id(), compressed.as_slice()),
+            None => (0, &*self.payload),
+        };
+        let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+        bytes.push(self.kind as u8);
+        bytes.push(flags);
+        bytes.push(compression);
+        bytes.push(self.encoding.checksum.map_or(0, Checksum::id));
+        bytes.extend_from_slice(&self.method_id.to_le_bytes());
+        if !self.metadata.is_empty() {
+            self.metadata.encode_into(&mut bytes)?;
+        }
+        bytes.extend_from_slice(payload);
+        if let Some(checksum) = self.encoding.checksum {
+            checksum.append(&mut bytes);
+        }
+        Ok(bytes)
+    }
+
+    // Decodes a frame that arrived with `tag` from `ep`, failing with
+    // `Error::ChecksumMismatch` if it has a checksum that does not match, or
+    // lacks the one the connection negotiated.
+    pub fn decode(
+        bytes: &'a [u8],
+        tag: u64,
+        ep: &Endpoint,
+        accepted: Accepted,
+    ) -> Result<Self, Error> {
+        if !verified(bytes, accepted.checksum)? {
+            return Err(Error::ChecksumMismatch {
+                tag,
+                endpoint: describe(ep),
+                len: bytes.len(),
+            });
+        }
+        Self::decode_unverified(bytes, accepted.compression)
+    }
+
+    // Decodes a frame without checking its checksum, failing if its payload
+    // is compressed with another algorithm than `compression`.
+    pub fn decode_unverified(
+        bytes: &'a [u8],
+        compression: Option<Compression>,
+    ) -> Result<Self, Error> {
+        let (mut frame, payload) = Self::split(bytes)?;
+        frame.payload = match bytes[2] {
+            0 => Cow::Borrowed(payload),
+            id => {
+                let algorithm = Compression::from_id(id)
+                    .filter(|&algorithm| Some(algorithm) == compression)
+                    .ok_or_else(|| {
+                        Error::Codec(format!(
+                            "RPC frame compressed with algorithm {id}, which was not negotiated"
+                        ))
+                    })?;
+                Cow::Owned(algorithm.decompress(payload)?)
+            }
+        };
+        Ok(frame)
+    }
+
+    // Decodes the header and metadata of a frame, for frames that are only
+    // rejected. The payload is left empty rather than decompressed.
+    pub fn decode_header(bytes: &'a [u8]) -> Result<Self, Error> {
+        Self::split(bytes).map(|(frame, _)| frame)
+    }
+
+    // The frame without its payload, and the payload as it was sent.
+    fn split(bytes: &'a [u8]) -> Result<(Self, &'a [u8]), Error> {
+        let checksum_len = checksum(bytes)?.map_or(0, Checksum::len);
+        if bytes.len() < HEADER_LEN + checksum_len {
+            return Err(Error::Codec(format!(
+                "RPC frame too short: {} bytes",
+                bytes.len()
+            )));
+        }
+        let bytes = &bytes[..bytes.len() - checksum_len];
+        let kind = FrameKind::from_u8(bytes[0])
+            .ok_or_else(|| Error::Codec(format!("unknown RPC frame kind {}", bytes[0])))?;
+        let flags = bytes[1];
+        let (metadata, payload) = if flags & FLAG_METADATA != 0 {
+            Metadata::decode(&bytes[HEADER_LEN..])?
+        } else {
+            (Metadata::new(), &bytes[HEADER_LEN..])
+        };
+        let frame = Frame {
+            kind,
+            flags,
+            method_id: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            metadata,
+            payload: Cow::Borrowed(&[]),
+            encoding: Encoding::default(),
+        };
+        Ok((frame, payload))
+    }
+}
+
+// The checksum algorithm of the frame in `bytes`, if it has a checksum.
+fn checksum(bytes: &[u8]) -> Result<Option<Checksum>, Error> {
+    match bytes.get(3) {
+        None | Some(0) => Ok(None),
+        Some(&id) => Checksum::from_id(id).map(Some).ok_or_else(|| {
+            Error::Codec(format!(
+                "RPC frame with unsupported checksum algorithm {id}"
+            ))
+        }),
+    }
+}
+
+// Whether the frame in `bytes` has a checksum that matches, or none and
+// none is `required`.
+fn verified(bytes: &[u8], required: Option<Checksum>) -> Result<bool, Error> {
+    let checksum = checksum(bytes)?;
+    Ok(match checksum {
+        _ if required.is_some() && checksum != required => false,
+        Some(checksum) => checksum.verify(bytes),
+        None => true,
+    })
+}
+
+// The peer address of `ep` or, for endpoints created from a worker address,
+// its handle.
+fn describe(ep: &Endpoint) -> String {
+    match ep.query().ok().and_then(|info| info.remote_addr) {
+        Some(addr) => addr.to_string(),
+        None => format!("endpoint {:p}", ep.ptr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(frame: Frame) -> Vec<u8> {
+        frame.encode().unwrap()
+    }
+
+    #[test]
+    fn frame_round_trips_with_metadata() {
+        let bytes = encoded(
+            Frame::new(FrameKind::Request, 0x1234_5678, b"payload")
+                .with_flags(FLAG_STREAMING)
+                .with_metadata(Metadata::new().with("tenant", "a")),
+        );
+        assert_eq!(bytes[1], FLAG_STREAMING | FLAG_METADATA);
+        let frame = Frame::decode_unverified(&bytes, None).unwrap();
+        assert_eq!(frame.kind, FrameKind::Request);
+        assert_eq!(frame.flags, FLAG_STREAMING | FLAG_METADATA);
+        assert_eq!(frame.method_id, 0x1234_5678);
+        assert_eq!(frame.metadata.get_str("tenant"), Some("a"));
+        assert_eq!(&*frame.payload, b"payload");
+    }
+
+    #[test]
+    fn frame_without_metadata_has_the_payload_after_the_header() {
+        let bytes = encoded(Frame::new(FrameKind::Response, 7, b"ok"));
+        assert_eq!(bytes.len(), HEADER_LEN + 2);
+        assert_eq!(bytes[1] & FLAG_METADATA, 0);
+        assert_eq!(
+            &*Frame::decode_unverified(&bytes, None).unwrap().payload,
+            b"ok"
+        );
+    }
+
+    #[test]
+    fn truncated_header_is_rejected() {
+        let bytes = encoded(Frame::new(FrameKind::Response, 7, b""));
+        for len in 0..HEADER_LEN {
+            assert!(matches!(
+                Frame::decode_unverified(&bytes[..len], None),
+                Err(Error::Codec(_))
+            ));
+        }
+        assert!(Frame::decode_unverified(&bytes, None).is_ok());
+    }
+
+    #[test]
+    fn truncated_metadata_is_rejected() {
+        let bytes = encoded(
+            Frame::new(FrameKind::Request, 7, b"")
+                .with_metadata(Metadata::new().with("key", "value")),
+        );
+        for len in HEADER_LEN..bytes.len() {
+            assert!(matches!(
+                Frame::decode_unverified(&bytes[..len], None),
+                Err(Error::Codec(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn unknown_header_values_are_rejected() {
+        let bytes = encoded(Frame::new(FrameKind::Response, 7, b"ok"));
+        let rejected = |offset: usize, value: u8| {
+            let mut bytes = bytes.clone();
+            bytes[offset] = value;
+            matches!(Frame::decode_unverified(&bytes, None), Err(Error::Codec(_)))
+        };
+        assert!(rejected(0, 0));
+        assert!(rejected(0, 8));
+        assert!(rejected(2, 0xff));
+        assert!(rejected(3, 0xff));
+    }
+
+    #[test]
+    fn frames_without_a_checksum_pass_unless_one_is_required() {
+        let bytes = encoded(Frame::new(FrameKind::Response, 7, b"ok"));
+        assert!(verified(&bytes, None).unwrap());
+    }
+
+    #[cfg(feature = "crc32c")]
+    #[test]
+    fn crc32c_checksum_is_verified() {
+        let encoding = Encoding {
+            checksum: Some(Checksum::Crc32c),
+            ..Encoding::default()
+        };
+        let bytes = encoded(Frame::new(FrameKind::Response, 7, b"ok").with_encoding(encoding));
+        assert_eq!(bytes.len(), HEADER_LEN + 2 + 4);
+        assert!(verified(&bytes, None).unwrap());
+        assert!(verified(&bytes, Some(Checksum::Crc32c)).unwrap());
+        assert_eq!(
+            &*Frame::decode_unverified(&bytes, None).unwrap().payload,
+            b"ok"
+        );
+
+        for i in 0..bytes.len() {
+            let mut corrupted = bytes.clone();
+            corrupted[i] ^= 0x10;
+            // Corrupting the checksum byte of the header makes it unknown.
+            assert!(!verified(&corrupted, None).unwrap_or(false), "byte {i}");
+        }
+        // Too short to hold the checksum it announces.
+        assert!(matches!(
+            Frame::decode_unverified(&bytes[..HEADER_LEN + 3], None),
+            Err(Error::Codec(_))
+        ));
+        assert!(!verified(&bytes[..3], Some(Checksum::Crc32c)).unwrap());
+    }
+
+    #[cfg(feature = "crc32c")]
+    #[test]
+    fn frames_without_the_required_checksum_are_rejected() {
+        let plain = encoded(Frame::new(FrameKind::Response, 7, b"ok"));
+        assert!(!verified(&plain, Some(Checksum::Crc32c)).unwrap());
+    }
+
+    #[cfg(all(feature = "crc32c", feature = "xxhash"))]
+    #[test]
+    fn frames_with_another_checksum_are_rejected() {
+        let encoding = Encoding {
+            checksum: Some(Checksum::Xxh3),
+            ..Encoding::default()
+        };
+        let bytes = encoded(Frame::new(FrameKind::Response, 7, b"ok").with_encoding(encoding));
+        assert!(verified(&bytes, None).unwrap());
+        assert!(!verified(&bytes, Some(Checksum::Crc32c)).unwrap());
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn payloads_compressed_without_negotiation_are_rejected() {
+        let encoding = Encoding {
+            compression: Some(Policy {
+                algorithm: Compression::Lz4,
+                threshold: 0,
+            }),
+            ..Encoding::default()
+        };
+        let payload = vec![b'a'; 256];
+        let bytes = encoded(Frame::new(FrameKind::Request, 7, &payload).with_encoding(encoding));
+        assert_eq!(bytes[2], Compression::Lz4.id());
+        assert!(matches!(
+            Frame::decode_unverified(&bytes, None),
+            Err(Error::Codec(_))
+        ));
+        let frame = Frame::decode_unverified(&bytes, Some(Compression::Lz4)).unwrap();
+        assert_eq!(&*frame.payload, &payload[..]);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn header_is_decoded_without_the_payload() {
+        let encoding = Encoding {
+            compression: Some(Policy {
+                algorithm: Compression::Lz4,
+                threshold: 0,
+            }),
+            ..Encoding::default()
+        };
+        let mut bytes = encoded(
+            Frame::new(FrameKind::Request, 7, &[0; 256])
+                .with_metadata(Metadata::new().with("key", "value"))
+                .with_encoding(encoding),
+        );
+        // A size prefix past the limit, which only decompressing would notice.
+        let compressed = Compression::Lz4.compress(&[0; 256]).unwrap();
+        let payload = bytes.len() - compressed.len();
+        bytes[payload..payload + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            Frame::decode_unverified(&bytes, Some(Compression::Lz4)),
+            Err(Error::Codec(_))
+        ));
+        let frame = Frame::decode_header(&bytes).unwrap();
+        assert_eq!(frame.kind, FrameKind::Request);
+        assert_eq!(frame.metadata.get_str("key"), Some("value"));
+        assert!(frame.payload.is_empty());
+    }
+}
@@ -0,0 +1,469 @@
+//! Version and capability negotiation when an RPC connection is set up.
+//!
+//! When it accepts a connection, the server sends the client its connection
+//! id and a random connection key, on the stream of the endpoint so that no
+//! other client can take them. The client sends the key with its hello and
+//! every frame of its calls, which tells the server that they come from that
+//! client rather than one using its connection id in the tag. The same
+//! message carries the inbox of the server's [keepalive](crate::keepalive)
+//! for the connection, or 0 if the server runs none.
+//!
+//! After receiving its connection id, a client may send a [`Hello`] with its
+//! protocol version, the service it wants, the codecs, compression and
+//! checksum algorithms it supports and who it is. The server answers with what was
+//! [`Negotiated`], or rejects the connection and closes it, in which case the
+//! client fails with [`Error::Handshake`].
+//!
+//! Both messages are encoded as [`Metadata`], so they can be read whatever
+//! codec the peers were built with, and peers ignore the keys they do not
+//! know. They travel on the connect channel with the connection id as the
+//! source: hellos with sequence 1 and answers with sequence 2.
+
+use super::checksum::{self, Checksum};
+use super::compression::{self, Compression};
+use super::*;
+use crate::codec::DEFAULT_CODEC_NAME;
+use crate::tag;
+use crate::ucp::endpoint::Endpoint;
+use tracing::debug;
+
+/// The version of the RPC protocol implemented by this crate.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// The oldest protocol version this crate talks to.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+pub(crate) const HELLO: u32 = 1;
+pub(crate) const ANSWER: u32 = 2;
+
+/// Reserved header carrying the connection key, as 8 little-endian bytes.
+pub(crate) const CONN_KEY_HEADER: &str = "ucx-conn-key";
+
+const VERSION_KEY: &str = "version";
+const SERVICE_KEY: &str = "service";
+const CODECS_KEY: &str = "codecs";
+const CODEC_KEY: &str = "codec";
+const COMPRESSION_KEY: &str = "compression";
+const CHECKSUMS_KEY: &str = "checksums";
+const CHECKSUM_KEY: &str = "checksum";
+const IDENTITY_KEY: &str = "identity";
+const ERROR_KEY: &str = "error";
+
+// The id and key of a connection, which the server grants a new client,
+// and the keepalive inbox of the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Grant {
+    pub conn_id: u32,
+    pub key: u64,
+    pub keepalive: u32,
+}
+
+impl Grant {
+    pub fn new(conn_id: u32, keepalive: u32) -> Result<Self, Error> {
+        Ok(Grant {
+            conn_id,
+            key: u64::from_le_bytes(crate::random_bytes()?),
+            keepalive,
+        })
+    }
+
+    pub fn send(&self, ep: &Endpoint, deadline: Option<Instant>) -> Result<(), Error> {
+        let mut body = self.conn_id.to_le_bytes().to_vec();
+        body.extend_from_slice(&self.key.to_le_bytes());
+        body.extend_from_slice(&self.keepalive.to_le_bytes());
+        tag::send_setup(ep, CONNECT_CHANNEL, &body, deadline)
+    }
+
+    pub fn recv(ep: &Endpoint, deadline: Option<Instant>) -> Result<Self, Error> {
+        let body: [u8; 16] = tag::recv_setup(ep, CONNECT_CHANNEL, deadline)?;
+        Ok(Grant {
+            conn_id: u32::from_le_bytes(body[..4].try_into().unwrap()),
+            key: u64::from_le_bytes(body[4..12].try_into().unwrap()),
+            keepalive: u32::from_le_bytes(body[12..].try_into().unwrap()),
+        })
+    }
+}
+
+// The connection key in `metadata`, if any.
+pub(crate) fn conn_key(metadata: &Metadata) -> Option<u64> {
+    let key = metadata.get(CONN_KEY_HEADER)?;
+    Some(u64::from_le_bytes(key.try_into().ok()?))
+}
+
+/// What a client tells the server about itself when connecting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    /// The protocol version of the client.
+    pub version: u32,
+    /// The service the client calls; empty if it calls several.
+    pub service: String,
+    /// The codecs the client can encode payloads with.
+    pub codecs: Vec<String>,
+    /// The compression algorithms the client supports, most preferred first.
+    pub compression: Vec<String>,
+    /// The checksum algorithms the client supports.
+    pub checksums: Vec<String>,
+    /// Who the client is, e.g. a host and process name, for the logs of the
+    /// server. It is not authenticated.
+    pub identity: String,
+}
+
+impl Hello {
+    /// A hello for `service` with the capabilities of this build.
+    pub fn new(service: impl Into<String>) -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            service: service.into(),
+            codecs: vec![DEFAULT_CODEC_NAME.to_string()],
+            compression: compression::SUPPORTED
+                .iter()
+                .map(|compression| compression.name().to_string())
+                .collect(),
+            checksums: checksum::SUPPORTED
+                .iter()
+                .map(|checksum| checksum.name().to_string())
+                .collect(),
+            identity: String::new(),
+        }
+    }
+
+    /// Sets the identity of the client.
+    pub fn with_identity(mut self, identity: impl Into<String>) -> Self {
+        self.identity = identity.into();
+        self
+    }
+
+    pub(crate) fn encode(&self, conn_key: u64) -> Result<Vec<u8>, Error> {
+        let metadata = Metadata::new()
+            .with(CONN_KEY_HEADER, conn_key.to_le_bytes())
+            .with(VERSION_KEY, self.version.to_string())
+            .with(SERVICE_KEY, self.service.as_str())
+            .with(CODECS_KEY, self.codecs.join(","))
+            .with(COMPRESSION_KEY, self.compression.join(","))
+            .with(CHECKSUMS_KEY, self.checksums.join(","))
+            .with(IDENTITY_KEY, self.identity.as_str());
+        let mut bytes = Vec::new();
+        metadata.encode_into(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    // Also returns the connection key the hello was sent with.
+    pub(crate) fn decode(bytes: &[u8]) -> Result<(Self, Option<u64>), Error> {
+        let (metadata, _) = Metadata::decode(bytes)?;
+        let text = |key| metadata.get_str(key).unwrap_or_default().to_string();
+        let hello = Hello {
+            version: version(&metadata)?,
+            service: text(SERVICE_KEY),
+            codecs: list(&metadata, CODECS_KEY),
+            compression: list(&metadata, COMPRESSION_KEY),
+            checksums: list(&metadata, CHECKSUMS_KEY),
+            identity: text(IDENTITY_KEY),
+        };
+        Ok((hello, conn_key(&metadata)))
+    }
+}
+
+/// What the server and a client agreed on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    /// The protocol version both sides speak.
+    pub version: u32,
+    /// The codec payloads are encoded with.
+    pub codec: String,
+    /// The compression algorithm payloads may be compressed with, if any.
+    pub compression: Option<String>,
+    /// The checksum every frame carries, if any.
+    pub checksum: Option<String>,
+}
+
+impl Negotiated {
+    // The algorithm of `checksum`.
+    pub(crate) fn checksum_algorithm(&self) -> Option<Checksum> {
+        self.checksum.as_deref().and_then(Checksum::from_name)
+    }
+}
+
+// Decides, on the server, whether to accept the connection of the client
+// that sent `hello`, and returns the reason if not. Clients must support the
+// checksum of the server, if it has one.
+pub(crate) fn negotiate(
+    hello: &Hello,
+    has_service: impl Fn(&str) -> bool,
+    checksum: Option<Checksum>,
+) -> Result<Negotiated, String> {
+    if hello.version < MIN_PROTOCOL_VERSION {
+        return Err(format!(
+            "protocol version {} is not supported, the oldest supported is {}",
+            hello.version, MIN_PROTOCOL_VERSION
+        ));
+    }
+    if !hello.service.is_empty() && !has_service(&hello.service) {
+        return Err(format!("service {:?} is not served", hello.service));
+    }
+    if !hello.codecs.iter().any(|codec| codec == DEFAULT_CODEC_NAME) {
+        return Err(format!(
+            "no common codec: the client supports {:?}, the server uses {:?}",
+            hello.codecs, DEFAULT_CODEC_NAME
+        ));
+    }
+    let compression = hello
+        .compression
+        .iter()
+        .find(|name| Compression::from_name(name).is_some())
+        .cloned();
+    if let Some(checksum) = checksum {
+        if !hello.checksums.iter().any(|name| name == checksum.name()) {
+            return Err(format!(
+                "no common checksum: the client supports {:?}, the server requires {:?}",
+                hello.checksums,
+                checksum.name()
+            ));
+        }
+    }
+    Ok(Negotiated {
+        version: hello.version.min(PROTOCOL_VERSION),
+        codec: DEFAULT_CODEC_NAME.to_string(),
+        compression,
+        checksum: checksum.map(|checksum| checksum.name().to_string()),
+    })
+}
+
+pub(crate) fn encode_answer(answer: Result<&Negotiated, &str>) -> Result<Vec<u8>, Error> {
+    let metadata = match answer {
+        Ok(negotiated) => Metadata::new()
+            .with(VERSION_KEY, negotiated.version.to_string())
+            .with(CODEC_KEY, negotiated.codec.as_str())
+            .with(
+                COMPRESSION_KEY,
+                negotiated.compression.as_deref().unwrap_or_default(),
+            )
+            .with(
+                CHECKSUM_KEY,
+                negotiated.checksum.as_deref().unwrap_or_default(),
+            ),
+        Err(reason) => Metadata::new().with(ERROR_KEY, reason),
+    };
+    let mut bytes = Vec::new();
+    metadata.encode_into(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Sends `hello` on the connection of `grant` and waits for the answer of the
+// server, checking that the client supports what the server picked.
+pub(crate) fn exchange(
+    ep: &Endpoint,
+    grant: Grant,
+    hello: &Hello,
+    deadline: Option<Instant>,
+) -> Result<Negotiated, Error> {
+    let conn_id = grant.conn_id;
+    ep.send_bytes_until(
+        CONNECT_CHANNEL.tag(conn_id, HELLO),
+        &hello.encode(grant.key)?,
+        deadline,
+    )?;
+    let (_, bytes) =
+        ep.recv_bytes_until(CONNECT_CHANNEL.tag(conn_id, ANSWER), u64::MAX, deadline)?;
+    let negotiated = decode_answer(hello, &bytes)?;
+    debug!(conn_id, ?negotiated, "rpc handshake completed");
+    Ok(negotiated)
+}
+
+// Decodes the answer of the server to `hello`.
+fn decode_answer(hello: &Hello, bytes: &[u8]) -> Result<Negotiated, Error> {
+    let (metadata, _) = Metadata::decode(bytes)?;
+    if let Some(reason) = metadata.get_str(ERROR_KEY) {
+        return Err(Error::Handshake(format!(
+            "rejected by the server: {reason}"
+        )));
+    }
+    let negotiated = Negotiated {
+        version: version(&metadata)?,
+        codec: metadata.get_str(CODEC_KEY).unwrap_or_default().to_string(),
+        compression: metadata
+            .get_str(COMPRESSION_KEY)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string),
+        checksum: metadata
+            .get_str(CHECKSUM_KEY)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string),
+    };
+    if !(MIN_PROTOCOL_VERSION..=hello.version).contains(&negotiated.version) {
+        return Err(Error::Handshake(format!(
+            "the server picked protocol version {}",
+            negotiated.version
+        )));
+    }
+    if !hello.codecs.contains(&negotiated.codec) {
+        return Err(Error::Handshake(format!(
+            "the server picked codec {:?}",
+            negotiated.codec
+        )));
+    }
+    if let Some(compression) = &negotiated.compression {
+        if !hello.compression.contains(compression) {
+            return Err(Error::Handshake(format!(
+                "the server picked compression {compression:?}"
+            )));
+        }
+    }
+    if let Some(checksum) = &negotiated.checksum {
+        if !hello.checksums.contains(checksum) || negotiated.checksum_algorithm().is_none() {
+            return Err(Error::Handshake(format!(
+                "the server requires checksum {checksum:?}"
+            )));
+        }
+    }
+    Ok(negotiated)
+}
+
+fn version(metadata: &Metadata) -> Result<u32, Error> {
+    metadata
+        .get_str(VERSION_KEY)
+        .and_then(|version| version.parse().ok())
+        .ok_or_else(|| Error::Handshake("missing protocol version".to_string()))
+}
+
+fn list(metadata: &Metadata, key: &str) -> Vec<String> {
+    metadata
+        .get_str(key)
+        .unwrap_or_default()
+        .split(',')
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello() -> Hello {
+        Hello::new("Greeter").with_identity("test")
+    }
+
+    #[test]
+    fn hello_round_trips_with_the_key() {
+        let hello = hello();
+        let (decoded, key) = Hello::decode(&hello.encode(42).unwrap()).unwrap();
+        assert_eq!(decoded, hello);
+        assert_eq!(key, Some(42));
+    }
+
+    #[test]
+    fn server_negotiates_with_compatible_clients() {
+        let negotiated = negotiate(&hello(), |name| name == "Greeter", None).unwrap();
+        assert_eq!(negotiated.version, PROTOCOL_VERSION);
+        assert_eq!(negotiated.codec, DEFAULT_CODEC_NAME);
+        assert_eq!(
+            negotiated.compression.as_deref(),
+            compression::SUPPORTED.first().map(|c| c.name())
+        );
+        assert_eq!(negotiated.checksum, None);
+
+        let mut any_service = hello();
+        any_service.service.clear();
+        assert!(negotiate(&any_service, |_| false, None).is_ok());
+    }
+
+    #[test]
+    fn server_rejects_incompatible_clients() {
+        let cases: Vec<(&str, Hello, Option<Checksum>)> = vec![
+            (
+                "protocol version",
+                Hello {
+                    version: MIN_PROTOCOL_VERSION - 1,
+                    ..hello()
+                },
+                None,
+            ),
+            (
+                "service",
+                Hello {
+                    service: "Unknown".to_string(),
+                    ..hello()
+                },
+                None,
+            ),
+            (
+                "codec",
+                Hello {
+                    codecs: vec!["nope".to_string()],
+                    ..hello()
+                },
+                None,
+            ),
+        ];
+        let required = checksum::SUPPORTED.iter().map(|&checksum| {
+            let hello = Hello {
+                checksums: Vec::new(),
+                ..hello()
+            };
+            ("checksum", hello, Some(checksum))
+        });
+        for (what, hello, checksum) in cases.into_iter().chain(required) {
+            let reason = negotiate(&hello, |name| name == "Greeter", checksum).unwrap_err();
+            assert!(reason.contains(what), "{what}: {reason}");
+        }
+    }
+
+    #[test]
+    fn client_rejects_answers_it_did_not_offer() {
+        let hello = Hello {
+            compression: Vec::new(),
+            checksums: Vec::new(),
+            ..hello()
+        };
+        let agreed = Negotiated {
+            version: PROTOCOL_VERSION,
+            codec: DEFAULT_CODEC_NAME.to_string(),
+            compression: None,
+            checksum: None,
+        };
+        let answer = |negotiated: &Negotiated| encode_answer(Ok(negotiated)).unwrap();
+        assert_eq!(decode_answer(&hello, &answer(&agreed)).unwrap(), agreed);
+
+        let cases = [
+            (
+                "protocol version",
+                Negotiated {
+                    version: PROTOCOL_VERSION + 1,
+                    ..agreed.clone()
+                },
+            ),
+            (
+                "codec",
+                Negotiated {
+                    codec: "nope".to_string(),
+                    ..agreed.clone()
+                },
+            ),
+            (
+                "compression",
+                Negotiated {
+                    compression: Some("lz4".to_string()),
+                    ..agreed.clone()
+                },
+            ),
+            (
+                "checksum",
+                Negotiated {
+                    checksum: Some("crc32c".to_string()),
+                    ..agreed.clone()
+                },
+            ),
+        ];
+        for (what, negotiated) in cases {
+            match decode_answer(&hello, &answer(&negotiated)) {
+                Err(Error::Handshake(reason)) => assert!(reason.contains(what), "{reason}"),
+                other => panic!("{what}: {other:?}"),
+            }
+        }
+
+        let rejected = encode_answer(Err("service \"Greeter\" is not served")).unwrap();
+        match decode_answer(&hello, &rejected) {
+            Err(Error::Handshake(reason)) => assert!(reason.contains("not served"), "{reason}"),
+            other => panic!("{other:?}"),
+        }
+    }
+}
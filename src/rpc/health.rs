@@ -0,0 +1,110 @@
+//! The standard health-check service.
+//!
+//! Servers register a [`HealthService`] and keep the status of their services
+//! up to date through it; clients, load balancers and orchestrators ask for
+//! the status with [`check`].
+
+use super::*;
+use crate::codec::{Codec, DefaultCodec};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// The name of the health-check service.
+pub const SERVICE: &str = "ucx_rpc.Health";
+/// The id of the method returning the [`ServingStatus`] of a service.
+pub const CHECK: u32 = method_id(SERVICE, "check");
+
+static METHODS: [(u32, &str); 1] = [(CHECK, "check")];
+
+/// Whether a service handles calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ServingStatus {
+    /// The service handles calls.
+    Serving,
+    /// The service is registered but does not handle calls, e.g. while it
+    /// starts or drains.
+    NotServing,
+    /// The server has no status for the service.
+    ServiceUnknown,
+}
+
+/// Reports the status of the services of a server.
+///
+/// Clones share the statuses, so one can be registered with the server while
+/// another updates them.
+#[derive(Debug, Clone, Default)]
+pub struct HealthService {
+    statuses: Rc<RefCell<HashMap<String, ServingStatus>>>,
+}
+
+impl HealthService {
+    /// Creates a health service reporting the server as serving and no
+    /// other service.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the status of `service`; the empty name stands for the whole server.
+    pub fn set_status(&self, service: &str, status: ServingStatus) {
+        self.statuses
+            .borrow_mut()
+            .insert(service.to_string(), status);
+    }
+
+    /// Returns the status of `service`.
+    pub fn status(&self, service: &str) -> ServingStatus {
+        match self.statuses.borrow().get(service) {
+            Some(&status) => status,
+            None if service.is_empty() => ServingStatus::Serving,
+            None => ServingStatus::ServiceUnknown,
+        }
+    }
+
+    /// Reports every service, and the server, as not serving, e.g. before
+    /// shutting down so that clients move to other replicas.
+    pub fn shutdown(&self) {
+        let mut statuses = self.statuses.borrow_mut();
+        for status in statuses.values_mut() {
+            *status = ServingStatus::NotServing;
+        }
+        statuses.insert(String::new(), ServingStatus::NotServing);
+    }
+}
+
+impl Service for HealthService {
+    fn name(&self) -> &'static str {
+        SERVICE
+    }
+
+    fn methods(&self) -> &'static [(u32, &'static str)] {
+        &METHODS
+    }
+
+    fn call(&self, method_id: u32, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        match method_id {
+            CHECK => {
+                let service: String = DefaultCodec::decode(payload)?;
+                DefaultCodec::encode(&self.status(&service))
+            }
+            _ => Err(Error::Unsupported),
+        }
+    }
+}
+
+/// Asks the server of `client` for the status of `service`; the empty name
+/// stands for the whole server.
+pub fn check(client: &Client, service: &str) -> Result<ServingStatus, Error> {
+    client.call(CHECK, service)
+}
+
+/// Like [`check`], failing unless the status is [`ServingStatus::Serving`].
+///
+/// Fits [`Balancer::set_health_check`](super::Balancer::set_health_check).
+pub fn require_serving(client: &Client, service: &str) -> Result<(), Error> {
+    match check(client, service)? {
+        ServingStatus::Serving => Ok(()),
+        status => Err(Error::Remote(format!("{service:?} is {status:?}"))),
+    }
+}
@@ -0,0 +1,225 @@
+//! Key/value metadata sent alongside requests and responses.
+//!
+//! Keys are lowercase ASCII; keys starting with `ucx-` are reserved for the
+//! RPC layer itself. On the wire, metadata is a little-endian `u16` entry
+//! count followed by, for each entry, a `u16` key length, the key, a `u32`
+//! value length and the value.
+
+use crate::Error;
+use std::collections::BTreeMap;
+
+pub(crate) const RESERVED_PREFIX: &str = "ucx-";
+
+/// Headers of a call or trailers of its response.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    entries: BTreeMap<String, Vec<u8>>,
+}
+
+impl Metadata {
+    /// Creates empty metadata.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets `key` to `value`, replacing the previous value.
+    ///
+    /// The key is lowercased.
+    pub fn insert(&mut self, key: impl AsRef<str>, value: impl Into<Vec<u8>>) {
+        self.entries
+            .insert(key.as_ref().to_ascii_lowercase(), value.into());
+    }
+
+    /// Like [`Metadata::insert`], returning `self` for chaining.
+    pub fn with(mut self, key: impl AsRef<str>, value: impl Into<Vec<u8>>) -> Self {
+        self.insert(key, value);
+        self
+    }
+
+    /// The value of `key`.
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        self.entries
+            .get(&key.to_ascii_lowercase())
+            .map(Vec::as_slice)
+    }
+
+    /// The value of `key` if it is valid UTF-8.
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key)
+            .and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    /// Removes `key` and returns its value.
+    pub fn remove(&mut self, key: &str) -> Option<Vec<u8>> {
+        self.entries.remove(&key.to_ascii_lowercase())
+    }
+
+    /// Iterates over the entries in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_slice()))
+    }
+
+    /// The number of entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether there are no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Adds the entries of `other`, replacing existing keys.
+    pub fn extend(&mut self, other: Metadata) {
+        self.entries.extend(other.entries);
+    }
+
+    // Splits off the entries reserved for the RPC layer.
+    pub(crate) fn take_reserved(&mut self) -> Metadata {
+        let (reserved, user) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|(key, _)| key.starts_with(RESERVED_PREFIX));
+        self.entries = user;
+        Metadata { entries: reserved }
+    }
+
+    pub(crate) fn encode_into(&self, bytes: &mut Vec<u8>) -> Result<(), Error> {
+        let too_large = || Error::Codec("metadata too large".to_string());
+        let count = u16::try_from(self.entries.len()).map_err(|_| too_large())?;
+        bytes.extend_from_slice(&count.to_le_bytes());
+        for (key, value) in &self.entries {
+            let key_len = u16::try_from(key.len()).map_err(|_| too_large())?;
+            let value_len = u32::try_from(value.len()).map_err(|_| too_large())?;
+            bytes.extend_from_slice(&key_len.to_le_bytes());
+            bytes.extend_from_slice(key.as_bytes());
+            bytes.extend_from_slice(&value_len.to_le_bytes());
+            bytes.extend_from_slice(value);
+        }
+        Ok(())
+    }
+
+    /// Decodes metadata from the start of `bytes` and returns it with the rest.
+    pub(crate) fn decode(bytes: &[u8]) -> Result<(Self, &[u8]), Error> {
+        let mut reader = Reader(bytes);
+        let mut entries = BTreeMap::new();
+        for _ in 0..u16::from_le_bytes(reader.take()?) {
+            let key_len = u16::from_le_bytes(reader.take()?) as usize;
+            let key = std::str::from_utf8(reader.take_slice(key_len)?)
+                .map_err(|_| Error::Codec("metadata key is not UTF-8".to_string()))?;
+            let value_len = u32::from_le_bytes(reader.take()?) as usize;
+            let value = reader.take_slice(value_len)?;
+            entries.insert(key.to_string(), value.to_vec());
+        }
+        Ok((Metadata { entries }, reader.0))
+    }
+}
+
+impl<K: AsRef<str>, V: Into<Vec<u8>>> FromIterator<(K, V)> for Metadata {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut metadata = Metadata::new();
+        for (key, value) in iter {
+            metadata.insert(key, value);
+        }
+        metadata
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take_slice(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < len {
+            return Err(Error::Codec("truncated metadata".to_string()));
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.take_slice(N)?.try_into().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(metadata: &Metadata) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        metadata.encode_into(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn metadata_round_trips_and_returns_the_rest() {
+        let metadata = Metadata::new()
+            .with("Tenant", "a")
+            .with("bin", [0, 0xff])
+            .with("empty", "");
+        let mut bytes = encoded(&metadata);
+        bytes.extend_from_slice(b"payload");
+        let (decoded, rest) = Metadata::decode(&bytes).unwrap();
+        assert_eq!(decoded, metadata);
+        assert_eq!(decoded.get_str("TENANT"), Some("a"));
+        assert_eq!(rest, b"payload");
+    }
+
+    #[test]
+    fn truncated_metadata_is_rejected() {
+        let bytes = encoded(&Metadata::new().with("key", "value").with("other", "x"));
+        for len in 0..bytes.len() {
+            assert!(
+                matches!(Metadata::decode(&bytes[..len]), Err(Error::Codec(_))),
+                "{len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn oversized_lengths_are_rejected_without_allocating() {
+        // A count, key length and value length far beyond the bytes that follow.
+        let mut bytes = u16::MAX.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.push(b'k');
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(b"short");
+        assert!(matches!(Metadata::decode(&bytes), Err(Error::Codec(_))));
+
+        let mut bytes = 1u16.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&u16::MAX.to_le_bytes());
+        bytes.extend_from_slice(b"key");
+        assert!(matches!(Metadata::decode(&bytes), Err(Error::Codec(_))));
+    }
+
+    #[test]
+    fn keys_that_do_not_fit_are_not_encoded() {
+        let key = "k".repeat(u16::MAX as usize + 1);
+        let mut bytes = Vec::new();
+        assert!(matches!(
+            Metadata::new().with(key, "v").encode_into(&mut bytes),
+            Err(Error::Codec(_))
+        ));
+    }
+
+    #[test]
+    fn keys_must_be_utf8() {
+        let mut bytes = 1u16.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.push(0xff);
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        assert!(matches!(Metadata::decode(&bytes), Err(Error::Codec(_))));
+    }
+
+    #[test]
+    fn reserved_entries_are_split_off() {
+        let mut metadata = Metadata::new()
+            .with("ucx-conn-key", "k")
+            .with("tenant", "a");
+        let reserved = metadata.take_reserved();
+        assert_eq!(reserved, Metadata::new().with("ucx-conn-key", "k"));
+        assert_eq!(metadata, Metadata::new().with("tenant", "a"));
+    }
+}
@@ -0,0 +1,223 @@
+//! Remote procedure calls over UCX tag messages.
+//!
+//! A [`Server`] accepts connections on a [`Listener`](crate::ucp::listener::Listener)
+//! and dispatches requests to registered [`Service`]s; a [`Client`] wraps a
+//! connected [`Endpoint`](crate::ucp::endpoint::Endpoint). Services are usually
+//! defined with the [`service`](crate::service) attribute, which generates both
+//! stubs.
+//!
+//! When a connection is accepted, the server sends the client a connection id
+//! and key.
+//! Clients may then negotiate the protocol version and capabilities of the
+//! connection, see [`handshake`].
+//! Requests and responses then travel on the [`tag`](crate::tag) channels of
+//! the RPC layer, with the connection id as the source and a call id as the
+//! sequence; the key sent with every request, stream frame and cancel notice
+//! tells the server which client it came from.
+//!
+//! Calls can be given a deadline and headers with [`CallOptions`]. A call that
+//! misses its deadline fails with [`Error::Timeout`] and the client sends a
+//! cancel notice, which a running handler can observe with [`cancelled`].
+//! Handlers read the headers and set response trailers through their
+//! [`CallContext`]. Every call carries a W3C `traceparent` header, see
+//! [`trace`].
+
+pub mod balance;
+pub mod checksum;
+pub mod client;
+pub mod compression;
+pub mod context;
+mod frame;
+pub mod handshake;
+pub mod health;
+pub mod metadata;
+pub mod retry;
+pub mod server;
+pub mod stream;
+pub mod trace;
+
+pub use self::balance::{Balancer, BalancerOptions, Pick, Picked};
+pub use self::checksum::Checksum;
+pub use self::client::{Client, Reply};
+pub use self::compression::Compression;
+pub use self::context::{cancelled, CallContext};
+pub use self::handshake::{Hello, Negotiated};
+pub use self::health::{HealthService, ServingStatus};
+pub use self::metadata::Metadata;
+pub use self::retry::RetryPolicy;
+pub use self::server::Server;
+pub use self::stream::{
+    BidiStreaming, ClientStreaming, RequestStream, ResponseStream, Responses, StreamCall,
+    StreamHandler,
+};
+pub use self::trace::TraceContext;
+
+use crate::tag::{self, TagChannel};
+use crate::Error;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+pub(crate) const CONNECT_CHANNEL: TagChannel = tag::RPC_CONNECT;
+pub(crate) const REQUEST_CHANNEL: TagChannel = tag::RPC_REQUEST;
+pub(crate) const RESPONSE_CHANNEL: TagChannel = tag::RPC_RESPONSE;
+
+/// Options of a single call.
+#[derive(Debug, Clone, Default)]
+pub struct CallOptions {
+    /// When to give up on the call; `None` waits forever.
+    pub deadline: Option<Instant>,
+    /// Headers sent with the request.
+    pub metadata: Metadata,
+    /// The name of the called method, `"<service>/<method>"`, which labels
+    /// the metrics of the call; generated client stubs set it. Calls
+    /// without one are counted as `"unknown"`.
+    pub method: Option<&'static str>,
+}
+
+impl CallOptions {
+    /// Creates options without a deadline.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gives up on the call at `deadline`.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Gives up on the call `timeout` from now.
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    /// Names the called method, see [`CallOptions::method`].
+    pub fn with_method(mut self, method: &'static str) -> Self {
+        self.method = Some(method);
+        self
+    }
+
+    /// Sends the header `key` with the request.
+    pub fn with_header(mut self, key: impl AsRef<str>, value: impl Into<Vec<u8>>) -> Self {
+        self.metadata.insert(key, value);
+        self
+    }
+}
+
+/// Computes the id of `method` in `service`, the FNV-1a hash of `"<service>/<method>"`.
+///
+/// This is the id the [`service`](crate::service) attribute assigns.
+pub const fn method_id(service: &str, method: &str) -> u32 {
+    const fn fnv1a(mut hash: u32, bytes: &[u8]) -> u32 {
+        let mut i = 0;
+        while i < bytes.len() {
+            hash ^= bytes[i] as u32;
+            hash = hash.wrapping_mul(0x0100_0193);
+            i += 1;
+        }
+        hash
+    }
+    let hash = fnv1a(0x811c_9dc5, service.as_bytes());
+    let hash = fnv1a(hash, b"/");
+    fnv1a(hash, method.as_bytes())
+}
+
+/// A set of RPC methods served by a [`Server`].
+pub trait Service {
+    /// The name the method ids are derived from.
+    fn name(&self) -> &'static str;
+
+    /// The ids and names of the methods of the service.
+    fn methods(&self) -> &'static [(u32, &'static str)];
+
+    /// Runs the method `method_id` on an encoded request and returns the encoded response.
+    fn call(&self, method_id: u32, payload: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /// Starts the streaming method `method_id`; the [`Server`] drives the
+    /// returned handler until the call ends.
+    fn call_streaming(
+        self: Rc<Self>,
+        method_id: u32,
+        call: StreamCall,
+    ) -> Result<StreamHandler, Error> {
+        let _ = (method_id, call);
+        Err(Error::Unsupported)
+    }
+}
+
+#[doc(hidden)]
+pub mod __private {
+    use futures::stream::{self, Stream, StreamExt};
+    use std::future::Future;
+
+    /// Drives an `async` service method to completion on the calling thread.
+    ///
+    /// The thread sleeps until the future is woken, so a handler awaiting
+    /// something that another thread completes does not spin.
+    pub fn block_on<F: Future>(future: F) -> F::Output {
+        futures::executor::block_on(future)
+    }
+
+    /// The stream returned by the future of an `async` streaming method.
+    pub fn flatten<S: Stream>(future: impl Future<Output = S>) -> impl Stream<Item = S::Item> {
+        stream::once(future).flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[crate::service]
+    trait Greeter {
+        fn greet(&self, name: String) -> Result<String, Error>;
+    }
+
+    #[crate::service(name = "pkg.v1.Other")]
+    trait Other {
+        async fn ping(&self, x: ()) -> Result<(), Error>;
+    }
+
+    struct Impl;
+
+    impl Greeter for Impl {
+        fn greet(&self, name: String) -> Result<String, Error> {
+            Ok(name)
+        }
+    }
+
+    impl Other for Impl {
+        async fn ping(&self, _: ()) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn method_id_is_fnv1a_of_the_qualified_name() {
+        assert_eq!(method_id("", ""), 0x2a0c_975e);
+        assert_eq!(method_id("Greeter", "greet"), 0x9830_e773);
+        assert_eq!(method_id("pkg.Other", "ping"), 0x10bb_47de);
+    }
+
+    #[test]
+    fn service_attribute_assigns_method_id() {
+        assert_eq!(greeter_methods::GREET, method_id("Greeter", "greet"));
+        assert_eq!(other_methods::PING, method_id("pkg.v1.Other", "ping"));
+    }
+
+    #[test]
+    fn services_list_the_method_ids() {
+        let greeter = GreeterServer(Impl);
+        assert_eq!(greeter.name(), "Greeter");
+        assert_eq!(
+            greeter.methods(),
+            &[(method_id("Greeter", "greet"), "greet")]
+        );
+        let other = OtherServer(Impl);
+        assert_eq!(other.name(), "pkg.v1.Other");
+        assert_eq!(
+            other.methods(),
+            &[(method_id("pkg.v1.Other", "ping"), "ping")]
+        );
+    }
+}
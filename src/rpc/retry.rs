@@ -0,0 +1,59 @@
+//! Retrying calls over reconnecting clients.
+
+use super::*;
+use std::collections::HashSet;
+
+/// Decides which calls a reconnecting [`Client`] replays after a failure.
+///
+/// Only idempotent methods are replayed, as a call whose connection failed
+/// may or may not have run on the server. Calls of other methods fail with
+/// the error, and the next call reconnects.
+#[derive(Debug, Clone, Default)]
+pub struct RetryPolicy {
+    /// The number of times a call is made, including the first one.
+    ///
+    /// The default of 0 is the same as 1: calls are not replayed.
+    pub max_attempts: u32,
+    idempotent: HashSet<u32>,
+}
+
+impl RetryPolicy {
+    /// A policy making calls of idempotent methods up to `max_attempts` times.
+    pub fn new(max_attempts: u32) -> Self {
+        RetryPolicy {
+            max_attempts,
+            idempotent: HashSet::new(),
+        }
+    }
+
+    /// Marks `method_id` as idempotent, so its calls are replayed.
+    pub fn idempotent(mut self, method_id: u32) -> Self {
+        self.idempotent.insert(method_id);
+        self
+    }
+
+    /// Returns true if `method_id` was marked idempotent.
+    pub fn is_idempotent(&self, method_id: u32) -> bool {
+        self.idempotent.contains(&method_id)
+    }
+
+    /// Returns true if a call of `method_id` that failed with `error` on
+    /// attempt number `attempt`, counting from 0, is replayed.
+    pub fn should_retry(&self, method_id: u32, error: &Error, attempt: u32) -> bool {
+        attempt + 1 < self.max_attempts
+            && self.is_idempotent(method_id)
+            && is_connection_error(error)
+    }
+}
+
+// Errors after which the connection is gone, unlike those of the call itself.
+pub(crate) fn is_connection_error(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::ConnectionReset
+            | Error::NotConnected
+            | Error::Unreachable
+            | Error::EndpointTimeout
+            | Error::IoError
+    )
+}
@@ -0,0 +1,705 @@
+//! Server side of RPC connections.
+
+use super::checksum::Checksum;
+use super::compression::{self, Compression, Policy};
+use super::context::CallState;
+use super::frame::{Accepted, Encoding, Frame, FrameKind, FLAG_STREAMING};
+use super::handshake::{self, Grant, Hello, Negotiated};
+use super::stream::{Channel, StreamCall, StreamHandler};
+use super::trace::{self, TraceContext};
+use super::*;
+use crate::auth::{Authenticator, Exchange};
+use crate::keepalive::{self, Keepalive, KeepaliveOptions};
+use crate::metrics;
+use crate::ucp::endpoint::Endpoint;
+use crate::ucp::listener::{ConnectionRequest, Listener};
+use crate::ucp::{TagMessage, Worker};
+use derivative::Derivative;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn, Span};
+
+/// How long a client has to authenticate, see [`Server::set_authenticator`].
+pub const AUTH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Accepts RPC connections and dispatches their requests to [`Service`]s.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct Server {
+    worker: Rc<Worker>,
+    #[derivative(Debug = "ignore")]
+    services: RefCell<HashMap<u32, Rc<dyn Service>>>,
+    connections: RefCell<HashMap<u32, Connection>>,
+    #[derivative(Debug = "ignore")]
+    pending: RefCell<Vec<ConnectionRequest>>,
+    #[derivative(Debug = "ignore")]
+    authenticating: RefCell<Vec<Authenticating>>,
+    #[derivative(Debug = "ignore")]
+    calls: RefCell<Vec<StreamingCall>>,
+    next_conn_id: Cell<u32>,
+    require_handshake: Cell<bool>,
+    compression_threshold: Cell<usize>,
+    checksum: Cell<Option<Checksum>>,
+    keepalive: Cell<Option<KeepaliveOptions>>,
+    #[derivative(Debug = "ignore")]
+    authenticator: RefCell<Option<Rc<dyn Authenticator>>>,
+}
+
+#[derive(Debug, Clone)]
+struct Connection {
+    ep: Rc<Endpoint>,
+    peer_addr: Option<SocketAddr>,
+    // Sent by the client with every call, see `handshake`.
+    key: u64,
+    // Set once the client completed the handshake.
+    handshake: Option<(Hello, Negotiated)>,
+    keepalive: Option<Rc<Keepalive>>,
+}
+
+// A connection whose client has not authenticated yet. It is kept out of
+// `connections`, so its calls and hellos are not answered.
+struct Authenticating {
+    conn_id: u32,
+    ep: Rc<Endpoint>,
+    peer_addr: Option<SocketAddr>,
+    exchange: Box<dyn Exchange>,
+    deadline: Instant,
+}
+
+// A streaming call whose handler has not finished.
+struct StreamingCall {
+    request_tag: u64,
+    channel: Rc<Channel>,
+    handler: StreamHandler,
+    ctx: Rc<CallContext>,
+    method: String,
+    span: Span,
+    trace: TraceContext,
+    started: Instant,
+}
+
+impl Server {
+    /// Creates a server without services, driven by `worker`.
+    pub fn new(worker: &Rc<Worker>) -> Rc<Self> {
+        Rc::new(Server {
+            worker: worker.clone(),
+            services: RefCell::new(HashMap::new()),
+            connections: RefCell::new(HashMap::new()),
+            pending: RefCell::new(Vec::new()),
+            authenticating: RefCell::new(Vec::new()),
+            calls: RefCell::new(Vec::new()),
+            next_conn_id: Cell::new(1),
+            require_handshake: Cell::new(false),
+            compression_threshold: Cell::new(compression::DEFAULT_THRESHOLD),
+            checksum: Cell::new(None),
+            keepalive: Cell::new(None),
+            authenticator: RefCell::new(None),
+        })
+    }
+
+    /// Registers `service`, failing if one of its method ids is already taken.
+    pub fn add_service<S: Service + 'static>(&self, service: S) -> Result<(), Error> {
+        let service: Rc<dyn Service> = Rc::new(service);
+        let mut services = self.services.borrow_mut();
+        for (id, method) in service.methods() {
+            if let Some(other) = services.get(id) {
+                warn!(
+                    "method {}/{} collides with a method of {} (id {:#x})",
+                    service.name(),
+                    method,
+                    other.name(),
+                    id
+                );
+                return Err(Error::AlreadyExists);
+            }
+        }
+        for (id, _) in service.methods() {
+            services.insert(*id, service.clone());
+        }
+        Ok(())
+    }
+
+    /// Sets whether clients must complete the [handshake](super::handshake) before calling.
+    ///
+    /// Calls on connections without a handshake then fail. The server answers
+    /// hellos either way.
+    pub fn require_handshake(&self, required: bool) {
+        self.require_handshake.set(required);
+    }
+
+    /// Sets the size from which the payloads the server sends are compressed,
+    /// on connections whose handshake negotiated a compression algorithm.
+    pub fn set_compression_threshold(&self, threshold: usize) {
+        self.compression_threshold.set(threshold);
+    }
+
+    /// Sets the checksum appended to the frames the server sends, see
+    /// [`checksum`](super::checksum). `None` sends no checksum.
+    ///
+    /// Clients that complete the handshake afterwards must support it, and
+    /// their frames are rejected unless they carry it.
+    pub fn set_checksum(&self, checksum: Option<Checksum>) {
+        self.checksum.set(checksum);
+    }
+
+    /// Runs a [keepalive](crate::keepalive) on the connections accepted
+    /// afterwards, answering the pings of clients that enabled theirs with
+    /// [`Client::set_keepalive`] and closing their connection once they go
+    /// silent. `None` runs none.
+    ///
+    /// The keepalives are polled by [`Server::progress`], so handlers that
+    /// block for longer than the tolerated silence make clients give up on
+    /// the server.
+    pub fn set_keepalive(&self, options: Option<KeepaliveOptions>) {
+        self.keepalive.set(options);
+    }
+
+    /// The hello of the client of `conn_id` and what was negotiated with it,
+    /// if it completed the handshake.
+    pub fn handshake(&self, conn_id: u32) -> Option<(Hello, Negotiated)> {
+        self.connections
+            .borrow()
+            .get(&conn_id)
+            .and_then(|conn| conn.handshake.clone())
+    }
+
+    /// Authenticates the clients of the connections accepted afterwards with
+    /// `authenticator`, closing the connections of those that fail before
+    /// they can call anything.
+    ///
+    /// Clients create their endpoint with [`auth::connect`](crate::auth::connect).
+    /// The exchanges are advanced by [`Server::progress`] alongside the
+    /// calls of other clients, and fail after [`AUTH_TIMEOUT`].
+    pub fn set_authenticator(&self, authenticator: impl Authenticator + 'static) {
+        *self.authenticator.borrow_mut() = Some(Rc::new(authenticator));
+    }
+
+    /// Listens for connections on `addr`.
+    ///
+    /// Connection requests are accepted by [`Server::progress`]. The server
+    /// stops listening when the returned listener is dropped.
+    pub fn listen(self: &Rc<Self>, addr: SocketAddr) -> Result<Rc<Listener<Rc<Server>>>, Error> {
+        unsafe { Listener::create(&self.worker, addr, Self::on_conn_request, self.clone()) }
+    }
+
+    // Called from inside `Worker::progress`, so the request is only queued here.
+    unsafe fn on_conn_request(conn_req: ConnectionRequest, _: Rc<Worker>, server: Rc<Server>) {
+        server.pending.borrow_mut().push(conn_req);
+    }
+
+    /// Accepts `conn_req` as an RPC connection and returns its connection id.
+    ///
+    /// If the server has an authenticator, the connection serves calls only
+    /// once its client has authenticated, see [`Server::set_authenticator`].
+    pub fn accept(&self, conn_req: ConnectionRequest) -> Result<u32, Error> {
+        let peer_addr = conn_req.client_addr().ok();
+        let ep = unsafe { Endpoint::from_conn_req(self.worker.clone(), conn_req)? };
+        self.admit(Rc::new(ep), peer_addr)
+    }
+
+    /// Serves the client at the other end of `ep` and returns its connection id.
+    ///
+    /// This is how clients that connect with a worker address are accepted:
+    /// the server creates `ep` from the worker address of the client, which
+    /// it receives out of band, while the client creates its endpoint from
+    /// the worker address of the server and calls [`Client::connect`].
+    ///
+    /// The client of `ep` is authenticated first if the server has an
+    /// authenticator, with the server taking the accepting side.
+    pub fn accept_endpoint(&self, ep: Rc<Endpoint>) -> Result<u32, Error> {
+        self.admit(ep, None)
+    }
+
+    // Adds the connection of `ep`, or starts authenticating its client.
+    fn admit(&self, ep: Rc<Endpoint>, peer_addr: Option<SocketAddr>) -> Result<u32, Error> {
+        let conn_id = self.next_conn_id.get();
+        self.next_conn_id
+            .set(match (conn_id + 1) & tag::SOURCE.max() as u32 {
+                0 => 1,
+                next => next,
+            });
+        let authenticator = self.authenticator.borrow().clone();
+        match authenticator {
+            Some(authenticator) => {
+                let deadline = Instant::now() + AUTH_TIMEOUT;
+                let exchange = authenticator.start(&ep, Some(deadline))?;
+                debug!(conn_id, ?peer_addr, "authenticating rpc client");
+                self.authenticating.borrow_mut().push(Authenticating {
+                    conn_id,
+                    ep,
+                    peer_addr,
+                    exchange,
+                    deadline,
+                });
+            }
+            None => self.add_connection(conn_id, ep, peer_addr)?,
+        }
+        Ok(conn_id)
+    }
+
+    // Advances the exchanges of the clients authenticating, adding the
+    // connections of those that succeed and closing the others.
+    fn authenticate(&self) -> usize {
+        let authenticating = std::mem::take(&mut *self.authenticating.borrow_mut());
+        let mut events = 0;
+        for mut conn in authenticating {
+            let result = match conn.exchange.poll(&conn.ep) {
+                Poll::Ready(result) => result,
+                Poll::Pending if Instant::now() < conn.deadline => {
+                    self.authenticating.borrow_mut().push(conn);
+                    continue;
+                }
+                Poll::Pending => Err(Error::Timeout),
+            };
+            events += 1;
+            let added = result
+                .and_then(|()| self.add_connection(conn.conn_id, conn.ep.clone(), conn.peer_addr));
+            if let Err(e) = added {
+                warn!(
+                    conn_id = conn.conn_id,
+                    peer_addr = ?conn.peer_addr,
+                    "closing unauthenticated rpc connection: {e}"
+                );
+                conn.ep.force_close();
+            }
+        }
+        events
+    }
+
+    fn add_connection(
+        &self,
+        conn_id: u32,
+        ep: Rc<Endpoint>,
+        peer_addr: Option<SocketAddr>,
+    ) -> Result<(), Error> {
+        // The client's inbox arrives with its first ping.
+        let keepalive = self.keepalive.get().map(|options| {
+            Rc::new(Keepalive::new(
+                ep.clone(),
+                options,
+                keepalive::next_inbox(),
+                None,
+            ))
+        });
+        let grant = Grant::new(conn_id, keepalive.as_ref().map_or(0, |k| k.inbox()))?;
+        grant.send(&ep, None)?;
+        self.connections.borrow_mut().insert(
+            conn_id,
+            Connection {
+                ep,
+                peer_addr,
+                key: grant.key,
+                handshake: None,
+                keepalive,
+            },
+        );
+        info!(conn_id, ?peer_addr, "rpc connection accepted");
+        Ok(())
+    }
+
+    /// Makes progress on the worker, accepts pending connections, advances
+    /// the authentication of new clients, handles
+    /// the requests that have arrived and drives the streaming calls in
+    /// progress.
+    ///
+    /// Returns the number of events processed.
+    pub fn progress(&self) -> usize {
+        let mut events = self.worker.progress() as usize;
+
+        let pending = std::mem::take(&mut *self.pending.borrow_mut());
+        for conn_req in pending {
+            events += 1;
+            if let Err(e) = self.accept(conn_req) {
+                warn!("failed to accept rpc connection: {e}");
+            }
+        }
+        events += self.authenticate();
+        let keepalives: Vec<_> = self
+            .connections
+            .borrow()
+            .iter()
+            .filter_map(|(&conn_id, conn)| Some((conn_id, conn.keepalive.clone()?)))
+            .collect();
+        for (conn_id, keepalive) in keepalives {
+            if let Err(e) = keepalive.service() {
+                debug!(conn_id, "rpc connection failed: {e}");
+            }
+        }
+        self.connections.borrow_mut().retain(|conn_id, conn| {
+            let closed = *conn.ep.closed.borrow();
+            if closed {
+                info!(conn_id, "rpc connection closed");
+            }
+            !closed
+        });
+
+        let hello_mask = CONNECT_CHANNEL.mask() | tag::SEQUENCE.mask();
+        while let Some(message) = self
+            .worker
+            .tag_probe(CONNECT_CHANNEL.tag(0, handshake::HELLO), hello_mask)
+        {
+            events += 1;
+            if let Err(e) = self.answer_hello(message) {
+                warn!("failed to answer rpc handshake: {e}");
+            }
+        }
+
+        while let Some(message) = self
+            .worker
+            .tag_probe(REQUEST_CHANNEL.tag(0, 0), REQUEST_CHANNEL.mask())
+        {
+            events += 1;
+            if let Err(e) = self.handle(message) {
+                warn!("failed to handle rpc request: {e}");
+            }
+        }
+        events + self.drive_calls()
+    }
+
+    // Polls the handlers of the streaming calls in progress and ends the
+    // calls whose handler has finished.
+    fn drive_calls(&self) -> usize {
+        // Taken out while the handlers run, which may use the server.
+        let mut calls = std::mem::take(&mut *self.calls.borrow_mut());
+        let before = calls.len();
+        calls.retain_mut(|call| {
+            let result = {
+                let _span = call.span.enter();
+                let _trace = call.trace.enter();
+                let _guard = call.ctx.enter();
+                match call.handler.drive(&call.channel) {
+                    Poll::Pending => return true,
+                    Poll::Ready(result) => result,
+                }
+            };
+            self.end_call(call, result);
+            false
+        });
+        let events = before - calls.len();
+        self.calls.borrow_mut().append(&mut calls);
+        events
+    }
+
+    fn end_call(&self, call: &StreamingCall, result: Result<Vec<u8>, Error>) {
+        metrics::rpc_completed(
+            "server",
+            &call.method,
+            call.started.elapsed(),
+            result.is_ok(),
+        );
+        trace::record_outcome(&call.span, result.as_ref().map(Vec::len));
+        if let Err(e) = call.channel.end(&result, call.ctx.take_trailers()) {
+            warn!(method = call.method, "failed to end rpc stream: {e}");
+        }
+    }
+
+    /// Calls [`Server::progress`] until `stop` returns true.
+    pub fn run_until(&self, stop: impl Fn() -> bool) {
+        while !stop() {
+            self.progress();
+        }
+    }
+
+    // Accepts or rejects the connection that sent the hello; rejected
+    // connections are closed once the answer is sent.
+    fn answer_hello(&self, message: TagMessage) -> Result<(), Error> {
+        let (conn_id, _) = TagChannel::split(message.sender_tag);
+        let bytes = self.worker.recv_probed(message)?;
+        let ep = self
+            .connections
+            .borrow()
+            .get(&conn_id)
+            .map(|conn| conn.ep.clone())
+            .ok_or(Error::NotConnected)?;
+        let answer_tag = CONNECT_CHANNEL.tag(conn_id, handshake::ANSWER);
+        let answer = match Hello::decode(&bytes) {
+            // Sent by another client with the connection id in the tag; the
+            // answer would go to the client that owns it.
+            Ok((_, key)) if key != self.conn_key(conn_id) => {
+                return Err(Error::Authentication(format!(
+                    "hello without the key of connection {conn_id}"
+                )));
+            }
+            Ok((hello, _)) => {
+                handshake::negotiate(&hello, |name| self.has_service(name), self.checksum.get())
+                    .map(|negotiated| (hello, negotiated))
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        match answer {
+            Ok((hello, negotiated)) => {
+                ep.send_bytes(answer_tag, &handshake::encode_answer(Ok(&negotiated))?)?;
+                info!(
+                    conn_id,
+                    identity = hello.identity,
+                    service = hello.service,
+                    version = negotiated.version,
+                    "rpc handshake completed"
+                );
+                if let Some(conn) = self.connections.borrow_mut().get_mut(&conn_id) {
+                    conn.handshake = Some((hello, negotiated));
+                }
+                Ok(())
+            }
+            Err(reason) => {
+                warn!(conn_id, "rejecting rpc connection: {reason}");
+                let result = ep.send_bytes(answer_tag, &handshake::encode_answer(Err(&reason))?);
+                self.connections.borrow_mut().remove(&conn_id);
+                result
+            }
+        }
+    }
+
+    fn conn_key(&self, conn_id: u32) -> Option<u64> {
+        self.connections.borrow().get(&conn_id).map(|conn| conn.key)
+    }
+
+    fn has_service(&self, name: &str) -> bool {
+        self.services
+            .borrow()
+            .values()
+            .any(|service| service.name() == name)
+    }
+
+    fn handle(&self, message: TagMessage) -> Result<(), Error> {
+        let request_tag = message.sender_tag;
+        let (conn_id, call_id) = TagChannel::split(request_tag);
+        let bytes = self.worker.recv_probed(message)?;
+        let conn = self.connections.borrow().get(&conn_id).cloned();
+        let channel = self
+            .calls
+            .borrow()
+            .iter()
+            .find(|call| call.request_tag == request_tag)
+            .map(|call| call.channel.clone());
+        if let Some(channel) = channel {
+            // Like requests, the frames of the client carry the key of its connection.
+            let key = Frame::decode_header(&bytes)
+                .ok()
+                .and_then(|frame| handshake::conn_key(&frame.metadata));
+            if key.is_none() || key != conn.as_ref().map(|conn| conn.key) {
+                warn!(
+                    conn_id,
+                    call_id, "dropping stream frame without the key of its connection"
+                );
+                return Ok(());
+            }
+            return channel.deliver(&bytes);
+        }
+        let response_tag = RESPONSE_CHANNEL.tag(conn_id, call_id);
+        let Some(conn) = conn else {
+            return self.reject_misrouted(&bytes, response_tag, "unknown connection id");
+        };
+        conn.ep.metrics().bytes_received.add(bytes.len() as u64);
+
+        let frame = match Frame::decode(&bytes, request_tag, &conn.ep, accepted(&conn)) {
+            // Stream frames that arrive after their call has ended.
+            Ok(frame) if frame.kind != FrameKind::Request => {
+                let kind = frame.kind;
+                debug!(conn_id, call_id, ?kind, "dropping frame of a finished call");
+                return Ok(());
+            }
+            Ok(frame) => frame,
+            Err(e) => {
+                let error = e.to_string();
+                let response = Frame::new(FrameKind::Error, 0, error.as_bytes())
+                    .with_encoding(self.encoding(&conn))
+                    .encode()?;
+                return conn.ep.send_bytes(response_tag, &response);
+            }
+        };
+        if handshake::conn_key(&frame.metadata) != Some(conn.key) {
+            return self.reject_misrouted(&bytes, response_tag, "connection id of another client");
+        }
+        let method_id = frame.method_id;
+        let encoding = self.encoding(&conn);
+        if self.require_handshake.get() && conn.handshake.is_none() {
+            let response = Frame::new(FrameKind::Error, method_id, b"handshake required")
+                .with_encoding(encoding);
+            return conn.ep.send_bytes(response_tag, &response.encode()?);
+        }
+
+        let (payload, channel) = if frame.flags & FLAG_STREAMING != 0 {
+            let Some((window, initial)) = frame.payload.split_first_chunk::<4>() else {
+                let response =
+                    Frame::new(FrameKind::Error, method_id, b"truncated streaming request")
+                        .with_encoding(encoding);
+                return conn.ep.send_bytes(response_tag, &response.encode()?);
+            };
+            let channel = Channel::new(
+                conn.ep.clone(),
+                method_id,
+                response_tag,
+                request_tag,
+                u32::from_le_bytes(*window),
+                true,
+            );
+            channel.set_encoding(encoding);
+            channel.set_accepted(accepted(&conn));
+            (initial, Some(channel))
+        } else {
+            (&frame.payload[..], None)
+        };
+        let state = match &channel {
+            Some(channel) => CallState::Streaming(channel.clone()),
+            None => CallState::Unary {
+                worker: self.worker.clone(),
+                request_tag,
+                conn_key: conn.key,
+                cancelled: Cell::new(false),
+            },
+        };
+        let ctx = CallContext::new(
+            method_id,
+            (conn_id, call_id),
+            conn.peer_addr,
+            conn.ep.clone(),
+            frame.metadata,
+            state,
+        );
+
+        let parent = TraceContext::extract(ctx.headers());
+        let trace = parent.map_or_else(TraceContext::new_root, |parent| parent.child());
+        let name = self.method_name(method_id);
+        let span_name = name.clone().unwrap_or_else(|| format!("{method_id:#010x}"));
+        let span = trace::server_span(&span_name, conn.peer_addr, &trace, parent, payload.len());
+        // Unknown ids share a label, so that clients cannot create metric series at will.
+        let method = name.unwrap_or_else(|| metrics::UNKNOWN_METHOD.to_string());
+
+        let started = Instant::now();
+        if let Some(channel) = channel {
+            let handler = {
+                let _span = span.enter();
+                let _trace = trace.enter();
+                let _guard = ctx.enter();
+                let call = StreamCall::new(channel.clone(), payload.to_vec());
+                self.service(method_id)
+                    .and_then(|service| service.call_streaming(method_id, call))
+            };
+            // A handler that failed to start ends the call once it is driven.
+            let call = StreamingCall {
+                request_tag,
+                channel,
+                handler: handler.unwrap_or_else(StreamHandler::failed),
+                ctx,
+                method,
+                span,
+                trace,
+                started,
+            };
+            self.calls.borrow_mut().push(call);
+            return Ok(());
+        }
+
+        let result = {
+            let _span = span.enter();
+            let _trace = trace.enter();
+            let _guard = ctx.enter();
+            // The client may have given up while the request was queued. It
+            // still gets a response, which it discards.
+            if ctx.is_cancelled() {
+                debug!(conn_id, call_id, "skipping cancelled rpc call");
+                Err(Error::Canceled)
+            } else {
+                self.service(method_id)
+                    .and_then(|service| service.call(method_id, payload))
+            }
+        };
+        metrics::rpc_completed("server", &method, started.elapsed(), result.is_ok());
+        trace::record_outcome(&span, result.as_ref().map(Vec::len));
+
+        let error;
+        let response = match &result {
+            Ok(payload) => Frame::new(FrameKind::Response, method_id, payload),
+            Err(e) => {
+                error = e.to_string();
+                Frame::new(FrameKind::Error, method_id, error.as_bytes())
+            }
+        };
+        let response = response
+            .with_metadata(ctx.take_trailers())
+            .with_encoding(encoding)
+            .encode()?;
+        conn.ep.send_bytes(response_tag, &response)
+    }
+
+    // Answers a request whose tag names another connection than its key, or
+    // none, on the connection of the key, whose client sent it. Requests
+    // without the key of a connection cannot be answered and are dropped.
+    fn reject_misrouted(&self, bytes: &[u8], response_tag: u64, reason: &str) -> Result<(), Error> {
+        let frame = match Frame::decode_header(bytes) {
+            Ok(frame) => frame,
+            Err(e) => {
+                warn!("dropping rpc request: {reason}, and {e}");
+                return Ok(());
+            }
+        };
+        let key = handshake::conn_key(&frame.metadata);
+        let sender = self
+            .connections
+            .borrow()
+            .values()
+            .find(|conn| Some(conn.key) == key)
+            .cloned();
+        let Some(sender) = sender else {
+            warn!("dropping rpc request: {reason}, and no connection has its key");
+            return Ok(());
+        };
+        warn!(peer_addr = ?sender.peer_addr, "rejecting rpc request: {reason}");
+        let response = Frame::new(FrameKind::Error, frame.method_id, reason.as_bytes())
+            .with_encoding(self.encoding(&sender));
+        sender.ep.send_bytes(response_tag, &response.encode()?)
+    }
+
+    // How the frames sent on `conn` are encoded, errors included, so that
+    // clients that require a checksum can read them.
+    fn encoding(&self, conn: &Connection) -> Encoding {
+        Encoding {
+            compression: conn.handshake.as_ref().and_then(|(_, negotiated)| {
+                Policy::negotiated(
+                    negotiated.compression.as_deref(),
+                    self.compression_threshold.get(),
+                )
+            }),
+            checksum: required_checksum(conn).or(self.checksum.get()),
+        }
+    }
+
+    // The name of the method `method_id`, if a service has it.
+    fn method_name(&self, method_id: u32) -> Option<String> {
+        let services = self.services.borrow();
+        let service = services.get(&method_id)?;
+        let (_, method) = service.methods().iter().find(|(id, _)| *id == method_id)?;
+        Some(format!("{}/{}", service.name(), method))
+    }
+
+    fn service(&self, method_id: u32) -> Result<Rc<dyn Service>, Error> {
+        self.services
+            .borrow()
+            .get(&method_id)
+            .cloned()
+            .ok_or(Error::Unsupported)
+    }
+}
+
+// The checksum negotiated for `conn`, which its frames must carry.
+fn required_checksum(conn: &Connection) -> Option<Checksum> {
+    conn.handshake
+        .as_ref()
+        .and_then(|(_, negotiated)| negotiated.checksum_algorithm())
+}
+
+// What the frames of `conn` must be encoded with.
+fn accepted(conn: &Connection) -> Accepted {
+    Accepted {
+        checksum: required_checksum(conn),
+        compression: conn
+            .handshake
+            .as_ref()
+            .and_then(|(_, negotiated)| Compression::from_name(negotiated.compression.as_deref()?)),
+    }
+}
@@ -0,0 +1,742 @@
+//! Streaming calls.
+//!
+//! A streaming call exchanges stream item frames on the tags of the call.
+//! Each side may have at most `window` items in flight: the receiver returns
+//! credits as it consumes items, and a client that runs out of credits blocks
+//! while making progress on the worker. The client ends its half of the call
+//! with an end-of-stream frame; the server ends the call with a response
+//! frame carrying the result of the handler, which doubles as the error
+//! trailer. Dropping a stream handle before the end, or missing the deadline
+//! of the call, sends a cancel notice to the peer.
+//!
+//! Handlers do not block the server: they are built on [`futures::Stream`],
+//! returning the [`Responses`] they stream or reading a [`RequestStream`],
+//! and the [`Server`] keeps the [`StreamHandler`] of every call in progress,
+//! polling each of them on every pass of [`Server::progress`] and sending at
+//! most [`ITEMS_PER_PASS`] items per call and pass. Like everything else
+//! driven by the worker, they are polled rather than woken, so the streams
+//! of this module register no wakers and only make progress when polled by
+//! the server.
+
+use super::frame::{Accepted, Encoding, Frame, FrameKind};
+use super::*;
+use crate::codec::{Codec, DefaultCodec};
+use crate::flow::Credits;
+use crate::keepalive::{self, Keepalive};
+use crate::ucp::endpoint::Endpoint;
+use futures::stream::{self, LocalBoxStream, Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{self, Poll};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Default number of stream items that may be in flight in each direction.
+pub const DEFAULT_WINDOW: u32 = 16;
+
+/// The most items a streaming call sends per pass of [`Server::progress`],
+/// so that the calls in progress take turns.
+pub const ITEMS_PER_PASS: usize = 16;
+
+// How long a dropped stream handle waits for the peer to end the call,
+// discarding the frames it sent before getting the cancel notice.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// One side of a streaming call.
+#[derive(Debug)]
+pub(crate) struct Channel {
+    ep: Rc<Endpoint>,
+    method_id: u32,
+    send_tag: u64,
+    recv_tag: u64,
+    // A cancel notice from the client aborts the whole call, while one from
+    // the server only means that it stopped reading requests.
+    is_server: bool,
+    encoding: Cell<Encoding>,
+    // Negotiated for the connection, see `Frame::decode`.
+    accepted: Cell<Accepted>,
+    // Sent with every frame on the client side, so that the server can tell
+    // them from frames that other peers send with the tag of the call.
+    conn_key: Cell<Option<u64>>,
+    credits: Credits,
+    incoming: RefCell<VecDeque<(FrameKind, Vec<u8>)>>,
+    peer_cancelled: Cell<bool>,
+    cancelled: Cell<bool>,
+    finished: Cell<bool>,
+    deadline: Cell<Option<Instant>>,
+    trailers: RefCell<Metadata>,
+    // Polled while waiting for the peer, on the client side.
+    keepalive: RefCell<Option<Rc<Keepalive>>>,
+}
+
+impl Channel {
+    pub(crate) fn new(
+        ep: Rc<Endpoint>,
+        method_id: u32,
+        send_tag: u64,
+        recv_tag: u64,
+        window: u32,
+        is_server: bool,
+    ) -> Rc<Self> {
+        let window = window.max(1);
+        Rc::new(Channel {
+            ep,
+            method_id,
+            send_tag,
+            recv_tag,
+            is_server,
+            encoding: Cell::new(Encoding::default()),
+            accepted: Cell::new(Accepted::default()),
+            conn_key: Cell::new(None),
+            credits: Credits::new(window, window),
+            incoming: RefCell::new(VecDeque::new()),
+            peer_cancelled: Cell::new(false),
+            cancelled: Cell::new(false),
+            finished: Cell::new(false),
+            deadline: Cell::new(None),
+            trailers: RefCell::new(Metadata::new()),
+            keepalive: RefCell::new(None),
+        })
+    }
+
+    pub(crate) fn set_deadline(&self, deadline: Option<Instant>) {
+        self.deadline.set(deadline);
+    }
+
+    pub(crate) fn set_encoding(&self, encoding: Encoding) {
+        self.encoding.set(encoding);
+    }
+
+    pub(crate) fn set_accepted(&self, accepted: Accepted) {
+        self.accepted.set(accepted);
+    }
+
+    pub(crate) fn set_conn_key(&self, key: u64) {
+        self.conn_key.set(Some(key));
+    }
+
+    pub(crate) fn set_keepalive(&self, keepalive: Option<Rc<Keepalive>>) {
+        *self.keepalive.borrow_mut() = keepalive;
+    }
+
+    pub(crate) fn peer_cancelled(&self) -> bool {
+        self.peer_cancelled.get()
+    }
+
+    // Applies the frames that have already arrived before checking.
+    pub(crate) fn poll_cancelled(&self) -> bool {
+        if let Err(e) = self.poll() {
+            debug!("failed to poll stream: {e}");
+        }
+        self.peer_cancelled.get()
+    }
+
+    pub(crate) fn send_frame(&self, kind: FrameKind, payload: &[u8]) -> Result<(), Error> {
+        self.send_frame_with(kind, payload, Metadata::new())
+    }
+
+    fn send_frame_with(
+        &self,
+        kind: FrameKind,
+        payload: &[u8],
+        metadata: Metadata,
+    ) -> Result<(), Error> {
+        let frame = Frame::new(kind, self.method_id, payload)
+            .with_metadata(self.with_conn_key(metadata))
+            .with_encoding(self.encoding.get())
+            .encode()?;
+        self.ep
+            .send_bytes_until(self.send_tag, &frame, self.deadline.get())
+            .map_err(|e| self.on_error(e))
+    }
+
+    fn with_conn_key(&self, mut metadata: Metadata) -> Metadata {
+        if let Some(key) = self.conn_key.get() {
+            metadata.insert(handshake::CONN_KEY_HEADER, key.to_le_bytes());
+        }
+        metadata
+    }
+
+    // Gives up on the call once its deadline has passed.
+    fn on_error(&self, e: Error) -> Error {
+        if e == Error::Timeout {
+            debug!(method_id = self.method_id, "stream timed out");
+            self.finished.set(true);
+            self.cancel();
+        }
+        e
+    }
+
+    fn send_item(&self, payload: &[u8]) -> Result<(), Error> {
+        self.poll()?;
+        while !self.closed_for_send() {
+            if self.credits.try_acquire() {
+                return self.send_frame(FrameKind::StreamItem, payload);
+            }
+            self.pump()?;
+        }
+        Err(Error::Canceled)
+    }
+
+    fn closed_for_send(&self) -> bool {
+        self.peer_cancelled.get() || self.cancelled.get() || self.finished.get()
+    }
+
+    // Credits and cancel notices are applied right away, other frames are
+    // queued for `recv_frame`.
+    fn apply(&self, bytes: &[u8]) -> Result<(), Error> {
+        let frame = Frame::decode(bytes, self.recv_tag, &self.ep, self.accepted.get())?;
+        match frame.kind {
+            FrameKind::Credit => {
+                let credits = frame
+                    .payload
+                    .get(..4)
+                    .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+                    .ok_or_else(|| Error::Codec("truncated credit frame".to_string()))?;
+                self.credits.add(credits);
+            }
+            FrameKind::Cancel => {
+                debug!(method_id = self.method_id, "stream cancelled by peer");
+                self.peer_cancelled.set(true);
+            }
+            kind => {
+                if matches!(kind, FrameKind::Response | FrameKind::Error) {
+                    self.finished.set(true);
+                    *self.trailers.borrow_mut() = frame.metadata;
+                }
+                self.incoming
+                    .borrow_mut()
+                    .push_back((kind, frame.payload.to_vec()));
+            }
+        }
+        Ok(())
+    }
+
+    // Applies a frame of the call that the server received while looking for requests.
+    pub(crate) fn deliver(&self, bytes: &[u8]) -> Result<(), Error> {
+        self.ep.metrics().bytes_received.add(bytes.len() as u64);
+        self.apply(bytes)
+    }
+
+    // Blocks until the next frame from the peer arrives.
+    fn pump(&self) -> Result<(), Error> {
+        self.pump_until(self.deadline.get())
+    }
+
+    fn pump_until(&self, deadline: Option<Instant>) -> Result<(), Error> {
+        let keepalive = self.keepalive.borrow().clone();
+        let bytes = keepalive::probe_until(keepalive.as_deref(), &self.ep, self.recv_tag, deadline)
+            .and_then(|message| self.ep.worker.recv_probed_until(message, deadline))
+            .map_err(|e| self.on_error(e))?;
+        self.deliver(&bytes)
+    }
+
+    // Applies the frames that have already arrived.
+    fn poll(&self) -> Result<(), Error> {
+        self.ep.worker.progress();
+        while let Some(message) = self.ep.worker.tag_probe(self.recv_tag, u64::MAX) {
+            let bytes = self.ep.worker.recv_probed(message)?;
+            self.deliver(&bytes)?;
+        }
+        Ok(())
+    }
+
+    // Applies the frames that have arrived and fails once the call cannot go on.
+    fn check(&self) -> Result<(), Error> {
+        self.poll()?;
+        if *self.ep.closed.borrow() {
+            return Err(Error::ConnectionReset);
+        }
+        if self.peer_cancelled.get() {
+            return Err(Error::Canceled);
+        }
+        if self
+            .deadline
+            .get()
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Err(self.on_error(Error::Timeout));
+        }
+        Ok(())
+    }
+
+    fn recv_frame(&self) -> Result<(FrameKind, Vec<u8>), Error> {
+        loop {
+            if let Some(frame) = self.try_recv_frame()? {
+                return Ok(frame);
+            }
+            self.pump()?;
+        }
+    }
+
+    // Takes the next frame that has arrived, if any.
+    fn try_recv_frame(&self) -> Result<Option<(FrameKind, Vec<u8>)>, Error> {
+        if self.incoming.borrow().is_empty() {
+            self.poll()?;
+        }
+        let next = self.incoming.borrow_mut().pop_front();
+        match next {
+            Some((kind, payload)) => {
+                if kind == FrameKind::StreamItem {
+                    self.grant()?;
+                }
+                Ok(Some((kind, payload)))
+            }
+            None if self.is_server && self.peer_cancelled.get() => Err(Error::Canceled),
+            None => Ok(None),
+        }
+    }
+
+    // Returns credits to the peer once half of the window has been consumed.
+    fn grant(&self) -> Result<(), Error> {
+        match self.credits.consume() {
+            Some(credits) => self.send_frame(FrameKind::Credit, &credits.to_le_bytes()),
+            None => Ok(()),
+        }
+    }
+
+    fn cancel(&self) {
+        if self.cancelled.replace(true) {
+            return;
+        }
+        let frame = Frame::new(FrameKind::Cancel, self.method_id, &[])
+            .with_metadata(self.with_conn_key(Metadata::new()))
+            .with_encoding(self.encoding.get())
+            .encode();
+        if let Err(e) = frame.and_then(|frame| self.ep.send_bytes(self.send_tag, &frame)) {
+            debug!("failed to send cancel notice: {e}");
+        }
+    }
+
+    // Cancels the call on the client and discards the frames the server sent
+    // before getting the cancel notice, up to the one that ends the call, so
+    // that they do not pile up in the unexpected queue of the worker.
+    fn abandon(&self) {
+        if self.finished.get() {
+            return;
+        }
+        self.cancel();
+        let drain_deadline = Instant::now() + DRAIN_TIMEOUT;
+        let deadline = self
+            .deadline
+            .get()
+            .map_or(drain_deadline, |deadline| deadline.min(drain_deadline));
+        while !self.finished.get() && !*self.ep.closed.borrow() {
+            if let Err(e) = self.pump_until(Some(deadline)) {
+                debug!(method_id = self.method_id, "stopped draining stream: {e}");
+                break;
+            }
+            self.incoming.borrow_mut().clear();
+        }
+    }
+
+    // Ends the call on the server with the encoded result of the handler, or
+    // the error that ended it. Clients give up on their own at the deadline
+    // and on a closed connection, so those calls are not answered.
+    pub(crate) fn end(
+        &self,
+        result: &Result<Vec<u8>, Error>,
+        trailers: Metadata,
+    ) -> Result<(), Error> {
+        self.finished.set(true);
+        let error;
+        let (kind, payload) = match result {
+            Ok(payload) => (FrameKind::Response, &payload[..]),
+            Err(Error::Timeout | Error::ConnectionReset) => return Ok(()),
+            Err(e) => {
+                error = e.to_string();
+                (FrameKind::Error, error.as_bytes())
+            }
+        };
+        self.send_frame_with(kind, payload, trailers)
+    }
+}
+
+// Ties a call handle to its message types without owning any of them.
+type CallMarker<Req, Resp, E> = PhantomData<fn() -> (Req, Resp, E)>;
+
+enum Received<T, R> {
+    Item(T),
+    Done(R),
+}
+
+fn recv_response<T, R, E>(channel: &Channel) -> Result<Received<T, R>, E>
+where
+    T: DeserializeOwned,
+    R: DeserializeOwned,
+    E: From<Error> + DeserializeOwned,
+{
+    let (kind, payload) = channel.recv_frame()?;
+    match kind {
+        FrameKind::StreamItem => Ok(Received::Item(DefaultCodec::decode(&payload)?)),
+        FrameKind::Response => {
+            let result: Result<R, E> = DefaultCodec::decode(&payload)?;
+            result.map(Received::Done)
+        }
+        FrameKind::Error => {
+            Err(Error::Remote(String::from_utf8_lossy(&payload).into_owned()).into())
+        }
+        kind => Err(Error::Codec(format!("unexpected {kind:?} frame")).into()),
+    }
+}
+
+/// The responses of a server-streaming call.
+///
+/// Yields the responses in order; a failed call ends with its error.
+#[derive(Debug)]
+pub struct ResponseStream<T, E> {
+    channel: Rc<Channel>,
+    done: bool,
+    _marker: PhantomData<fn() -> (T, E)>,
+}
+
+impl<T, E> ResponseStream<T, E> {
+    pub(crate) fn new(channel: Rc<Channel>) -> Self {
+        ResponseStream {
+            channel,
+            done: false,
+            _marker: PhantomData,
+        }
+    }
+
+    /// The trailers of the call, available once the stream has ended.
+    pub fn trailers(&self) -> Metadata {
+        self.channel.trailers.borrow().clone()
+    }
+}
+
+impl<T, E> Iterator for ResponseStream<T, E>
+where
+    T: DeserializeOwned,
+    E: From<Error> + DeserializeOwned,
+{
+    type Item = Result<T, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match recv_response::<T, (), E>(&self.channel) {
+            Ok(Received::Item(item)) => Some(Ok(item)),
+            Ok(Received::Done(())) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<T, E> Drop for ResponseStream<T, E> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.channel.abandon();
+        }
+    }
+}
+
+/// A client-streaming call: send requests, then [`finish`](Self::finish) to get the response.
+#[derive(Debug)]
+pub struct ClientStreaming<Req, Resp, E> {
+    channel: Rc<Channel>,
+    done: bool,
+    _marker: CallMarker<Req, Resp, E>,
+}
+
+impl<Req, Resp, E> ClientStreaming<Req, Resp, E>
+where
+    Req: Serialize,
+    Resp: DeserializeOwned,
+    E: From<Error> + DeserializeOwned,
+{
+    pub(crate) fn new(channel: Rc<Channel>) -> Self {
+        ClientStreaming {
+            channel,
+            done: false,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Sends one request, blocking while the server has no room for it.
+    ///
+    /// Fails with [`Error::Canceled`] once the server stopped reading; the
+    /// response is still available through [`finish`](Self::finish).
+    pub fn send(&mut self, request: &Req) -> Result<(), E> {
+        Ok(self.channel.send_item(&DefaultCodec::encode(request)?)?)
+    }
+
+    /// Ends the request stream and waits for the response.
+    pub fn finish(self) -> Result<Resp, E> {
+        self.finish_with_trailers().map(|reply| reply.value)
+    }
+
+    /// Like [`finish`](Self::finish), also returning the response trailers.
+    pub fn finish_with_trailers(mut self) -> Result<Reply<Resp>, E> {
+        if !self.channel.closed_for_send() {
+            self.channel.send_frame(FrameKind::StreamEnd, &[])?;
+        }
+        self.done = true;
+        loop {
+            if let Received::Done(value) = recv_response::<(), Resp, E>(&self.channel)? {
+                return Ok(Reply {
+                    value,
+                    trailers: self.channel.trailers.take(),
+                });
+            }
+        }
+    }
+}
+
+impl<Req, Resp, E> Drop for ClientStreaming<Req, Resp, E> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.channel.abandon();
+        }
+    }
+}
+
+/// A bidirectional streaming call.
+///
+/// Requests are sent with [`send`](Self::send) and responses are read by
+/// iterating over the call.
+#[derive(Debug)]
+pub struct BidiStreaming<Req, Resp, E> {
+    channel: Rc<Channel>,
+    send_closed: bool,
+    done: bool,
+    _marker: CallMarker<Req, Resp, E>,
+}
+
+impl<Req, Resp, E> BidiStreaming<Req, Resp, E>
+where
+    Req: Serialize,
+    Resp: DeserializeOwned,
+    E: From<Error> + DeserializeOwned,
+{
+    pub(crate) fn new(channel: Rc<Channel>) -> Self {
+        BidiStreaming {
+            channel,
+            send_closed: false,
+            done: false,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Sends one request, blocking while the server has no room for it.
+    pub fn send(&mut self, request: &Req) -> Result<(), E> {
+        if self.send_closed {
+            return Err(Error::Canceled.into());
+        }
+        Ok(self.channel.send_item(&DefaultCodec::encode(request)?)?)
+    }
+
+    /// The trailers of the call, available once the responses have ended.
+    pub fn trailers(&self) -> Metadata {
+        self.channel.trailers.borrow().clone()
+    }
+
+    /// Ends the request stream; responses can still be read.
+    pub fn close_send(&mut self) -> Result<(), E> {
+        if !self.send_closed {
+            self.send_closed = true;
+            if !self.channel.closed_for_send() {
+                self.channel.send_frame(FrameKind::StreamEnd, &[])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<Req, Resp, E> Iterator for BidiStreaming<Req, Resp, E>
+where
+    Resp: DeserializeOwned,
+    E: From<Error> + DeserializeOwned,
+{
+    type Item = Result<Resp, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match recv_response::<Resp, (), E>(&self.channel) {
+            Ok(Received::Item(item)) => Some(Ok(item)),
+            Ok(Received::Done(())) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<Req, Resp, E> Drop for BidiStreaming<Req, Resp, E> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.channel.abandon();
+        }
+    }
+}
+
+/// A streaming call handed to [`Service::call_streaming`].
+#[derive(Debug)]
+pub struct StreamCall {
+    channel: Rc<Channel>,
+    initial: Vec<u8>,
+}
+
+impl StreamCall {
+    pub(crate) fn new(channel: Rc<Channel>, initial: Vec<u8>) -> Self {
+        StreamCall { channel, initial }
+    }
+
+    /// Decodes the request a server-streaming call was opened with.
+    pub fn request<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        DefaultCodec::decode(&self.initial)
+    }
+
+    /// The requests streamed by the client.
+    pub fn requests<T>(&self) -> RequestStream<T> {
+        RequestStream {
+            channel: self.channel.clone(),
+            done: false,
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// The responses a server-streaming or bidirectional handler streams.
+///
+/// The call ends after the last response, or with the first error.
+pub type Responses<T, E> = LocalBoxStream<'static, Result<T, E>>;
+
+/// The requests of a client-streaming or bidirectional call, as seen by the handler.
+///
+/// Yields [`Poll::Pending`] until the next request arrives. Dropping it
+/// before the end tells the client to stop sending.
+#[derive(Debug)]
+pub struct RequestStream<T> {
+    channel: Rc<Channel>,
+    done: bool,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> Stream for RequestStream<T> {
+    type Item = Result<T, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        let item = match self.channel.try_recv_frame() {
+            Ok(None) => return Poll::Pending,
+            Ok(Some((FrameKind::StreamItem, payload))) => {
+                return Poll::Ready(Some(DefaultCodec::decode(&payload)))
+            }
+            Ok(Some((FrameKind::StreamEnd, _))) => None,
+            Ok(Some((kind, _))) => Some(Err(Error::Codec(format!("unexpected {kind:?} frame")))),
+            Err(e) => Some(Err(e)),
+        };
+        self.done = true;
+        Poll::Ready(item)
+    }
+}
+
+impl<T> Drop for RequestStream<T> {
+    fn drop(&mut self) {
+        if !self.done && !self.channel.peer_cancelled() {
+            self.channel.cancel();
+        }
+    }
+}
+
+// What a streaming handler produces, encoded.
+enum Output {
+    Item(Vec<u8>),
+    // The result of the handler, which ends the call.
+    Done(Vec<u8>),
+}
+
+/// A streaming handler in progress, which the [`Server`] drives.
+///
+/// [`Service::call_streaming`] builds it from what the handler returns.
+pub struct StreamHandler(LocalBoxStream<'static, Result<Output, Error>>);
+
+impl std::fmt::Debug for StreamHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StreamHandler").finish_non_exhaustive()
+    }
+}
+
+impl StreamHandler {
+    /// A handler streaming `responses`.
+    pub fn responses<T, E>(responses: impl Stream<Item = Result<T, E>> + 'static) -> Self
+    where
+        T: Serialize + 'static,
+        E: Serialize + 'static,
+    {
+        let responses = responses.map(|response| match response {
+            Ok(item) => DefaultCodec::encode(&item).map(Output::Item),
+            Err(e) => DefaultCodec::encode(&Err::<(), E>(e)).map(Output::Done),
+        });
+        let end = stream::once(async { DefaultCodec::encode(&Ok::<(), E>(())).map(Output::Done) });
+        StreamHandler(responses.chain(end).boxed_local())
+    }
+
+    /// A handler answering with the result of `response`.
+    pub fn response<T, E>(response: impl Future<Output = Result<T, E>> + 'static) -> Self
+    where
+        T: Serialize,
+        E: Serialize,
+    {
+        let response = async move { DefaultCodec::encode(&response.await).map(Output::Done) };
+        StreamHandler(stream::once(response).boxed_local())
+    }
+
+    // A handler that failed to start.
+    pub(crate) fn failed(error: Error) -> Self {
+        StreamHandler(stream::once(async { Err(error) }).boxed_local())
+    }
+
+    // Polls the handler, sending the items it produces while the client has
+    // room for them, and returns the encoded result once it has finished.
+    pub(crate) fn drive(&mut self, channel: &Channel) -> Poll<Result<Vec<u8>, Error>> {
+        let mut cx = task::Context::from_waker(futures::task::noop_waker_ref());
+        for _ in 0..ITEMS_PER_PASS {
+            if let Err(e) = channel.check() {
+                return Poll::Ready(Err(e));
+            }
+            if channel.credits.available() == 0 {
+                return Poll::Pending;
+            }
+            match self.0.poll_next_unpin(&mut cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Ok(Output::Item(item)))) => {
+                    channel.credits.try_acquire();
+                    if let Err(e) = channel.send_frame(FrameKind::StreamItem, &item) {
+                        return Poll::Ready(Err(e));
+                    }
+                }
+                Poll::Ready(Some(Ok(Output::Done(result)))) => return Poll::Ready(Ok(result)),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                Poll::Ready(None) => {
+                    return Poll::Ready(Err(Error::Codec(
+                        "handler ended without a result".to_string(),
+                    )))
+                }
+            }
+        }
+        Poll::Pending
+    }
+}
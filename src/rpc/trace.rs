@@ -0,0 +1,292 @@
+//! Propagation of trace context across calls.
+//!
+//! Clients send a W3C `traceparent` header with every call and servers run
+//! each handler in a `tracing` span that records the trace and span ids, so
+//! the spans of a request that crosses several servers share one trace id.
+//! Calls made from inside a handler continue the trace of the handled call.
+
+use super::Metadata;
+use crate::Error;
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::time::SystemTime;
+use tracing::field::{display, Empty};
+use tracing::{info_span, Span};
+
+/// The W3C trace context header.
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+thread_local! {
+    static CURRENT: Cell<Option<TraceContext>> = const { Cell::new(None) };
+}
+
+/// Identifies a span within a distributed trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    /// The id shared by all spans of the trace.
+    pub trace_id: u128,
+    /// The id of the span.
+    pub span_id: u64,
+    /// Whether the trace is sampled.
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// Starts a new trace.
+    pub fn new_root() -> Self {
+        TraceContext {
+            trace_id: (random_u64() as u128) << 64 | random_u64() as u128,
+            span_id: random_u64(),
+            sampled: true,
+        }
+    }
+
+    /// A new span in the same trace.
+    pub fn child(&self) -> Self {
+        TraceContext {
+            span_id: random_u64(),
+            ..*self
+        }
+    }
+
+    /// The context of the call being handled on this thread, or the one
+    /// installed with [`TraceContext::enter`].
+    pub fn current() -> Option<Self> {
+        CURRENT.get()
+    }
+
+    /// Makes this the current context until the guard is dropped.
+    pub fn enter(self) -> TraceGuard {
+        TraceGuard(CURRENT.replace(Some(self)))
+    }
+
+    /// Parses a `traceparent` header value.
+    pub fn from_traceparent(value: &str) -> Option<Self> {
+        let mut parts = value.split('-');
+        let (version, trace_id, span_id, flags) =
+            (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        if version != "00" || trace_id.len() != 32 || span_id.len() != 16 || flags.len() != 2 {
+            return None;
+        }
+        // Version 00 has exactly four fields of hex digits; `from_str_radix`
+        // alone would also take a sign.
+        if parts.next().is_some()
+            || ![trace_id, span_id, flags]
+                .iter()
+                .all(|part| part.bytes().all(|b| b.is_ascii_hexdigit()))
+        {
+            return None;
+        }
+        let trace_id = u128::from_str_radix(trace_id, 16).ok()?;
+        let span_id = u64::from_str_radix(span_id, 16).ok()?;
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        // All-zero ids are invalid.
+        if trace_id == 0 || span_id == 0 {
+            return None;
+        }
+        Some(TraceContext {
+            trace_id,
+            span_id,
+            sampled: flags & 1 != 0,
+        })
+    }
+
+    /// Formats the context as a `traceparent` header value.
+    pub fn to_traceparent(&self) -> String {
+        format!(
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.span_id, self.sampled as u8
+        )
+    }
+
+    /// Reads the context from the `traceparent` header of `metadata`.
+    pub fn extract(metadata: &Metadata) -> Option<Self> {
+        metadata
+            .get_str(TRACEPARENT_HEADER)
+            .and_then(Self::from_traceparent)
+    }
+
+    /// Writes the context into the `traceparent` header of `metadata`.
+    pub fn inject(&self, metadata: &mut Metadata) {
+        metadata.insert(TRACEPARENT_HEADER, self.to_traceparent());
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_traceparent())
+    }
+}
+
+/// Restores the previous trace context when dropped.
+#[derive(Debug)]
+pub struct TraceGuard(Option<TraceContext>);
+
+impl Drop for TraceGuard {
+    fn drop(&mut self) {
+        CURRENT.set(self.0.take());
+    }
+}
+
+/// The span of an outgoing call.
+pub(crate) fn client_span(
+    method_id: u32,
+    trace: &TraceContext,
+    parent: Option<TraceContext>,
+    request_bytes: usize,
+) -> Span {
+    let span = info_span!(
+        "rpc.client",
+        method_id = %format_args!("{method_id:#010x}"),
+        trace_id = %format_args!("{:032x}", trace.trace_id),
+        span_id = %format_args!("{:016x}", trace.span_id),
+        parent_id = Empty,
+        request_bytes,
+        response_bytes = Empty,
+        status = Empty,
+    );
+    if let Some(parent) = parent {
+        span.record(
+            "parent_id",
+            display(format_args!("{:016x}", parent.span_id)),
+        );
+    }
+    span
+}
+
+/// The span of a handled call.
+pub(crate) fn server_span(
+    method: &str,
+    peer: Option<SocketAddr>,
+    trace: &TraceContext,
+    parent: Option<TraceContext>,
+    request_bytes: usize,
+) -> Span {
+    let span = info_span!(
+        "rpc.server",
+        method,
+        peer = Empty,
+        trace_id = %format_args!("{:032x}", trace.trace_id),
+        span_id = %format_args!("{:016x}", trace.span_id),
+        parent_id = Empty,
+        request_bytes,
+        response_bytes = Empty,
+        status = Empty,
+    );
+    if let Some(peer) = peer {
+        span.record("peer", display(peer));
+    }
+    if let Some(parent) = parent {
+        span.record(
+            "parent_id",
+            display(format_args!("{:016x}", parent.span_id)),
+        );
+    }
+    span
+}
+
+/// Records how a call ended on its span, given the response size.
+pub(crate) fn record_outcome(span: &Span, result: Result<usize, &Error>) {
+    match result {
+        Ok(response_bytes) => {
+            span.record("response_bytes", response_bytes);
+            span.record("status", "ok");
+        }
+        Err(e) => {
+            span.record("status", display(e));
+        }
+    }
+}
+
+// Each `RandomState` is seeded differently, which is enough for span ids.
+pub(crate) fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(elapsed) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(elapsed.as_nanos());
+    }
+    match hasher.finish() {
+        0 => 1,
+        id => id,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traceparent_round_trips() {
+        let trace = TraceContext {
+            trace_id: 0x4bf92f3577b34da6a3ce929d0e0e4736,
+            span_id: 0x00f067aa0ba902b7,
+            sampled: true,
+        };
+        let value = trace.to_traceparent();
+        assert_eq!(
+            value,
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+        assert_eq!(TraceContext::from_traceparent(&value), Some(trace));
+
+        let unsampled = TraceContext {
+            sampled: false,
+            ..trace
+        };
+        assert_eq!(
+            TraceContext::from_traceparent(&unsampled.to_traceparent()),
+            Some(unsampled)
+        );
+    }
+
+    #[test]
+    fn malformed_traceparents_are_rejected() {
+        for value in [
+            "",
+            "00",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-1",
+            "00-4bf92f3577b34da6a3ce929d0e0e473g-00f067aa0ba902b7-01",
+            "00-+bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+        ] {
+            assert_eq!(TraceContext::from_traceparent(value), None, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn context_is_carried_in_metadata() {
+        let trace = TraceContext::new_root();
+        let mut metadata = Metadata::new();
+        trace.inject(&mut metadata);
+        assert_eq!(TraceContext::extract(&metadata), Some(trace));
+        assert_eq!(TraceContext::extract(&Metadata::new()), None);
+    }
+
+    #[test]
+    fn children_share_the_trace() {
+        let root = TraceContext::new_root();
+        let child = root.child();
+        assert_eq!(child.trace_id, root.trace_id);
+        assert_ne!(child.span_id, 0);
+    }
+
+    #[test]
+    fn entered_context_is_restored_on_drop() {
+        let outer = TraceContext::new_root();
+        let inner = outer.child();
+        let _outer = outer.enter();
+        {
+            let _inner = inner.enter();
+            assert_eq!(TraceContext::current(), Some(inner));
+        }
+        assert_eq!(TraceContext::current(), Some(outer));
+    }
+}
@@ -0,0 +1,464 @@
+//! Partitioning of the 64-bit tag space.
+//!
+//! Tags are split into three fields:
+//!
+//! | bits  | field                                    |
+//! |-------|------------------------------------------|
+//! | 56-63 | channel                                  |
+//! | 32-55 | source, e.g. a connection id or a rank   |
+//! | 0-31  | sequence, e.g. a call id or a step       |
+//!
+//! Each subsystem sending tag messages on a worker owns channels of its own,
+//! so its receives never match another subsystem's messages. Channels
+//! [`FIRST_RESERVED`] and up are used by this crate; the others are handed
+//! out by the [`TagSpace`] of the worker, which also rejects reservations
+//! that overlap those of another owner.
+//!
+//! Tag matching is done on the worker, so a tag message cannot be told apart
+//! from one with the same tag sent through another endpoint. Layers that set
+//! up a link therefore send their first message on the stream of the
+//! endpoint, which only its peer receives, prefixed with the id of their
+//! channel; since the stream is shared, they take turns in a fixed order:
+//! authentication, then the RPC connection, then keepalive.
+
+use crate::ucp::endpoint::Endpoint;
+use crate::Error;
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use std::time::Instant;
+use tracing::warn;
+
+/// The channel field.
+pub const CHANNEL: TagField = TagField::new(56, 8);
+/// The source field.
+pub const SOURCE: TagField = TagField::new(32, 24);
+/// The sequence field.
+pub const SEQUENCE: TagField = TagField::new(0, 32);
+
+/// The first of the channels reserved for this crate.
+pub const FIRST_RESERVED: u8 = 0xf0;
+/// Carries the handshakes of RPC connections.
+pub const RPC_CONNECT: TagChannel = TagChannel::new(0xf0);
+/// Carries RPC requests and the stream items clients send.
+pub const RPC_REQUEST: TagChannel = TagChannel::new(0xf1);
+/// Carries RPC responses and the stream items servers send.
+pub const RPC_RESPONSE: TagChannel = TagChannel::new(0xf2);
+/// Carries the messages of collective operations.
+pub const COLLECTIVE: TagChannel = TagChannel::new(0xf3);
+/// Carries publish/subscribe frames.
+pub const PUBSUB: TagChannel = TagChannel::new(0xf4);
+/// Carries the messages and credit grants of flow-controlled endpoints.
+pub const FLOW: TagChannel = TagChannel::new(0xf5);
+/// Carries keepalive pings and their answers.
+pub const KEEPALIVE: TagChannel = TagChannel::new(0xf6);
+/// Carries the authentication exchanges of new connections.
+pub const AUTH: TagChannel = TagChannel::new(0xf7);
+
+const BUILTIN: [(&str, TagChannel); 8] = [
+    ("rpc", RPC_CONNECT),
+    ("rpc", RPC_REQUEST),
+    ("rpc", RPC_RESPONSE),
+    ("collectives", COLLECTIVE),
+    ("pubsub", PUBSUB),
+    ("flow", FLOW),
+    ("keepalive", KEEPALIVE),
+    ("auth", AUTH),
+];
+
+/// A range of bits of the tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TagField {
+    shift: u32,
+    bits: u32,
+}
+
+impl TagField {
+    /// The `bits` bits starting at bit `shift`.
+    pub const fn new(shift: u32, bits: u32) -> Self {
+        assert!(bits > 0 && shift + bits <= 64);
+        TagField { shift, bits }
+    }
+
+    /// The largest value the field holds.
+    pub const fn max(self) -> u64 {
+        u64::MAX >> (64 - self.bits)
+    }
+
+    /// The bits of the field.
+    pub const fn mask(self) -> u64 {
+        self.max() << self.shift
+    }
+
+    /// Places `value` in the field, dropping the bits that do not fit.
+    pub const fn encode(self, value: u64) -> u64 {
+        (value & self.max()) << self.shift
+    }
+
+    /// The value of the field in `tag`.
+    pub const fn decode(self, tag: u64) -> u64 {
+        (tag >> self.shift) & self.max()
+    }
+}
+
+/// A channel of the tag space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TagChannel(u8);
+
+impl TagChannel {
+    /// The channel with id `id`.
+    pub const fn new(id: u8) -> Self {
+        TagChannel(id)
+    }
+
+    /// The id of the channel.
+    pub const fn id(self) -> u8 {
+        self.0
+    }
+
+    /// The tag on this channel with the given source and sequence.
+    ///
+    /// Sources wider than [`SOURCE`] are truncated.
+    pub const fn tag(self, source: u32, sequence: u32) -> u64 {
+        CHANNEL.encode(self.0 as u64)
+            | SOURCE.encode(source as u64)
+            | SEQUENCE.encode(sequence as u64)
+    }
+
+    /// The source and sequence of `tag`.
+    pub const fn split(tag: u64) -> (u32, u32) {
+        (SOURCE.decode(tag) as u32, SEQUENCE.decode(tag) as u32)
+    }
+
+    /// The mask matching every tag on the channel, used with [`TagChannel::tag`].
+    pub const fn mask(self) -> u64 {
+        CHANNEL.mask()
+    }
+
+    /// The mask matching every tag on the channel from one source.
+    pub const fn source_mask(self) -> u64 {
+        CHANNEL.mask() | SOURCE.mask()
+    }
+
+    /// Whether `tag` is on this channel.
+    pub const fn contains(self, tag: u64) -> bool {
+        CHANNEL.decode(tag) == self.0 as u64
+    }
+}
+
+// Sends the setup message `body` of the layer owning `channel` on the stream
+// of `ep`.
+pub(crate) fn send_setup(
+    ep: &Endpoint,
+    channel: TagChannel,
+    body: &[u8],
+    deadline: Option<Instant>,
+) -> Result<(), Error> {
+    let mut message = vec![channel.id()];
+    message.extend_from_slice(body);
+    ep.stream_send_until(&message, deadline)
+}
+
+// Receives the setup message of the layer owning `channel` from the stream
+// of `ep`, failing with `Error::Handshake` if the peer sent that of another
+// layer.
+pub(crate) fn recv_setup<const N: usize>(
+    ep: &Endpoint,
+    channel: TagChannel,
+    deadline: Option<Instant>,
+) -> Result<[u8; N], Error> {
+    let mut id = [0];
+    ep.stream_recv_until(&mut id, deadline)?;
+    if id[0] != channel.id() {
+        return Err(Error::Handshake(format!(
+            "expected a setup message on channel {:#04x}, got one on {:#04x}",
+            channel.id(),
+            id[0]
+        )));
+    }
+    let mut body = [0; N];
+    ep.stream_recv_until(&mut body, deadline)?;
+    Ok(body)
+}
+
+#[derive(Debug)]
+struct Entry {
+    owner: String,
+    tag: u64,
+    mask: u64,
+    // Reservations of the same range by the same owner share an entry.
+    refs: usize,
+}
+
+/// The tags reserved on a worker, see [`Worker::tag_space`](crate::ucp::Worker::tag_space).
+///
+/// A reservation is a tag and a mask, like a receive: it covers every tag
+/// that matches `tag` on the bits set in `mask`. Two owners cannot hold
+/// reservations that cover a common tag.
+#[derive(Debug)]
+pub struct TagSpace {
+    entries: Rc<RefCell<Vec<Entry>>>,
+}
+
+impl Default for TagSpace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TagSpace {
+    /// A tag space where only the channels of this crate are reserved.
+    pub fn new() -> Self {
+        let entries = BUILTIN
+            .iter()
+            .map(|(owner, channel)| Entry {
+                owner: owner.to_string(),
+                tag: channel.tag(0, 0),
+                mask: channel.mask(),
+                refs: 1,
+            })
+            .collect();
+        TagSpace {
+            entries: Rc::new(RefCell::new(entries)),
+        }
+    }
+
+    /// Reserves the tags matching `tag` on the bits of `mask` for `owner`.
+    ///
+    /// Fails with [`Error::InvalidParam`] if `tag` has bits outside `mask`,
+    /// and with [`Error::AlreadyExists`] if another owner holds a reservation
+    /// covering one of the tags. The reservation lasts until the returned
+    /// guard is dropped.
+    pub fn reserve(&self, owner: &str, tag: u64, mask: u64) -> Result<TagReservation, Error> {
+        if tag & !mask != 0 {
+            return Err(Error::InvalidParam);
+        }
+        let mut entries = self.entries.borrow_mut();
+        if let Some(other) = entries
+            .iter()
+            .find(|e| e.owner != owner && overlaps((e.tag, e.mask), (tag, mask)))
+        {
+            warn!(
+                "tags {tag:#018x}/{mask:#018x} of {owner} overlap {:#018x}/{:#018x} of {}",
+                other.tag, other.mask, other.owner
+            );
+            return Err(Error::AlreadyExists);
+        }
+        match entries
+            .iter_mut()
+            .find(|e| e.owner == owner && e.tag == tag && e.mask == mask)
+        {
+            Some(entry) => entry.refs += 1,
+            None => entries.push(Entry {
+                owner: owner.to_string(),
+                tag,
+                mask,
+                refs: 1,
+            }),
+        }
+        Ok(TagReservation {
+            entries: Rc::downgrade(&self.entries),
+            owner: owner.to_string(),
+            tag,
+            mask,
+        })
+    }
+
+    /// Reserves every tag on `channel` for `owner`.
+    pub fn reserve_channel(
+        &self,
+        owner: &str,
+        channel: TagChannel,
+    ) -> Result<TagReservation, Error> {
+        self.reserve(owner, channel.tag(0, 0), channel.mask())
+    }
+
+    /// Reserves the lowest free channel below [`FIRST_RESERVED`] for `owner`.
+    ///
+    /// Channel 0 is never handed out, as it holds the tags of code that does
+    /// not use channels. Fails with the error of `UCS_ERR_NO_RESOURCE` if all
+    /// are taken.
+    pub fn allocate_channel(&self, owner: &str) -> Result<(TagChannel, TagReservation), Error> {
+        for id in 1..FIRST_RESERVED {
+            let channel = TagChannel::new(id);
+            let taken = self
+                .entries
+                .borrow()
+                .iter()
+                .any(|e| overlaps((e.tag, e.mask), (channel.tag(0, 0), channel.mask())));
+            if !taken {
+                return Ok((channel, self.reserve_channel(owner, channel)?));
+            }
+        }
+        Err(Error::NoReource)
+    }
+
+    /// The owner, tag and mask of every reservation.
+    pub fn reservations(&self) -> Vec<(String, u64, u64)> {
+        self.entries
+            .borrow()
+            .iter()
+            .map(|e| (e.owner.clone(), e.tag, e.mask))
+            .collect()
+    }
+}
+
+// Whether a tag matches both.
+fn overlaps((tag1, mask1): (u64, u64), (tag2, mask2): (u64, u64)) -> bool {
+    (tag1 ^ tag2) & mask1 & mask2 == 0
+}
+
+/// Tags reserved in a [`TagSpace`], released when dropped.
+#[derive(Debug)]
+pub struct TagReservation {
+    entries: Weak<RefCell<Vec<Entry>>>,
+    owner: String,
+    tag: u64,
+    mask: u64,
+}
+
+impl TagReservation {
+    /// The owner of the tags.
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    /// The tag the reserved tags match.
+    pub fn tag(&self) -> u64 {
+        self.tag
+    }
+
+    /// The bits of the tag that are fixed.
+    pub fn mask(&self) -> u64 {
+        self.mask
+    }
+}
+
+impl Drop for TagReservation {
+    fn drop(&mut self) {
+        let Some(entries) = self.entries.upgrade() else {
+            return;
+        };
+        let mut entries = entries.borrow_mut();
+        if let Some(i) = entries
+            .iter()
+            .position(|e| e.owner == self.owner && e.tag == self.tag && e.mask == self.mask)
+        {
+            entries[i].refs -= 1;
+            if entries[i].refs == 0 {
+                entries.remove(i);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ucp::Context;
+    use std::time::Duration;
+
+    #[test]
+    fn fields_encode_and_decode() {
+        assert_eq!(SOURCE.max(), 0xff_ffff);
+        assert_eq!(SOURCE.mask(), 0x00ff_ffff_0000_0000);
+        assert_eq!(SOURCE.encode(0x1234), 0x0000_1234_0000_0000);
+        // Bits that do not fit are dropped rather than spilling into the channel.
+        assert_eq!(SOURCE.encode(0x1ff_ffff), SOURCE.mask());
+        assert_eq!(SOURCE.decode(u64::MAX), SOURCE.max());
+        assert_eq!(CHANNEL.mask() | SOURCE.mask() | SEQUENCE.mask(), u64::MAX);
+
+        let tag = RPC_REQUEST.tag(0x12_3456, 0x89ab_cdef);
+        assert_eq!(tag, 0xf112_3456_89ab_cdef);
+        assert_eq!(TagChannel::split(tag), (0x12_3456, 0x89ab_cdef));
+        assert!(RPC_REQUEST.contains(tag));
+        assert!(!RPC_RESPONSE.contains(tag));
+    }
+
+    #[test]
+    fn builtin_channels_are_reserved() {
+        let tags = TagSpace::new();
+        for (_, channel) in BUILTIN {
+            assert_eq!(
+                tags.reserve_channel("app", channel).unwrap_err(),
+                Error::AlreadyExists
+            );
+        }
+        assert_eq!(
+            tags.reserve("app", RPC_REQUEST.tag(1, 2), u64::MAX)
+                .unwrap_err(),
+            Error::AlreadyExists
+        );
+    }
+
+    #[test]
+    fn reservations_must_be_covered_by_their_mask() {
+        let tags = TagSpace::new();
+        assert_eq!(
+            tags.reserve("app", 1, CHANNEL.mask()).unwrap_err(),
+            Error::InvalidParam
+        );
+    }
+
+    #[test]
+    fn the_same_owner_reserves_again() {
+        let tags = TagSpace::new();
+        let channel = TagChannel::new(5);
+        let first = tags.reserve_channel("app", channel).unwrap();
+        let second = tags.reserve_channel("app", channel).unwrap();
+        let held = |owner: &str| {
+            tags.reservations()
+                .iter()
+                .filter(|(o, tag, _)| o == owner && *tag == channel.tag(0, 0))
+                .count()
+        };
+        assert_eq!(held("app"), 1);
+
+        drop(first);
+        assert_eq!(held("app"), 1);
+        assert_eq!(
+            tags.reserve_channel("other", channel).unwrap_err(),
+            Error::AlreadyExists
+        );
+        drop(second);
+        assert_eq!(held("app"), 0);
+        assert!(tags.reserve_channel("other", channel).is_ok());
+    }
+
+    #[test]
+    fn allocated_channels_are_released_on_drop() {
+        let tags = TagSpace::new();
+        let (first, reservation) = tags.allocate_channel("a").unwrap();
+        assert_eq!(first, TagChannel::new(1));
+        let (second, _held) = tags.allocate_channel("b").unwrap();
+        assert_eq!(second, TagChannel::new(2));
+        drop(reservation);
+        assert_eq!(tags.allocate_channel("c").unwrap().0, first);
+    }
+
+    #[test]
+    fn allocation_fails_once_every_channel_is_taken() {
+        let tags = TagSpace::new();
+        let held: Vec<_> = (1..FIRST_RESERVED)
+            .map(|_| tags.allocate_channel("app").unwrap())
+            .collect();
+        assert_eq!(held.last().unwrap().0, TagChannel::new(FIRST_RESERVED - 1));
+        assert_eq!(tags.allocate_channel("app").unwrap_err(), Error::NoReource);
+    }
+
+    #[test]
+    fn recv_setup_gives_up_on_a_silent_peer() {
+        let context = Context::new().unwrap();
+        let worker = context.create_worker().unwrap();
+        let peer = context.create_worker().unwrap();
+        let address = peer.address().unwrap();
+        let ep = unsafe { Endpoint::from_worker_address(worker, address.as_ref()) }.unwrap();
+
+        let started = Instant::now();
+        let deadline = started + Duration::from_millis(100);
+        let result = recv_setup::<16>(&ep, RPC_CONNECT, Some(deadline));
+        assert!(matches!(result, Err(Error::Timeout)), "{result:?}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // The receive could not be cancelled, so the endpoint was closed.
+        assert!(*ep.closed.borrow());
+    }
+}
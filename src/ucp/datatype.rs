@@ -0,0 +1,510 @@
+//! Generic datatypes with user-defined pack/unpack callbacks.
+//!
+//! UCX drives the callbacks of a generic datatype while it moves a message,
+//! so a value can be serialized straight into transport buffers (and rebuilt
+//! from them) without an intermediate contiguous copy.
+//!
+//! Only tag operations take generic datatypes so far
+//! ([`Endpoint::tag_send_generic`](super::endpoint::Endpoint::tag_send_generic)
+//! and [`Endpoint::tag_recv_generic`](super::endpoint::Endpoint::tag_recv_generic)),
+//! as this crate does not wrap the stream and active message APIs yet.
+
+use super::*;
+use std::cell::RefCell;
+use std::marker::PhantomData;
+
+/// A type that can be serialized by a generic UCX datatype.
+pub trait UcxPack {
+    /// Total number of bytes produced by packing `self`.
+    fn packed_size(&self) -> usize;
+
+    /// Packs the bytes of the serialized form starting at `offset` into `dest`.
+    ///
+    /// Returns the number of bytes written, which must be `dest.len()` unless
+    /// the end of the serialized form is reached.
+    fn pack(&self, offset: usize, dest: &mut [u8]) -> usize;
+}
+
+/// A type that can be rebuilt from the serialized form of a generic UCX datatype.
+pub trait UcxUnpack {
+    /// Per-operation parser state, created when UCX starts unpacking a message.
+    type State: Default;
+
+    /// Upper bound on the number of bytes this value can take in.
+    ///
+    /// UCX reports a truncated message when the incoming data is larger.
+    fn capacity(&self) -> usize {
+        usize::MAX
+    }
+
+    /// Unpacks the fragment `src` found at `offset` in the serialized form.
+    fn unpack(&mut self, state: &mut Self::State, offset: usize, src: &[u8]) -> Result<(), Error>;
+
+    /// Checks the value once the last fragment has been unpacked.
+    ///
+    /// The error is that of the receive, as if the last fragment had failed.
+    fn finish(&mut self, state: &mut Self::State) -> Result<(), Error> {
+        let _ = state;
+        Ok(())
+    }
+}
+
+// The errors of `UcxUnpack::finish`, which UCX has no way to report, by the
+// address of the value that failed. Taken by the request of the receive.
+pub(crate) type Failures = RefCell<Vec<(usize, Error)>>;
+
+/// A generic datatype registered with UCX for values of type `T`.
+///
+/// A datatype created with [`Datatype::new`] moves values both ways, while
+/// those of [`Datatype::send_only`] and [`Datatype::recv_only`] only need
+/// `T` to implement the trait of their direction; receiving with a send-only
+/// datatype fails with [`Error::Unsupported`], and so does sending with a
+/// receive-only one.
+#[derive(Debug)]
+pub struct Datatype<T> {
+    pub(crate) handle: ucp_datatype_t,
+    pub(crate) can_send: bool,
+    pub(crate) can_recv: bool,
+    pub(crate) failures: Rc<Failures>,
+    _marker: PhantomData<fn(&T)>,
+}
+
+// The state of one pack or unpack operation, behind the opaque state pointer
+// UCX hands to the callbacks.
+trait Operation {
+    fn packed_size(&self) -> usize;
+    fn pack(&self, offset: usize, dest: &mut [u8]) -> usize;
+    fn unpack(&mut self, offset: usize, src: &[u8]) -> ucs_status_t;
+
+    fn finish(&mut self) {}
+}
+
+struct PackOperation<T>(*const T);
+
+impl<T: UcxPack> Operation for PackOperation<T> {
+    fn packed_size(&self) -> usize {
+        unsafe { (*self.0).packed_size() }
+    }
+
+    fn pack(&self, offset: usize, dest: &mut [u8]) -> usize {
+        unsafe { (*self.0).pack(offset, dest) }
+    }
+
+    fn unpack(&mut self, _: usize, _: &[u8]) -> ucs_status_t {
+        ucs_status_t::UCS_ERR_INVALID_PARAM
+    }
+}
+
+struct UnpackOperation<T: UcxUnpack>(*mut T, T::State, *const Failures);
+
+impl<T: UcxUnpack> Operation for UnpackOperation<T> {
+    fn packed_size(&self) -> usize {
+        unsafe { (*self.0).capacity() }
+    }
+
+    fn pack(&self, _: usize, _: &mut [u8]) -> usize {
+        0
+    }
+
+    fn unpack(&mut self, offset: usize, src: &[u8]) -> ucs_status_t {
+        match unsafe { (*self.0).unpack(&mut self.1, offset, src) } {
+            Ok(()) => ucs_status_t::UCS_OK,
+            Err(e) => e.status(),
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Err(e) = unsafe { (*self.0).finish(&mut self.1) } {
+            unsafe { (*self.2).borrow_mut().push((self.0 as usize, e)) };
+        }
+    }
+}
+
+// The operation of a datatype used in the direction it was not created for.
+struct Unsupported;
+
+impl Operation for Unsupported {
+    fn packed_size(&self) -> usize {
+        0
+    }
+
+    fn pack(&self, _: usize, _: &mut [u8]) -> usize {
+        0
+    }
+
+    fn unpack(&mut self, _: usize, _: &[u8]) -> ucs_status_t {
+        ucs_status_t::UCS_ERR_UNSUPPORTED
+    }
+}
+
+type StartPack = unsafe extern "C" fn(*mut c_void, *const c_void, usize) -> *mut c_void;
+type StartUnpack = unsafe extern "C" fn(*mut c_void, *mut c_void, usize) -> *mut c_void;
+
+// The state is only used while UCX moves the value, so the lifetime of `T`
+// can be erased.
+fn start<'a>(operation: Box<dyn Operation + 'a>) -> *mut c_void {
+    Box::into_raw(Box::new(operation)) as _
+}
+
+unsafe extern "C" fn start_pack<T: UcxPack>(
+    _: *mut c_void,
+    buffer: *const c_void,
+    _: usize,
+) -> *mut c_void {
+    start(Box::new(PackOperation(buffer as *const T)))
+}
+
+unsafe extern "C" fn start_unpack<T: UcxUnpack>(
+    failures: *mut c_void,
+    buffer: *mut c_void,
+    _: usize,
+) -> *mut c_void {
+    start(Box::new(UnpackOperation(
+        buffer as *mut T,
+        T::State::default(),
+        failures as *const Failures,
+    )))
+}
+
+unsafe extern "C" fn start_unsupported_pack(
+    _: *mut c_void,
+    _: *const c_void,
+    _: usize,
+) -> *mut c_void {
+    start(Box::new(Unsupported))
+}
+
+unsafe extern "C" fn start_unsupported_unpack(
+    _: *mut c_void,
+    _: *mut c_void,
+    _: usize,
+) -> *mut c_void {
+    start(Box::new(Unsupported))
+}
+
+unsafe extern "C" fn packed_size(state: *mut c_void) -> usize {
+    (*(state as *const Box<dyn Operation>)).packed_size()
+}
+
+unsafe extern "C" fn pack(
+    state: *mut c_void,
+    offset: usize,
+    dest: *mut c_void,
+    max_length: usize,
+) -> usize {
+    let dest = std::slice::from_raw_parts_mut(dest as *mut u8, max_length);
+    (*(state as *const Box<dyn Operation>)).pack(offset, dest)
+}
+
+unsafe extern "C" fn unpack(
+    state: *mut c_void,
+    offset: usize,
+    src: *const c_void,
+    length: usize,
+) -> ucs_status_t {
+    let src = std::slice::from_raw_parts(src as *const u8, length);
+    (*(state as *mut Box<dyn Operation>)).unpack(offset, src)
+}
+
+unsafe extern "C" fn finish(state: *mut c_void) {
+    let mut operation = Box::from_raw(state as *mut Box<dyn Operation>);
+    operation.finish();
+}
+
+impl<T> Datatype<T> {
+    fn create(
+        start_pack: Option<StartPack>,
+        start_unpack: Option<StartUnpack>,
+    ) -> Result<Self, Error> {
+        let ops = ucp_generic_dt_ops_t {
+            start_pack: Some(start_pack.unwrap_or(start_unsupported_pack)),
+            start_unpack: Some(start_unpack.unwrap_or(start_unsupported_unpack)),
+            packed_size: Some(packed_size),
+            pack: Some(pack),
+            unpack: Some(unpack),
+            finish: Some(finish),
+        };
+        let failures = Rc::new(Failures::default());
+        let mut handle = MaybeUninit::uninit();
+        let status =
+            unsafe { ucp_dt_create_generic(&ops, Rc::as_ptr(&failures) as _, handle.as_mut_ptr()) };
+        Error::from_status(status)?;
+
+        Ok(Datatype {
+            handle: unsafe { handle.assume_init() },
+            can_send: start_pack.is_some(),
+            can_recv: start_unpack.is_some(),
+            failures,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<T: UcxPack + UcxUnpack> Datatype<T> {
+    /// Registers the pack/unpack callbacks of `T` as a new generic datatype.
+    pub fn new() -> Result<Self, Error> {
+        Self::create(Some(start_pack::<T>), Some(start_unpack::<T>))
+    }
+}
+
+impl<T: UcxPack> Datatype<T> {
+    /// Registers the pack callbacks of `T` as a generic datatype for sending.
+    pub fn send_only() -> Result<Self, Error> {
+        Self::create(Some(start_pack::<T>), None)
+    }
+}
+
+impl<T: UcxUnpack> Datatype<T> {
+    /// Registers the unpack callbacks of `T` as a generic datatype for receiving.
+    pub fn recv_only() -> Result<Self, Error> {
+        Self::create(None, Some(start_unpack::<T>))
+    }
+}
+
+impl<T> Drop for Datatype<T> {
+    fn drop(&mut self) {
+        unsafe { ucp_dt_destroy(self.handle) }
+    }
+}
+
+/// Copies the window `[offset, offset + dest.len())` of a sequence of segments.
+struct SegmentPacker<'a> {
+    skip: usize,
+    dest: &'a mut [u8],
+    written: usize,
+}
+
+impl<'a> SegmentPacker<'a> {
+    fn new(offset: usize, dest: &'a mut [u8]) -> Self {
+        SegmentPacker {
+            skip: offset,
+            dest,
+            written: 0,
+        }
+    }
+
+    fn put(&mut self, segment: &[u8]) {
+        if self.skip >= segment.len() {
+            self.skip -= segment.len();
+            return;
+        }
+        let segment = &segment[self.skip..];
+        self.skip = 0;
+        let n = segment.len().min(self.dest.len() - self.written);
+        self.dest[self.written..self.written + n].copy_from_slice(&segment[..n]);
+        self.written += n;
+    }
+}
+
+impl UcxPack for Vec<u8> {
+    fn packed_size(&self) -> usize {
+        self.len()
+    }
+
+    fn pack(&self, offset: usize, dest: &mut [u8]) -> usize {
+        let mut packer = SegmentPacker::new(offset, dest);
+        packer.put(self);
+        packer.written
+    }
+}
+
+/// Previous contents are discarded when the first fragment arrives.
+impl UcxUnpack for Vec<u8> {
+    type State = bool;
+
+    fn unpack(&mut self, started: &mut bool, offset: usize, src: &[u8]) -> Result<(), Error> {
+        if !*started {
+            *started = true;
+            self.clear();
+        }
+        if self.len() < offset + src.len() {
+            self.resize(offset + src.len(), 0);
+        }
+        self.as_mut_slice()[offset..offset + src.len()].copy_from_slice(src);
+        Ok(())
+    }
+}
+
+/// Serialized as a little-endian `u64` element count followed by each element
+/// as a little-endian `u64` length and its bytes.
+impl UcxPack for Vec<Vec<u8>> {
+    fn packed_size(&self) -> usize {
+        8 + self.iter().map(|item| 8 + item.len()).sum::<usize>()
+    }
+
+    fn pack(&self, offset: usize, dest: &mut [u8]) -> usize {
+        let mut packer = SegmentPacker::new(offset, dest);
+        packer.put(&(self.len() as u64).to_le_bytes());
+        for item in self {
+            packer.put(&(item.len() as u64).to_le_bytes());
+            packer.put(item);
+        }
+        packer.written
+    }
+}
+
+/// Parser state of [`Vec<Vec<u8>>`], which requires fragments in order.
+#[derive(Debug, Default)]
+pub struct VecOfVecState {
+    offset: usize,
+    header: [u8; 8],
+    header_len: usize,
+    count: Option<u64>,
+    remaining_items: u64,
+    remaining_bytes: usize,
+}
+
+/// The counts and lengths come from the peer, so nothing is allocated ahead
+/// of the bytes that actually arrive: a message whose lengths do not add up
+/// to its size fails with [`Error::MessageTruncated`] instead.
+impl UcxUnpack for Vec<Vec<u8>> {
+    type State = VecOfVecState;
+
+    fn unpack(
+        &mut self,
+        state: &mut VecOfVecState,
+        offset: usize,
+        mut src: &[u8],
+    ) -> Result<(), Error> {
+        if offset != state.offset {
+            return Err(Error::InvalidParam);
+        }
+        state.offset += src.len();
+
+        while !src.is_empty() {
+            if state.remaining_bytes > 0 {
+                let item = self.last_mut().ok_or(Error::MessageTruncated)?;
+                let n = state.remaining_bytes.min(src.len());
+                item.extend_from_slice(&src[..n]);
+                state.remaining_bytes -= n;
+                src = &src[n..];
+                continue;
+            }
+            if state.count.is_some() && state.remaining_items == 0 {
+                return Err(Error::MessageTruncated);
+            }
+
+            let n = (8 - state.header_len).min(src.len());
+            state.header[state.header_len..state.header_len + n].copy_from_slice(&src[..n]);
+            state.header_len += n;
+            src = &src[n..];
+            if state.header_len < 8 {
+                break;
+            }
+            state.header_len = 0;
+            let value = u64::from_le_bytes(state.header);
+
+            if state.count.is_none() {
+                state.count = Some(value);
+                state.remaining_items = value;
+                self.clear();
+            } else {
+                state.remaining_items -= 1;
+                state.remaining_bytes =
+                    usize::try_from(value).map_err(|_| Error::MessageTruncated)?;
+                self.push(Vec::new());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self, state: &mut VecOfVecState) -> Result<(), Error> {
+        if state.count.is_none() || state.remaining_items > 0 || state.remaining_bytes > 0 {
+            return Err(Error::MessageTruncated);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packed(value: &Vec<Vec<u8>>) -> Vec<u8> {
+        let mut bytes = vec![0; value.packed_size()];
+        assert_eq!(value.pack(0, &mut bytes), bytes.len());
+        bytes
+    }
+
+    fn unpack_in_fragments(bytes: &[u8], fragment: usize) -> Result<Vec<Vec<u8>>, Error> {
+        let mut value = vec![b"stale".to_vec()];
+        let mut state = VecOfVecState::default();
+        for (i, chunk) in bytes.chunks(fragment).enumerate() {
+            value.unpack(&mut state, i * fragment, chunk)?;
+        }
+        value.finish(&mut state)?;
+        Ok(value)
+    }
+
+    #[test]
+    fn vec_of_vec_round_trips_in_any_fragment_size() {
+        let value = vec![b"hello".to_vec(), Vec::new(), vec![7; 100]];
+        let bytes = packed(&value);
+        for fragment in [1, 3, 8, 13, bytes.len()] {
+            assert_eq!(unpack_in_fragments(&bytes, fragment).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn vec_of_vec_packs_from_an_offset() {
+        let value = vec![b"abc".to_vec(), b"defg".to_vec()];
+        let bytes = packed(&value);
+        let mut tail = vec![0; 4];
+        assert_eq!(value.pack(bytes.len() - 2, &mut tail), 2);
+        assert_eq!(&tail[..2], b"fg");
+    }
+
+    #[test]
+    fn vec_of_vec_rejects_trailing_bytes() {
+        let mut bytes = packed(&vec![b"abc".to_vec()]);
+        bytes.extend_from_slice(&[0; 8]);
+        assert_eq!(unpack_in_fragments(&bytes, 5), Err(Error::MessageTruncated));
+    }
+
+    #[test]
+    fn vec_of_vec_rejects_out_of_order_fragments() {
+        let bytes = packed(&vec![b"abc".to_vec()]);
+        let mut value: Vec<Vec<u8>> = Vec::new();
+        let mut state = VecOfVecState::default();
+        assert_eq!(
+            value.unpack(&mut state, 8, &bytes[8..]),
+            Err(Error::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn vec_of_vec_does_not_trust_lengths() {
+        let mut bytes = u64::MAX.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&(usize::MAX as u64).to_le_bytes());
+        bytes.extend_from_slice(b"abc");
+        let mut value: Vec<Vec<u8>> = Vec::new();
+        let mut state = VecOfVecState::default();
+        for (i, chunk) in bytes.chunks(4).enumerate() {
+            value.unpack(&mut state, i * 4, chunk).unwrap();
+        }
+        assert!(value.capacity() < 1024 && value[0].capacity() < 1024);
+        assert_eq!(value.finish(&mut state), Err(Error::MessageTruncated));
+    }
+
+    #[test]
+    fn vec_of_vec_rejects_truncated_messages() {
+        let bytes = packed(&vec![b"abc".to_vec(), b"de".to_vec()]);
+        for len in 0..bytes.len() {
+            assert_eq!(
+                unpack_in_fragments(&bytes[..len], 3),
+                Err(Error::MessageTruncated),
+                "{len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn vec_of_u8_replaces_previous_contents() {
+        let mut value = b"previous contents".to_vec();
+        let mut started = false;
+        value.unpack(&mut started, 3, b"def").unwrap();
+        value.unpack(&mut started, 0, b"abc").unwrap();
+        assert_eq!(value, b"abcdef");
+    }
+}
@@ -1,6 +1,11 @@
 use super::*;
+use crate::codec::{Codec, DefaultCodec};
+use crate::metrics::{self, EndpointMetrics, Operation};
+use crate::ucp::datatype::{Datatype, Failures, UcxPack, UcxUnpack};
 use crate::ucp::listener::ConnectionRequest;
-use std::{cell::RefCell, net::SocketAddr, rc::Weak};
+use crate::ucp::pool::Buffer;
+use std::{cell::RefCell, net::SocketAddr, rc::Weak, time::Instant};
+use serde::{de::DeserializeOwned, Serialize};
 use socket2::SockAddr;
 use tracing::{debug, error, info};
 
@@ -13,29 +18,140 @@ pub struct Endpoint {
   pub ptr: ucp_ep_h,
   pub closed: Rc<RefCell<bool>>,
   pub worker: Rc<Worker>,
+  metrics: Arc<EndpointMetrics>,
 }
 
 #[derive(Debug)]
 pub struct StatusPtr {
   pub ptr: ucs_status_ptr_t,
+  op: Operation,
+  started: Instant,
+  // The counters and size of a send, counted once it has completed.
+  sent: Option<(Arc<EndpointMetrics>, u64)>,
+  // Where the unpacking of a generic receive reports that it failed, and the
+  // address of the value it went to.
+  unpacked: Option<(Rc<Failures>, usize)>,
 }
 
 impl StatusPtr {
-  pub fn wait(self, worker: &Worker) -> Result<(), Error> {
-      if !self.ptr.is_null() && UCS_PTR_STATUS(self.ptr) != ucs_status_t::UCS_OK {
-          let mut checked_status = ucs_status_t::UCS_INPROGRESS;
-          debug!("wait worker: {:?}", worker.print_to_stderr());
-          while checked_status == ucs_status_t::UCS_INPROGRESS {
-              unsafe {
-                checked_status = ucp_request_check_status(self.ptr);
+  /// Wraps the request returned by an `*_nbx` call, counting it as in flight until dropped.
+  pub fn new(ptr: ucs_status_ptr_t, op: Operation) -> Self {
+      if UCS_PTR_IS_PTR(ptr) {
+          metrics::operation_started(op);
+      }
+      StatusPtr {
+          ptr,
+          op,
+          started: Instant::now(),
+          sent: None,
+          unpacked: None,
+      }
+  }
+
+  // Counts `bytes` as sent on `metrics` once the request completes successfully.
+  fn counting_sent(mut self, metrics: &Arc<EndpointMetrics>, bytes: usize) -> Self {
+      self.sent = Some((metrics.clone(), bytes as u64));
+      self
+  }
+
+  // Fails the request if unpacking into `value` fails once it has completed.
+  fn checking_unpack<T>(mut self, datatype: &Datatype<T>, value: &T) -> Self {
+      self.unpacked = Some((datatype.failures.clone(), value as *const T as usize));
+      self
+  }
+
+  fn completed(&mut self, status: ucs_status_t) {
+      if status == ucs_status_t::UCS_OK {
+          if let Some((metrics, bytes)) = self.sent.take() {
+              metrics.bytes_sent.add(bytes);
+          }
+      }
+  }
+
+  // The result of the request, which completed with `status`.
+  fn finished(&mut self, status: ucs_status_t) -> Result<(), Error> {
+      metrics::operation_completed(self.op, self.started.elapsed());
+      self.completed(status);
+      let failure = self.take_unpack_failure();
+      Error::from_status(status)?;
+      failure.map_or(Ok(()), Err)
+  }
+
+  fn take_unpack_failure(&mut self) -> Option<Error> {
+      let (failures, value) = self.unpacked.take()?;
+      let mut failures = failures.borrow_mut();
+      let i = failures.iter().position(|(address, _)| *address == value)?;
+      Some(failures.swap_remove(i).1)
+  }
+
+  pub fn wait(mut self, worker: &Worker) -> Result<(), Error> {
+      self.wait_or_expire(worker, None).expect("a request without a deadline does not expire")
+  }
+
+  /// Like [`StatusPtr::wait`], but gives up at `deadline`.
+  ///
+  /// An expired request is cancelled with `ucp_request_cancel` and the
+  /// error is [`Error::Timeout`], unless it completed before the cancel
+  /// took effect, in which case its own status is returned. Only tag
+  /// receives can be cancelled, so a request still in progress after the
+  /// cancel is not waited for: it is handed to `worker` together with
+  /// `buffer`, which is dropped once the request completes.
+  pub fn wait_holding<T: 'static>(
+      mut self,
+      worker: &Worker,
+      buffer: T,
+      deadline: Option<Instant>,
+  ) -> Result<T, Error> {
+      match self.wait_or_expire(worker, deadline) {
+          Some(result) => result.map(|()| buffer),
+          None => {
+              worker.hold_expired(self, Box::new(buffer));
+              Err(Error::Timeout)
+          }
+      }
+  }
+
+  // Waits for the request until `deadline`. Returns `None` if the request is
+  // still in progress after it was cancelled at the deadline.
+  pub(crate) fn wait_or_expire(&mut self, worker: &Worker, deadline: Option<Instant>) -> Option<Result<(), Error>> {
+      if !UCS_PTR_IS_PTR(self.ptr) {
+          return Some(self.finished(UCS_PTR_STATUS(self.ptr)));
+      }
+      let mut checked_status = ucs_status_t::UCS_INPROGRESS;
+      debug!("wait worker: {:?}", worker.print_to_stderr());
+      while checked_status == ucs_status_t::UCS_INPROGRESS {
+          if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+              self.cancel(worker);
+              // A cancelled tag receive completes with UCS_ERR_CANCELED right
+              // away; other requests carry on.
+              let status = self.status();
+              if status == ucs_status_t::UCS_INPROGRESS {
+                  debug!("wait timed out, ptr: {:?}", self.ptr);
+                  return None;
               }
-              worker.progress();
-              // info!("wait checked_status: {:?}", checked_status);
+              if status == ucs_status_t::UCS_ERR_CANCELED {
+                  debug!("wait timed out, ptr: {:?}", self.ptr);
+                  let _ = self.finished(status);
+                  return Some(Err(Error::Timeout));
+              }
+              return Some(self.finished(status));
           }
-          debug!("wait checked_status: {:?}", checked_status);
-          Error::from_status(checked_status)
-      } else {
-          Ok(())
+          unsafe {
+            checked_status = ucp_request_check_status(self.ptr);
+          }
+          worker.progress();
+          // info!("wait checked_status: {:?}", checked_status);
+      }
+      debug!("wait checked_status: {:?}", checked_status);
+      Some(self.finished(checked_status))
+  }
+
+  /// Cancels the request if it is still in progress.
+  ///
+  /// The request completes with `UCS_ERR_CANCELED` on a later progress of `worker`.
+  pub fn cancel(&self, worker: &Worker) {
+      if UCS_PTR_IS_PTR(self.ptr) {
+          unsafe { ucp_request_cancel(worker.handle, self.ptr) }
       }
   }
 
@@ -44,22 +160,118 @@ impl StatusPtr {
       unsafe { ucp_request_check_status(self.ptr) }
   }
 }
+
+// The status pointer of an operation that failed with UCS_ERR_UNSUPPORTED
+// before it could be posted.
+fn unsupported() -> ucs_status_ptr_t {
+  ucs_status_t::UCS_ERR_UNSUPPORTED as isize as ucs_status_ptr_t
+}
+
 impl Drop for StatusPtr {
   fn drop(&mut self) {
         debug!("StatusPtr drop, ptr: {:?}", self.ptr,);
+      // Requests dropped without waiting for them count if they have completed.
+      if self.sent.is_some() {
+          let status = if UCS_PTR_IS_PTR(self.ptr) { self.status() } else { UCS_PTR_STATUS(self.ptr) };
+          self.completed(status);
+      }
+      self.take_unpack_failure();
       if UCS_PTR_IS_PTR(self.ptr) {
+          metrics::operation_finished(self.op);
           unsafe { ucp_request_free(self.ptr as _) }
       }
   }
 }
 
 impl Endpoint {
+  /// The traffic counters of the endpoint.
+  ///
+  /// Receives are matched on the worker, so only messages received through
+  /// the endpoint's own methods or the RPC layer are counted as received.
+  pub fn metrics(&self) -> &Arc<EndpointMetrics> {
+      &self.metrics
+  }
+
   pub fn print_to_stderr(&self) {
       unsafe {
           ucp_ep_print_info(self.ptr, stderr);
       }
   }
 
+  /// Writes the information printed by [`Endpoint::print_to_stderr`] to `out`.
+  pub fn print_info(&self, out: &mut impl std::io::Write) -> std::io::Result<()> {
+      info::write_dump(out, |stream| unsafe { ucp_ep_print_info(self.ptr, stream) })
+  }
+
+  /// Fails with [`Error::Unreachable`] unless every lane of the endpoint
+  /// uses an intra-node transport, see [`EndpointInfo::is_intra_node`].
+  pub fn require_intra_node(&self) -> Result<(), Error> {
+      let info = self.query()?;
+      if !info.is_intra_node() {
+          let transports: Vec<_> = info.transports.iter().map(|t| t.transport_name.as_str()).collect();
+          debug!(?transports, "endpoint is not intra-node");
+          return Err(Error::Unreachable);
+      }
+      Ok(())
+  }
+
+  /// Fetches information about the endpoint.
+  pub fn query(&self) -> Result<EndpointInfo, Error> {
+      // Enough for every lane UCX can set up.
+      const MAX_TRANSPORTS: usize = 64;
+      let mut entries = [ucp_transport_entry_t {
+          transport_name: std::ptr::null(),
+          device_name: std::ptr::null(),
+      }; MAX_TRANSPORTS];
+      let mut attr = MaybeUninit::<ucp_ep_attr>::uninit();
+      unsafe {
+          let attr = &mut *attr.as_mut_ptr();
+          attr.field_mask = (ucp_ep_attr_field::UCP_EP_ATTR_FIELD_NAME
+              | ucp_ep_attr_field::UCP_EP_ATTR_FIELD_TRANSPORTS)
+              .0 as u64;
+          attr.transports = ucp_transports_t {
+              entries: entries.as_mut_ptr(),
+              num_entries: MAX_TRANSPORTS as _,
+              entry_size: std::mem::size_of::<ucp_transport_entry_t>(),
+          };
+      }
+      let status = unsafe { ucp_ep_query(self.ptr, attr.as_mut_ptr()) };
+      Error::from_status(status)?;
+      let attr = unsafe { attr.assume_init() };
+      let transports = entries[..attr.transports.num_entries as usize]
+          .iter()
+          .map(|entry| unsafe {
+              TransportInfo {
+                  transport_name: info::c_string(entry.transport_name),
+                  device_name: info::c_string(entry.device_name),
+              }
+          })
+          .collect();
+
+      // Socket addresses are only known for client-server endpoints, and
+      // the query fails for the others.
+      let mut addrs = MaybeUninit::<ucp_ep_attr>::uninit();
+      unsafe { &mut *addrs.as_mut_ptr() }.field_mask =
+          (ucp_ep_attr_field::UCP_EP_ATTR_FIELD_LOCAL_SOCKADDR
+              | ucp_ep_attr_field::UCP_EP_ATTR_FIELD_REMOTE_SOCKADDR)
+              .0 as u64;
+      let status = unsafe { ucp_ep_query(self.ptr, addrs.as_mut_ptr()) };
+      let (local_addr, remote_addr) = match Error::from_status(status) {
+          Ok(()) => {
+              let addrs = unsafe { addrs.assume_init() };
+              (socket_addr(&addrs.local_sockaddr), socket_addr(&addrs.remote_sockaddr))
+          }
+          Err(_) => (None, None),
+      };
+
+      Ok(EndpointInfo {
+          name: info::name(&attr.name),
+          local_addr,
+          remote_addr,
+          transports,
+      })
+  }
+
   pub unsafe fn from_sockaddr(worker: Rc<Worker>, addr: SocketAddr) -> Result<Self, Error> {
       unsafe extern "C" fn err_handler(user_data: *mut c_void, _: ucp_ep_h, _: ucs_status_t) {
           let closed_flag: Weak<RefCell<bool>> = Weak::from_raw(user_data as _);
@@ -98,6 +310,7 @@ impl Endpoint {
           ptr: ep.assume_init(),
           closed: closed_flag,
           worker,
+          metrics: EndpointMetrics::register(Some(addr.to_string())),
       })
   }
 
@@ -111,6 +324,7 @@ impl Endpoint {
               *closed_flag.borrow_mut() = true;
           }
       }
+      let peer = conn_req.client_addr().ok().map(|addr| addr.to_string());
       let ep_params_default = MaybeUninit::uninit();
       let closed_flag = Rc::new(RefCell::new(false));
       let ep_params = ucp_ep_params {
@@ -128,11 +342,56 @@ impl Endpoint {
       };
       let mut ep = MaybeUninit::uninit();
       let status = ucp_ep_create(worker.handle, &ep_params, ep.as_mut_ptr());
+      if let Err(e) = Error::from_status(status) {
+          metrics::connection_rejected();
+          return Err(e);
+      }
+      metrics::connection_accepted();
+      Ok(Self {
+          ptr: ep.assume_init(),
+          closed: closed_flag,
+          worker,
+          metrics: EndpointMetrics::register(peer),
+      })
+  }
+
+  /// Connects to a remote worker by the address it got from [`Worker::address`].
+  ///
+  /// # Safety
+  ///
+  /// `address` must be the bytes of a worker address, usually received out of
+  /// band, e.g. through [`crate::bootstrap`].
+  pub unsafe fn from_worker_address(worker: Rc<Worker>, address: &[u8]) -> Result<Self, Error> {
+      unsafe extern "C" fn err_handler(user_data: *mut c_void, _: ucp_ep_h, _: ucs_status_t) {
+          let closed_flag: Weak<RefCell<bool>> = Weak::from_raw(user_data as _);
+          if let Some(closed_flag) = closed_flag.upgrade() {
+              *closed_flag.borrow_mut() = true;
+          }
+      }
+      let ep_params_default = MaybeUninit::uninit();
+      let closed_flag = Rc::new(RefCell::new(false));
+      let ep_params = ucp_ep_params {
+          field_mask: (ucp_ep_params_field::UCP_EP_PARAM_FIELD_REMOTE_ADDRESS
+              | ucp_ep_params_field::UCP_EP_PARAM_FIELD_ERR_HANDLING_MODE
+              | ucp_ep_params_field::UCP_EP_PARAM_FIELD_ERR_HANDLER)
+              .0 as u64,
+          address: address.as_ptr() as _,
+          err_mode: ucx1_sys::ucp_err_handling_mode_t::UCP_ERR_HANDLING_MODE_PEER,
+          err_handler: ucp_err_handler {
+              cb: Some(err_handler),
+              arg: Rc::downgrade(&closed_flag).as_ptr() as _,
+          },
+          ..ep_params_default.assume_init()
+      };
+      let mut ep = MaybeUninit::uninit();
+      let status = ucp_ep_create(worker.handle, &ep_params, ep.as_mut_ptr());
+      debug!("from_worker_address length: {} status: {:?}", address.len(), status);
       Error::from_status(status)?;
       Ok(Self {
           ptr: ep.assume_init(),
           closed: closed_flag,
           worker,
+          metrics: EndpointMetrics::register(None),
       })
   }
 
@@ -171,7 +430,7 @@ impl Endpoint {
           tag,
           &params,
       );
-      StatusPtr { ptr }
+      StatusPtr::new(ptr, Operation::TagSend).counting_sent(&self.metrics, buffer.as_ref().len())
   }
 
   pub unsafe fn tag_recv<C: Fn(ucs_status_t)>(
@@ -212,27 +471,335 @@ impl Endpoint {
           tag_mask,
           &params,
       );
-      StatusPtr { ptr }
+      StatusPtr::new(ptr, Operation::TagRecv)
+  }
+
+  /// Sends `value` through the pack callbacks of a generic `datatype`.
+  ///
+  /// # Safety
+  ///
+  /// `value` must stay alive and unmodified until the returned request completes.
+  pub unsafe fn tag_send_generic<T: UcxPack, C: Fn(ucs_status_t)>(
+      &self,
+      tag: u64,
+      value: &T,
+      datatype: &Datatype<T>,
+      callback: Weak<C>,
+  ) -> StatusPtr {
+      unsafe extern "C" fn cb<C: Fn(ucs_status_t)>(
+          _: *mut c_void,
+          status: ucs_status_t,
+          user_data: *mut c_void,
+      ) {
+          let state: Weak<C> = Weak::from_raw(user_data as _);
+          if let Some(callback) = state.upgrade() {
+              (callback)(status)
+          }
+      }
+      let params_default = MaybeUninit::uninit();
+      let params = ucp_request_param_t {
+          op_attr_mask: (ucp_op_attr_t::UCP_OP_ATTR_FIELD_CALLBACK as u32
+              | ucp_op_attr_t::UCP_OP_ATTR_FIELD_USER_DATA as u32
+              | ucp_op_attr_t::UCP_OP_ATTR_FIELD_DATATYPE as u32),
+          cb: ucp_request_param_t__bindgen_ty_1 {
+              send: Some(cb::<C>),
+          },
+          user_data: callback.as_ptr() as _,
+          datatype: datatype.handle,
+          ..params_default.assume_init()
+      };
+      if !datatype.can_send {
+          return StatusPtr::new(unsupported(), Operation::TagSend);
+      }
+      let ptr = ucp_tag_send_nbx(self.ptr, value as *const T as _, 1, tag, &params);
+      StatusPtr::new(ptr, Operation::TagSend).counting_sent(&self.metrics, value.packed_size())
+  }
+
+  /// Receives a message into `value` through the unpack callbacks of a generic `datatype`.
+  ///
+  /// UCX cannot report an error of [`UcxUnpack::finish`], so only waiting on
+  /// the request returns it; `callback` is called with the status of UCX.
+  ///
+  /// # Safety
+  ///
+  /// `value` must not be touched until the returned request completes.
+  pub unsafe fn tag_recv_generic<T: UcxUnpack, C: Fn(ucs_status_t)>(
+      &self,
+      value: &mut T,
+      datatype: &Datatype<T>,
+      tag: u64,
+      tag_mask: u64,
+      callback: Weak<C>,
+  ) -> StatusPtr {
+      unsafe extern "C" fn cb<C: Fn(ucs_status_t)>(
+          _: *mut c_void,
+          status: ucs_status_t,
+          _: *const ucp_tag_recv_info,
+          user_data: *mut c_void,
+      ) {
+          let callback: Weak<C> = Weak::from_raw(user_data as _);
+          if let Some(callback) = callback.upgrade() {
+              (callback)(status)
+          }
+      }
+      let params_default = MaybeUninit::uninit();
+      let params = ucp_request_param_t {
+          op_attr_mask: (ucp_op_attr_t::UCP_OP_ATTR_FIELD_CALLBACK as u32
+              | ucp_op_attr_t::UCP_OP_ATTR_FIELD_USER_DATA as u32
+              | ucp_op_attr_t::UCP_OP_ATTR_FIELD_DATATYPE as u32),
+          datatype: datatype.handle,
+          cb: ucp_request_param_t__bindgen_ty_1 {
+              recv: Some(cb::<C>),
+          },
+          user_data: callback.as_ptr() as _,
+          ..params_default.assume_init()
+      };
+      if !datatype.can_recv {
+          return StatusPtr::new(unsupported(), Operation::TagRecv);
+      }
+      let ptr = ucp_tag_recv_nbx(
+          self.worker.handle,
+          value as *mut T as _,
+          1,
+          tag,
+          tag_mask,
+          &params,
+      );
+      StatusPtr::new(ptr, Operation::TagRecv).checking_unpack(datatype, value)
   }
 }
 
-impl Drop for Endpoint {
-  fn drop(&mut self) {
-      if !*self.closed.borrow() {
-          unsafe {
-              let req_params_default = MaybeUninit::uninit();
-              let req_params = ucp_request_param_t {
-                  op_attr_mask: ucp_op_attr_t::UCP_OP_ATTR_FIELD_FLAGS as u32,
-                  flags: ucp_ep_close_flags_t::UCP_EP_CLOSE_FLAG_FORCE.0,
-                  ..req_params_default.assume_init()
-              };
-              let status = ucp_ep_close_nbx(self.ptr, &req_params);
-              let status = StatusPtr { ptr: status };
-              if let Err(e) = status.wait(&self.worker) {
-                  error!("{e}");
-              }
+impl Endpoint {
+  /// Serializes `msg` with the [`DefaultCodec`] and sends it with `tag`, blocking until done.
+  pub fn send_msg<T: Serialize + ?Sized>(&self, tag: u64, msg: &T) -> Result<(), Error> {
+      self.send_msg_with::<DefaultCodec, T>(tag, msg)
+  }
+
+  /// Receives a message matching `tag`/`tag_mask` and deserializes it with the [`DefaultCodec`].
+  ///
+  /// Tag matching is done on the worker, so the message may come from any
+  /// endpoint of the worker.
+  pub fn recv_msg<T: DeserializeOwned>(&self, tag: u64, tag_mask: u64) -> Result<T, Error> {
+      self.recv_msg_with::<DefaultCodec, T>(tag, tag_mask)
+  }
+
+  /// Like [`Endpoint::send_msg`] with an explicit codec.
+  pub fn send_msg_with<C: Codec, T: Serialize + ?Sized>(&self, tag: u64, msg: &T) -> Result<(), Error> {
+      self.send_msg_with_until::<C, T>(tag, msg, None)
+  }
+
+  /// Like [`Endpoint::recv_msg`] with an explicit codec.
+  ///
+  /// The message is probed first, so the receive buffer always fits the payload.
+  pub fn recv_msg_with<C: Codec, T: DeserializeOwned>(&self, tag: u64, tag_mask: u64) -> Result<T, Error> {
+      self.recv_msg_with_until::<C, T>(tag, tag_mask, None)
+  }
+
+  /// Like [`Endpoint::send_msg_with`], failing with [`Error::Timeout`] if
+  /// the send has not completed by `deadline`.
+  pub fn send_msg_with_until<C: Codec, T: Serialize + ?Sized>(
+      &self,
+      tag: u64,
+      msg: &T,
+      deadline: Option<Instant>,
+  ) -> Result<(), Error> {
+      self.send_bytes_until(tag, &C::encode(msg)?, deadline)
+  }
+
+  /// Like [`Endpoint::recv_msg_with`], failing with [`Error::Timeout`] if
+  /// no message has arrived by `deadline`.
+  pub fn recv_msg_with_until<C: Codec, T: DeserializeOwned>(
+      &self,
+      tag: u64,
+      tag_mask: u64,
+      deadline: Option<Instant>,
+  ) -> Result<T, Error> {
+      let (_, bytes) = self.recv_bytes_until(tag, tag_mask, deadline)?;
+      C::decode(&bytes)
+  }
+
+  /// Like [`Worker::tag_recv_bytes_until`], but fails with
+  /// [`Error::ConnectionReset`] once the endpoint is closed and no matching
+  /// message has arrived.
+  pub fn recv_bytes_until(
+      &self,
+      tag: u64,
+      tag_mask: u64,
+      deadline: Option<Instant>,
+  ) -> Result<(u64, Vec<u8>), Error> {
+      let message = loop {
+          if let Some(message) = self.worker.tag_probe(tag, tag_mask) {
+              break message;
+          }
+          if *self.closed.borrow() {
+              return Err(Error::ConnectionReset);
+          }
+          if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+              return Err(Error::Timeout);
+          }
+          self.worker.progress();
+      };
+      let sender_tag = message.sender_tag;
+      let bytes = self.worker.recv_probed_until(message, deadline)?;
+      self.metrics.bytes_received.add(bytes.len() as u64);
+      Ok((sender_tag, bytes))
+  }
+
+  /// Sends `bytes` with `tag`, blocking until the send completes.
+  pub fn send_bytes(&self, tag: u64, bytes: &[u8]) -> Result<(), Error> {
+      self.send_bytes_until(tag, bytes, None)
+  }
+
+  /// Like [`Endpoint::send_bytes`], but fails with [`Error::Timeout`] at
+  /// `deadline`, force-closing the endpoint if the send is still in flight.
+  pub fn send_bytes_until(&self, tag: u64, bytes: &[u8], deadline: Option<Instant>) -> Result<(), Error> {
+      let status = unsafe { self.tag_send(tag, bytes, Weak::<fn(ucs_status_t)>::new()) };
+      self.wait_until(status, deadline)
+  }
+
+  /// Sends `bytes` on the stream of the endpoint, failing with
+  /// [`Error::Timeout`] if the send has not completed by `deadline`.
+  ///
+  /// Unlike tag messages, stream data is only received by the peer of this
+  /// endpoint, so it can set up a link before the peers have tags of their own.
+  /// The endpoint is force-closed if the send is still in flight at `deadline`.
+  pub fn stream_send_until(&self, bytes: &[u8], deadline: Option<Instant>) -> Result<(), Error> {
+      let status = unsafe {
+          let params_default = MaybeUninit::uninit();
+          let params = ucp_request_param_t {
+              op_attr_mask: ucp_op_attr_t::UCP_OP_ATTR_FIELD_DATATYPE as u32,
+              datatype: ucp_dt_make_contig(1),
+              ..params_default.assume_init()
+          };
+          let ptr = ucp_stream_send_nbx(self.ptr, bytes.as_ptr() as _, bytes.len(), &params);
+          StatusPtr::new(ptr, Operation::StreamSend).counting_sent(&self.metrics, bytes.len())
+      };
+      self.wait_until(status, deadline)
+  }
+
+  /// Fills `buffer` with the next bytes of the stream of the endpoint, failing
+  /// with [`Error::Timeout`] and force-closing the endpoint if they have not
+  /// all arrived by `deadline`.
+  pub fn stream_recv_until(&self, buffer: &mut [u8], deadline: Option<Instant>) -> Result<(), Error> {
+      let mut length = 0;
+      let status = unsafe {
+          let params_default = MaybeUninit::uninit();
+          let params = ucp_request_param_t {
+              op_attr_mask: (ucp_op_attr_t::UCP_OP_ATTR_FIELD_DATATYPE as u32
+                  | ucp_op_attr_t::UCP_OP_ATTR_FIELD_FLAGS as u32),
+              flags: ucp_stream_recv_flags_t::UCP_STREAM_RECV_FLAG_WAITALL.0,
+              datatype: ucp_dt_make_contig(1),
+              ..params_default.assume_init()
+          };
+          let ptr = ucp_stream_recv_nbx(
+              self.ptr,
+              buffer.as_mut_ptr() as _,
+              buffer.len(),
+              &mut length,
+              &params,
+          );
+          StatusPtr::new(ptr, Operation::StreamRecv)
+      };
+      self.wait_until(status, deadline)?;
+      self.metrics.bytes_received.add(buffer.len() as u64);
+      Ok(())
+  }
+
+  /// Like [`Endpoint::tag_send`], passing the registration of `buffer` to UCX
+  /// so the send takes the zero-copy path without registering the memory.
+  ///
+  /// # Safety
+  ///
+  /// `buffer` must stay alive and unmodified until the returned request completes.
+  pub unsafe fn tag_send_buffer<C: Fn(ucs_status_t)>(
+      &self,
+      tag: u64,
+      buffer: &Buffer,
+      callback: Weak<C>,
+  ) -> StatusPtr {
+      unsafe extern "C" fn cb<C: Fn(ucs_status_t)>(
+          _: *mut c_void,
+          status: ucs_status_t,
+          user_data: *mut c_void,
+      ) {
+          let state: Weak<C> = Weak::from_raw(user_data as _);
+          if let Some(callback) = state.upgrade() {
+              (callback)(status)
+          }
+      }
+      let params_default = MaybeUninit::uninit();
+      let params = ucp_request_param_t {
+          op_attr_mask: (ucp_op_attr_t::UCP_OP_ATTR_FIELD_CALLBACK as u32
+              | ucp_op_attr_t::UCP_OP_ATTR_FIELD_USER_DATA as u32
+              | ucp_op_attr_t::UCP_OP_ATTR_FIELD_DATATYPE as u32
+              | ucp_op_attr_t::UCP_OP_ATTR_FIELD_MEMH as u32),
+          cb: ucp_request_param_t__bindgen_ty_1 {
+              send: Some(cb::<C>),
+          },
+          user_data: callback.as_ptr() as _,
+          datatype: ucp_dt_make_contig(1),
+          memh: buffer.memh(),
+          ..params_default.assume_init()
+      };
+      let ptr = ucp_tag_send_nbx(self.ptr, buffer.as_ptr() as _, buffer.len(), tag, &params);
+      StatusPtr::new(ptr, Operation::TagSend).counting_sent(&self.metrics, buffer.len())
+  }
+
+  /// Closes the endpoint without waiting for the peer, failing the requests
+  /// in flight on it. Does nothing if the endpoint is already closed.
+  ///
+  /// UCX keeps an endpoint to an unresponsive peer open until its own
+  /// timeout; this gives up on it immediately.
+  pub fn force_close(&self) {
+      if self.closed.replace(true) {
+          return;
+      }
+      unsafe {
+          let req_params_default = MaybeUninit::uninit();
+          let req_params = ucp_request_param_t {
+              op_attr_mask: ucp_op_attr_t::UCP_OP_ATTR_FIELD_FLAGS as u32,
+              flags: ucp_ep_close_flags_t::UCP_EP_CLOSE_FLAG_FORCE.0,
+              ..req_params_default.assume_init()
+          };
+          let status = ucp_ep_close_nbx(self.ptr, &req_params);
+          let status = StatusPtr::new(status, Operation::EpClose);
+          if let Err(e) = status.wait(&self.worker) {
+              error!("{e}");
           }
       }
+  }
+
+  // Waits for `status`, a request on this endpoint, until `deadline`.
+  //
+  // Sends and stream receives cannot be cancelled, and their buffers are
+  // borrowed by the caller, so one still in progress at the deadline is
+  // completed by force-closing the endpoint before it is freed.
+  fn wait_until(&self, mut status: StatusPtr, deadline: Option<Instant>) -> Result<(), Error> {
+      match status.wait_or_expire(&self.worker, deadline) {
+          Some(result) => result,
+          None => {
+              self.force_close();
+              Err(Error::Timeout)
+          }
+      }
+  }
+
+  /// Sends the bytes in use of `buffer` with `tag`, blocking until the send completes.
+  pub fn send_buffer(&self, tag: u64, buffer: &Buffer) -> Result<(), Error> {
+      self.send_buffer_until(tag, buffer, None)
+  }
+
+  /// Like [`Endpoint::send_buffer`], but fails with [`Error::Timeout`] at
+  /// `deadline`, force-closing the endpoint if the send is still in flight.
+  pub fn send_buffer_until(&self, tag: u64, buffer: &Buffer, deadline: Option<Instant>) -> Result<(), Error> {
+      let status = unsafe { self.tag_send_buffer(tag, buffer, Weak::<fn(ucs_status_t)>::new()) };
+      self.wait_until(status, deadline)
+  }
+}
+
+impl Drop for Endpoint {
+  fn drop(&mut self) {
+      self.force_close();
       // unsafe { ucp_ep_destroy(self.ptr) }
   }
 }
\ No newline at end of file
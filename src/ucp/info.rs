@@ -0,0 +1,140 @@
+//! Typed results of the UCP query functions.
+
+use std::ffi::CStr;
+use std::io;
+use std::net::SocketAddr;
+use std::os::raw::c_char;
+use ucx1_sys::*;
+
+/// Attributes of a [`Context`](super::Context), see [`Context::info`](super::Context::info).
+#[derive(Debug, Clone)]
+pub struct ContextInfo {
+    /// The name of the context.
+    pub name: String,
+    /// The size of the private area UCX reserves in front of each request.
+    pub request_size: usize,
+    /// Thread safe level of the context.
+    pub thread_mode: ucs_thread_mode_t,
+    /// The memory types the context can register and access.
+    pub memory_types: Vec<ucs_memory_type>,
+}
+
+/// Attributes of a [`Worker`](super::Worker).
+#[derive(Debug, Clone)]
+pub struct WorkerInfo {
+    /// The name of the worker.
+    pub name: String,
+    /// Thread safe level of the worker.
+    pub thread_mode: ucs_thread_mode_t,
+    /// The length in bytes of the worker address.
+    pub address_length: usize,
+}
+
+/// Attributes of an [`Endpoint`](super::endpoint::Endpoint).
+#[derive(Debug, Clone)]
+pub struct EndpointInfo {
+    /// The name of the endpoint.
+    pub name: String,
+    /// The local address of the connection.
+    ///
+    /// Only endpoints created from a socket address or a connection request have one.
+    pub local_addr: Option<SocketAddr>,
+    /// The address of the peer.
+    ///
+    /// Only endpoints created from a socket address or a connection request have one.
+    pub remote_addr: Option<SocketAddr>,
+    /// The transports the endpoint uses, one entry per lane.
+    pub transports: Vec<TransportInfo>,
+}
+
+/// A transport used by an endpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TransportInfo {
+    /// The name of the transport, e.g. `rc_mlx5` or `tcp`.
+    pub transport_name: String,
+    /// The name of the device the transport runs on, e.g. `mlx5_0:1` or `eth0`.
+    pub device_name: String,
+}
+
+impl TransportInfo {
+    /// Returns true if the transport only reaches peers on the same node,
+    /// through shared memory or to the process itself.
+    pub fn is_intra_node(&self) -> bool {
+        matches!(
+            self.transport_name.as_str(),
+            "self" | "posix" | "sysv" | "xpmem" | "cma" | "knem" | "cuda_ipc" | "rocm_ipc"
+        )
+    }
+}
+
+impl EndpointInfo {
+    /// Returns true if every lane of the endpoint uses an intra-node
+    /// transport, so no traffic takes the network.
+    pub fn is_intra_node(&self) -> bool {
+        !self.transports.is_empty() && self.transports.iter().all(TransportInfo::is_intra_node)
+    }
+}
+
+const MEMORY_TYPES: [ucs_memory_type; 9] = [
+    ucs_memory_type::UCS_MEMORY_TYPE_HOST,
+    ucs_memory_type::UCS_MEMORY_TYPE_CUDA,
+    ucs_memory_type::UCS_MEMORY_TYPE_CUDA_MANAGED,
+    ucs_memory_type::UCS_MEMORY_TYPE_ROCM,
+    ucs_memory_type::UCS_MEMORY_TYPE_ROCM_MANAGED,
+    ucs_memory_type::UCS_MEMORY_TYPE_RDMA,
+    ucs_memory_type::UCS_MEMORY_TYPE_ZE_HOST,
+    ucs_memory_type::UCS_MEMORY_TYPE_ZE_DEVICE,
+    ucs_memory_type::UCS_MEMORY_TYPE_ZE_MANAGED,
+];
+
+// Expands a bit mask indexed by `ucs_memory_type`.
+pub(crate) fn memory_types(mask: u64) -> Vec<ucs_memory_type> {
+    MEMORY_TYPES
+        .into_iter()
+        .filter(|&ty| mask & (1 << ty as u32) != 0)
+        .collect()
+}
+
+pub(crate) fn name(name: &[c_char]) -> String {
+    // SAFETY: `c_char` and `u8` have the same layout.
+    let bytes = unsafe { &*(name as *const [c_char] as *const [u8]) };
+    CStr::from_bytes_until_nul(bytes)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Reads a string owned by UCX.
+///
+/// # Safety
+///
+/// `ptr` must be null or point to a NUL-terminated string.
+pub(crate) unsafe fn c_string(ptr: *const c_char) -> String {
+    if ptr.is_null() {
+        return String::new();
+    }
+    CStr::from_ptr(ptr).to_string_lossy().into_owned()
+}
+
+/// Runs `print` on an in-memory C stream and copies what it wrote to `out`.
+pub(crate) fn write_dump(
+    out: &mut impl io::Write,
+    print: impl FnOnce(*mut FILE),
+) -> io::Result<()> {
+    let mut buf = std::ptr::null_mut();
+    let mut len = 0;
+    let stream = unsafe { libc::open_memstream(&mut buf, &mut len) };
+    if stream.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    print(stream as *mut FILE);
+    // Closing the stream finalizes `buf` and `len`.
+    let closed = unsafe { libc::fclose(stream) };
+    let result = if closed != 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        let dump = unsafe { std::slice::from_raw_parts(buf as *const u8, len) };
+        out.write_all(dump)
+    };
+    unsafe { libc::free(buf as *mut _) };
+    result
+}
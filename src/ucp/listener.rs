@@ -102,4 +102,15 @@ impl ConnectionRequest {
   unsafe fn from_raw(ptr: *mut ucp_conn_request) -> Self {
       Self { ptr }
   }
+
+  /// The address of the client that sent the request.
+  pub fn client_addr(&self) -> Result<SocketAddr, Error> {
+      let mut attr = MaybeUninit::<ucp_conn_request_attr_t>::uninit();
+      unsafe { &mut *attr.as_mut_ptr() }.field_mask =
+          ucp_conn_request_attr_field::UCP_CONN_REQUEST_ATTR_FIELD_CLIENT_ADDR.0 as u64;
+      let status = unsafe { ucp_conn_request_query(self.ptr, attr.as_mut_ptr()) };
+      Error::from_status(status)?;
+      let attr = unsafe { attr.assume_init() };
+      socket_addr(&attr.client_address).ok_or(Error::InvalidAddr)
+  }
 }
@@ -0,0 +1,152 @@
+//! Memory registration.
+
+use super::*;
+use std::slice;
+
+/// Memory registered with a [`Context`] by `ucp_mem_map`.
+///
+/// Passing the handle with an operation on the memory spares UCX from
+/// registering it, or copying it through a bounce buffer, every time. The
+/// memory is unregistered, and freed if UCX allocated it, on drop.
+#[derive(Debug)]
+pub struct MemoryHandle {
+    context: Arc<Context>,
+    handle: ucp_mem_h,
+    address: *mut u8,
+    length: usize,
+}
+
+// The handle is owned by the context, which is thread safe.
+unsafe impl Send for MemoryHandle {}
+unsafe impl Sync for MemoryHandle {}
+
+impl Context {
+    /// Allocates `length` bytes of memory registered with the context.
+    pub fn mem_alloc(self: &Arc<Self>, length: usize) -> Result<MemoryHandle, Error> {
+        self.mem_map(
+            null_mut(),
+            length,
+            ucp_mem_map_flags_t::UCP_MEM_MAP_ALLOCATE.0,
+        )
+    }
+
+    /// Registers the `length` bytes at `address` with the context.
+    ///
+    /// # Safety
+    ///
+    /// The memory must stay allocated until the returned handle is dropped.
+    pub unsafe fn mem_register(
+        self: &Arc<Self>,
+        address: *mut u8,
+        length: usize,
+    ) -> Result<MemoryHandle, Error> {
+        self.mem_map(address as _, length, 0)
+    }
+
+    fn mem_map(
+        self: &Arc<Self>,
+        address: *mut c_void,
+        length: usize,
+        flags: u32,
+    ) -> Result<MemoryHandle, Error> {
+        if length == 0 {
+            return Err(Error::InvalidParam);
+        }
+        #[allow(invalid_value)]
+        #[allow(clippy::uninit_assumed_init)]
+        let params = ucp_mem_map_params_t {
+            field_mask: (ucp_mem_map_params_field::UCP_MEM_MAP_PARAM_FIELD_ADDRESS
+                | ucp_mem_map_params_field::UCP_MEM_MAP_PARAM_FIELD_LENGTH
+                | ucp_mem_map_params_field::UCP_MEM_MAP_PARAM_FIELD_FLAGS)
+                .0 as u64,
+            address,
+            length,
+            flags,
+            ..unsafe { MaybeUninit::uninit().assume_init() }
+        };
+        let mut handle = MaybeUninit::uninit();
+        let status = unsafe { ucp_mem_map(self.handle, &params, handle.as_mut_ptr()) };
+        Error::from_status(status)?;
+        let handle = unsafe { handle.assume_init() };
+
+        // UCX picks the address of the memory it allocates.
+        #[allow(invalid_value)]
+        #[allow(clippy::uninit_assumed_init)]
+        let mut attr = ucp_mem_attr_t {
+            field_mask: (ucp_mem_attr_field::UCP_MEM_ATTR_FIELD_ADDRESS
+                | ucp_mem_attr_field::UCP_MEM_ATTR_FIELD_LENGTH)
+                .0 as u64,
+            ..unsafe { MaybeUninit::uninit().assume_init() }
+        };
+        let status = unsafe { ucp_mem_query(handle, &mut attr) };
+        if let Err(e) = Error::from_status(status) {
+            unsafe { ucp_mem_unmap(self.handle, handle) };
+            return Err(e);
+        }
+        Ok(MemoryHandle {
+            context: self.clone(),
+            handle,
+            address: attr.address as _,
+            length: attr.length,
+        })
+    }
+}
+
+impl MemoryHandle {
+    /// The raw handle, to pass as the `memh` of an operation.
+    pub fn handle(&self) -> ucp_mem_h {
+        self.handle
+    }
+
+    /// The start of the registered memory.
+    pub fn address(&self) -> *mut u8 {
+        self.address
+    }
+
+    /// The length of the registered memory.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Returns true if the registration covers no memory.
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Returns true if the registration covers the `length` bytes at `address`.
+    pub fn contains(&self, address: *const u8, length: usize) -> bool {
+        let start = self.address as usize;
+        let address = address as usize;
+        address >= start && address.saturating_add(length) <= start + self.length
+    }
+
+    /// The registered memory.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else, including a transfer in progress, may write to the memory
+    /// while the slice is alive.
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        slice::from_raw_parts(self.address, self.length)
+    }
+
+    /// The registered memory.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else, including a transfer in progress, may access the memory
+    /// while the slice is alive.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn as_mut_slice(&self) -> &mut [u8] {
+        slice::from_raw_parts_mut(self.address, self.length)
+    }
+}
+
+impl Drop for MemoryHandle {
+    fn drop(&mut self) {
+        let status = unsafe { ucp_mem_unmap(self.context.handle, self.handle) };
+        if let Err(e) = Error::from_status(status) {
+            tracing::error!("failed to unmap memory: {e}");
+        }
+    }
+}
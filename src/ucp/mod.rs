@@ -10,14 +10,31 @@ use std::rc::Rc;
 use std::sync::Arc;
 use ucx1_sys::*;
 
+pub mod datatype;
 pub mod endpoint;
+pub mod info;
 pub mod listener;
+pub mod memory;
+pub mod pool;
+pub mod rcache;
 pub mod worker;
 
 use crate::Error;
 
+// Converts an address filled in by UCX.
+pub(crate) fn socket_addr(storage: &sockaddr_storage) -> Option<std::net::SocketAddr> {
+    // SAFETY: both are the C `struct sockaddr_storage`.
+    let storage: libc::sockaddr_storage = unsafe { std::mem::transmute_copy(storage) };
+    let len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    unsafe { socket2::SockAddr::new(storage, len) }.as_socket()
+}
+
 // pub use self::endpoint::*;
 // pub use self::listener::*;
+pub use self::info::{ContextInfo, EndpointInfo, TransportInfo, WorkerInfo};
+pub use self::memory::MemoryHandle;
+pub use self::pool::{Buffer, BufferPool, PoolOptions};
+pub use self::rcache::{Registration, RegistrationCache};
 pub use self::worker::*;
 
 /// The configuration for UCP application context.
@@ -51,6 +68,95 @@ impl Config {
         let title = CString::new("UCP Configuration").expect("Not a valid CStr");
         unsafe { ucp_config_print(self.handle, stderr, title.as_ptr(), flags) };
     }
+
+    /// Writes the configuration printed by [`Config::print_to_stderr`] to `out`.
+    pub fn print_info(&self, out: &mut impl std::io::Write) -> std::io::Result<()> {
+        let flags = ucs_config_print_flags_t::UCS_CONFIG_PRINT_CONFIG
+            | ucs_config_print_flags_t::UCS_CONFIG_PRINT_DOC
+            | ucs_config_print_flags_t::UCS_CONFIG_PRINT_HEADER
+            | ucs_config_print_flags_t::UCS_CONFIG_PRINT_HIDDEN;
+        let title = CString::new("UCP Configuration").expect("Not a valid CStr");
+        info::write_dump(out, |stream| unsafe {
+            ucp_config_print(self.handle, stream, title.as_ptr(), flags)
+        })
+    }
+
+    /// Sets the configuration variable `name`, given without its `UCX_` prefix.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        let name = CString::new(name).map_err(|_| Error::InvalidParam)?;
+        let value = CString::new(value).map_err(|_| Error::InvalidParam)?;
+        let status = unsafe { ucp_config_modify(self.handle, name.as_ptr(), value.as_ptr()) };
+        Error::from_status(status)
+    }
+
+    /// Selects the transports for peers on the same node, see [`IntraNode`].
+    ///
+    /// This sets `TLS`, building on the `UCX_TLS` environment variable.
+    pub fn set_intra_node(&mut self, mode: IntraNode) -> Result<(), Error> {
+        let tls = std::env::var("UCX_TLS").unwrap_or_default();
+        match mode {
+            IntraNode::Default => Ok(()),
+            IntraNode::Prefer => match IntraNode::prefer_tls(&tls) {
+                Some(tls) => self.set("TLS", &tls),
+                None => Ok(()),
+            },
+            IntraNode::Require => self.set("TLS", "sm,self"),
+        }
+    }
+}
+
+/// How a context picks transports for peers on the same node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IntraNode {
+    /// Leaves the transports to the configuration.
+    #[default]
+    Default,
+    /// Makes sure the shared-memory transports are enabled, which UCX then
+    /// prefers to loopback networking for peers on the same node.
+    Prefer,
+    /// Enables only the shared-memory transports, so peers on other nodes
+    /// are unreachable.
+    Require,
+}
+
+impl IntraNode {
+    // Shared-memory transports, by their names and the aliases covering them.
+    const SHARED_MEMORY: [&'static str; 7] = ["sm", "shm", "posix", "sysv", "xpmem", "cma", "knem"];
+
+    // Adds shared memory to a `UCX_TLS` value, or returns None if it is enabled.
+    fn prefer_tls(tls: &str) -> Option<String> {
+        let is_shared_memory = |name: &str| name == "self" || Self::SHARED_MEMORY.contains(&name);
+        if let Some(excluded) = tls.strip_prefix('^') {
+            let kept: Vec<&str> = excluded
+                .split(',')
+                .filter(|name| !is_shared_memory(name))
+                .collect();
+            if kept.len() == excluded.split(',').count() {
+                return None;
+            }
+            return Some(if kept.is_empty() {
+                "all".to_string()
+            } else {
+                format!("^{}", kept.join(","))
+            });
+        }
+        let names: Vec<&str> = tls.split(',').filter(|name| !name.is_empty()).collect();
+        if names.is_empty() || names.contains(&"all") {
+            return None;
+        }
+        if names.contains(&"sm") && names.contains(&"self") {
+            return None;
+        }
+        let rest = names.iter().filter(|&&name| name != "sm" && name != "self");
+        Some(
+            ["sm", "self"]
+                .iter()
+                .chain(rest)
+                .copied()
+                .collect::<Vec<_>>()
+                .join(","),
+        )
+    }
 }
 
 impl Drop for Config {
@@ -128,13 +234,22 @@ impl Context {
         unsafe { ucp_context_print_info(self.handle, stderr) };
     }
 
+    /// Writes the information printed by [`Context::print_to_stderr`] to `out`.
+    pub fn print_info(&self, out: &mut impl std::io::Write) -> std::io::Result<()> {
+        info::write_dump(out, |stream| unsafe {
+            ucp_context_print_info(self.handle, stream)
+        })
+    }
+
     /// Fetches information about the context.
     pub fn query(&self) -> Result<ucp_context_attr, Error> {
         #[allow(invalid_value)]
         #[allow(clippy::uninit_assumed_init)]
         let mut attr = ucp_context_attr {
             field_mask: (ucp_context_attr_field::UCP_ATTR_FIELD_REQUEST_SIZE
-                | ucp_context_attr_field::UCP_ATTR_FIELD_THREAD_MODE)
+                | ucp_context_attr_field::UCP_ATTR_FIELD_THREAD_MODE
+                | ucp_context_attr_field::UCP_ATTR_FIELD_MEMORY_TYPES
+                | ucp_context_attr_field::UCP_ATTR_FIELD_NAME)
                 .0 as u64,
             ..unsafe { MaybeUninit::uninit().assume_init() }
         };
@@ -143,6 +258,17 @@ impl Context {
 
         Ok(attr)
     }
+
+    /// Like [`Context::query`], decoded into a [`ContextInfo`].
+    pub fn info(&self) -> Result<ContextInfo, Error> {
+        let attr = self.query()?;
+        Ok(ContextInfo {
+            name: info::name(&attr.name),
+            request_size: attr.request_size,
+            thread_mode: attr.thread_mode,
+            memory_types: info::memory_types(attr.memory_types),
+        })
+    }
 }
 
 impl Drop for Context {
@@ -176,4 +302,42 @@ extern "C" {
 //     unsafe extern "C" fn cleanup(request: *mut c_void) {
 //         std::ptr::drop_in_place(request as *mut Self)
 //     }
-// }
\ No newline at end of file
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefer(tls: &str) -> Option<String> {
+        IntraNode::prefer_tls(tls)
+    }
+
+    #[test]
+    fn prefer_tls_keeps_lists_with_shared_memory() {
+        assert_eq!(prefer(""), None);
+        assert_eq!(prefer("all"), None);
+        assert_eq!(prefer("rc,all"), None);
+        assert_eq!(prefer("sm,self,rc"), None);
+        assert_eq!(prefer("rc,self,sm"), None);
+    }
+
+    #[test]
+    fn prefer_tls_puts_shared_memory_first() {
+        assert_eq!(prefer("rc,tcp").as_deref(), Some("sm,self,rc,tcp"));
+        assert_eq!(prefer("tcp,sm").as_deref(), Some("sm,self,tcp"));
+        assert_eq!(prefer("self,tcp").as_deref(), Some("sm,self,tcp"));
+        assert_eq!(prefer("posix,rc").as_deref(), Some("sm,self,posix,rc"));
+        // Empty names, as left by stray commas, are dropped.
+        assert_eq!(prefer("rc,,tcp,").as_deref(), Some("sm,self,rc,tcp"));
+    }
+
+    #[test]
+    fn prefer_tls_stops_excluding_shared_memory() {
+        assert_eq!(prefer("^rc"), None);
+        assert_eq!(prefer("^rc,tcp"), None);
+        assert_eq!(prefer("^sm,rc").as_deref(), Some("^rc"));
+        assert_eq!(prefer("^rc,posix,cma").as_deref(), Some("^rc"));
+        assert_eq!(prefer("^sm").as_deref(), Some("all"));
+        assert_eq!(prefer("^shm,self").as_deref(), Some("all"));
+    }
+}
@@ -0,0 +1,227 @@
+//! Pools of registered buffers.
+
+use super::memory::MemoryHandle;
+use super::*;
+use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
+use std::slice;
+use tracing::debug;
+
+/// Sizes the buffers of a [`BufferPool`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolOptions {
+    /// The capacities of the pooled buffers, in increasing order.
+    ///
+    /// A buffer comes from the smallest class that fits the requested length.
+    /// Longer buffers are registered on their own and not pooled.
+    pub size_classes: Vec<usize>,
+    /// The bytes registered at once when a size class runs out of buffers.
+    ///
+    /// A slab holds at least one buffer, however large its class.
+    pub slab_size: usize,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions {
+            size_classes: vec![4 << 10, 64 << 10, 1 << 20, 16 << 20],
+            slab_size: 4 << 20,
+        }
+    }
+}
+
+/// Buffers in memory registered with a [`Context`].
+///
+/// Memory is registered in slabs, which are carved into buffers of the same
+/// size class and kept until the pool is dropped, so sending from or
+/// receiving into a pooled buffer takes the zero-copy path without
+/// registering memory. Dropped buffers go back to the pool.
+#[derive(Debug)]
+pub struct BufferPool {
+    context: Arc<Context>,
+    slab_size: usize,
+    classes: Vec<RefCell<SizeClass>>,
+}
+
+#[derive(Debug)]
+struct SizeClass {
+    size: usize,
+    slabs: Vec<Rc<MemoryHandle>>,
+    // Free buffers, as a slab and the offset of the buffer in it.
+    free: Vec<(Rc<MemoryHandle>, usize)>,
+}
+
+impl BufferPool {
+    /// Creates an empty pool of memory registered with `context`.
+    pub fn new(context: &Arc<Context>, options: PoolOptions) -> Rc<Self> {
+        let mut sizes = options.size_classes;
+        sizes.retain(|&size| size > 0);
+        sizes.sort_unstable();
+        sizes.dedup();
+        Rc::new(BufferPool {
+            context: context.clone(),
+            slab_size: options.slab_size,
+            classes: sizes
+                .into_iter()
+                .map(|size| {
+                    RefCell::new(SizeClass {
+                        size,
+                        slabs: Vec::new(),
+                        free: Vec::new(),
+                    })
+                })
+                .collect(),
+        })
+    }
+
+    /// Takes a buffer of `len` bytes, registering a slab if its size class
+    /// has no free buffer.
+    ///
+    /// The bytes are zeroed when the slab is registered, but hold whatever a
+    /// previous user left in them afterwards.
+    pub fn get(self: &Rc<Self>, len: usize) -> Result<Buffer, Error> {
+        let Some(class) = self.classes.iter().position(|c| c.borrow().size >= len) else {
+            debug!(len, "registering a buffer larger than the size classes");
+            let region = Rc::new(self.context.mem_alloc(len.max(1))?);
+            unsafe { region.address().write_bytes(0, region.len()) };
+            return Ok(Buffer {
+                pool: self.clone(),
+                class: None,
+                capacity: region.len(),
+                region,
+                offset: 0,
+                len,
+            });
+        };
+        let mut size_class = self.classes[class].borrow_mut();
+        if size_class.free.is_empty() {
+            size_class.grow(&self.context, self.slab_size)?;
+        }
+        let (region, offset) = size_class.free.pop().unwrap();
+        Ok(Buffer {
+            pool: self.clone(),
+            class: Some(class),
+            capacity: size_class.size,
+            region,
+            offset,
+            len,
+        })
+    }
+
+    /// The bytes registered for the pooled buffers.
+    pub fn registered_bytes(&self) -> usize {
+        self.classes
+            .iter()
+            .map(|class| {
+                class
+                    .borrow()
+                    .slabs
+                    .iter()
+                    .map(|slab| slab.len())
+                    .sum::<usize>()
+            })
+            .sum()
+    }
+
+    /// The number of buffers of the size class fitting `len` that are free.
+    pub fn available(&self, len: usize) -> usize {
+        self.classes
+            .iter()
+            .find(|class| class.borrow().size >= len)
+            .map_or(0, |class| class.borrow().free.len())
+    }
+}
+
+impl SizeClass {
+    fn grow(&mut self, context: &Arc<Context>, slab_size: usize) -> Result<(), Error> {
+        let count = (slab_size / self.size).max(1);
+        let slab = Rc::new(context.mem_alloc(count * self.size)?);
+        unsafe { slab.address().write_bytes(0, slab.len()) };
+        debug!(size = self.size, count, "registered a slab of buffers");
+        self.free
+            .extend((0..count).rev().map(|i| (slab.clone(), i * self.size)));
+        self.slabs.push(slab);
+        Ok(())
+    }
+}
+
+/// A buffer taken from a [`BufferPool`], returned to it on drop.
+///
+/// Derefs to its first [`Buffer::len`] bytes.
+#[derive(Debug)]
+pub struct Buffer {
+    pool: Rc<BufferPool>,
+    // None for buffers too large for the size classes.
+    class: Option<usize>,
+    region: Rc<MemoryHandle>,
+    offset: usize,
+    capacity: usize,
+    len: usize,
+}
+
+impl Buffer {
+    /// The number of bytes in use.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if no bytes are in use.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of bytes the buffer can hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Sets the number of bytes in use, up to the capacity.
+    pub fn set_len(&mut self, len: usize) -> Result<(), Error> {
+        if len > self.capacity {
+            return Err(Error::BufferTooSmall);
+        }
+        self.len = len;
+        Ok(())
+    }
+
+    /// The registration of the memory of the buffer.
+    pub fn memh(&self) -> ucp_mem_h {
+        self.region.handle()
+    }
+
+    /// The start of the buffer.
+    pub fn as_ptr(&self) -> *mut u8 {
+        unsafe { self.region.address().add(self.offset) }
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_ptr(), self.len) }
+    }
+}
+
+impl AsRef<[u8]> for Buffer {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Some(class) = self.class {
+            self.pool.classes[class]
+                .borrow_mut()
+                .free
+                .push((self.region.clone(), self.offset));
+        }
+    }
+}
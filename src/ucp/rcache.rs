@@ -0,0 +1,380 @@
+//! Caching of memory registrations.
+
+use super::memory::MemoryHandle;
+use super::*;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::os::raw::c_int;
+use std::sync::Mutex;
+use tracing::{debug, warn};
+
+/// The number of invalidations held between two calls into the cache; more
+/// invalidate every registration.
+const INVALIDATION_CAPACITY: usize = 1024;
+
+/// Registrations of user memory, kept for reuse.
+///
+/// Registering memory pins its pages and is expensive, so workloads that
+/// keep transferring the same buffers, like RMA, want to register them once.
+/// The cache registers whole pages and merges registrations that overlap or
+/// touch into one covering them all. Registrations not in use are kept until
+/// the pinned memory exceeds the budget of the cache, then unregistered least
+/// recently used first.
+///
+/// When memory is unmapped, registrations covering it become stale. The cache
+/// subscribes to the unmap events of UCX memory hooks to drop them; where the
+/// hooks are disabled, callers must report freed memory with
+/// [`RegistrationCache::invalidate`].
+#[derive(Debug)]
+pub struct RegistrationCache {
+    context: Arc<Context>,
+    budget: usize,
+    // Registrations by start address; they never overlap.
+    regions: RefCell<BTreeMap<usize, Region>>,
+    pinned: Cell<usize>,
+    tick: Cell<u64>,
+    invalidations: Box<Mutex<Invalidations>>,
+    hooked: bool,
+}
+
+#[derive(Debug)]
+struct Region {
+    handle: Rc<MemoryHandle>,
+    end: usize,
+    last_used: u64,
+}
+
+// Filled by the unmap hook, which must not allocate as it may run inside `free`.
+#[derive(Debug)]
+struct Invalidations {
+    ranges: Vec<(usize, usize)>,
+    overflowed: bool,
+}
+
+/// A cached registration covering the memory passed to
+/// [`RegistrationCache::register`].
+///
+/// The registration is not unregistered while the handle is alive.
+#[derive(Debug, Clone)]
+pub struct Registration {
+    handle: Rc<MemoryHandle>,
+}
+
+impl Registration {
+    /// The registration, which may cover more than the requested memory.
+    pub fn handle(&self) -> &MemoryHandle {
+        &self.handle
+    }
+
+    /// The raw handle, to pass as the `memh` of an operation.
+    pub fn memh(&self) -> ucp_mem_h {
+        self.handle.handle()
+    }
+}
+
+impl RegistrationCache {
+    /// Creates a cache of registrations with `context`, unregistering unused
+    /// ones once more than `budget` bytes are pinned.
+    pub fn new(context: &Arc<Context>, budget: usize) -> Rc<Self> {
+        let mut invalidations = Box::new(Mutex::new(Invalidations {
+            ranges: Vec::with_capacity(INVALIDATION_CAPACITY),
+            overflowed: false,
+        }));
+        let status = unsafe {
+            ucm_set_event_handler(
+                ucm_event_type_t::UCM_EVENT_VM_UNMAPPED as c_int,
+                0,
+                Some(on_unmapped),
+                &mut *invalidations as *mut Mutex<Invalidations> as _,
+            )
+        };
+        let hooked = match Error::from_status(status) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("memory hooks unavailable, freed memory must be invalidated by hand: {e}");
+                false
+            }
+        };
+        Rc::new(RegistrationCache {
+            context: context.clone(),
+            budget,
+            regions: RefCell::new(BTreeMap::new()),
+            pinned: Cell::new(0),
+            tick: Cell::new(0),
+            invalidations,
+            hooked,
+        })
+    }
+
+    /// The bytes pinned by the registrations in the cache.
+    pub fn pinned_bytes(&self) -> usize {
+        self.pinned.get()
+    }
+
+    /// The number of registrations in the cache.
+    pub fn len(&self) -> usize {
+        self.regions.borrow().len()
+    }
+
+    /// Returns true if the cache holds no registration.
+    pub fn is_empty(&self) -> bool {
+        self.regions.borrow().is_empty()
+    }
+
+    /// Returns a registration covering `bytes`, registering the memory unless
+    /// a cached registration already covers it.
+    ///
+    /// Fails with [`Error::ExceedsLimit`] if registering the memory would pin
+    /// more than the budget even after unregistering every unused
+    /// registration.
+    pub fn register(&self, bytes: &[u8]) -> Result<Registration, Error> {
+        if bytes.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        self.apply_invalidations();
+        let tick = self.tick.get() + 1;
+        self.tick.set(tick);
+
+        let page = page_size();
+        let mut start = bytes.as_ptr() as usize & !(page - 1);
+        let mut end = (bytes.as_ptr() as usize + bytes.len()).next_multiple_of(page);
+
+        let mut regions = self.regions.borrow_mut();
+        if let Some((_, region)) = regions.range_mut(..=start).next_back() {
+            if region.end >= end {
+                region.last_used = tick;
+                return Ok(Registration {
+                    handle: region.handle.clone(),
+                });
+            }
+        }
+
+        // Registrations overlapping or touching the memory are merged into a
+        // new one. Those in use stay registered until their handles go.
+        let merged: Vec<usize> = regions
+            .range(..=end)
+            .rev()
+            .take_while(|(_, region)| region.end >= start)
+            .map(|(&region_start, _)| region_start)
+            .collect();
+        for region_start in merged {
+            let region = regions.remove(&region_start).unwrap();
+            self.pinned
+                .set(self.pinned.get() - (region.end - region_start));
+            start = start.min(region_start);
+            end = end.max(region.end);
+        }
+
+        self.evict(&mut regions, end - start)?;
+        let handle = Rc::new(unsafe { self.context.mem_register(start as _, end - start)? });
+        debug!(start, len = end - start, "registered memory");
+        self.pinned.set(self.pinned.get() + (end - start));
+        regions.insert(
+            start,
+            Region {
+                handle: handle.clone(),
+                end,
+                last_used: tick,
+            },
+        );
+        Ok(Registration { handle })
+    }
+
+    /// Drops the registrations covering any of the `len` bytes at `address`,
+    /// which were freed.
+    ///
+    /// Registrations in use stay registered until their handles are dropped,
+    /// but are not handed out again.
+    pub fn invalidate(&self, address: *const u8, len: usize) {
+        let start = address as usize;
+        let end = start.saturating_add(len);
+        let mut regions = self.regions.borrow_mut();
+        let stale: Vec<usize> = regions
+            .range(..end)
+            .rev()
+            .take_while(|(_, region)| region.end > start)
+            .map(|(&region_start, _)| region_start)
+            .collect();
+        for region_start in stale {
+            let region = regions.remove(&region_start).unwrap();
+            self.pinned
+                .set(self.pinned.get() - (region.end - region_start));
+            debug!(start = region_start, "invalidated registration");
+        }
+    }
+
+    /// Unregisters every registration that is not in use.
+    pub fn clear(&self) {
+        self.regions.borrow_mut().retain(|&start, region| {
+            let used = Rc::strong_count(&region.handle) > 1;
+            if !used {
+                self.pinned.set(self.pinned.get() - (region.end - start));
+            }
+            used
+        });
+    }
+
+    // Unregisters unused registrations, least recently used first, until
+    // `len` more bytes fit in the budget.
+    fn evict(&self, regions: &mut BTreeMap<usize, Region>, len: usize) -> Result<(), Error> {
+        while self.pinned.get() + len > self.budget {
+            let lru = regions
+                .iter()
+                .filter(|(_, region)| Rc::strong_count(&region.handle) == 1)
+                .min_by_key(|(_, region)| region.last_used)
+                .map(|(&start, _)| start);
+            let Some(start) = lru else {
+                return Err(Error::ExceedsLimit);
+            };
+            let region = regions.remove(&start).unwrap();
+            self.pinned.set(self.pinned.get() - (region.end - start));
+            debug!(start, "evicted registration");
+        }
+        Ok(())
+    }
+
+    fn apply_invalidations(&self) {
+        // Swapped in under the lock, so that the hook never waits for an
+        // allocation, which could itself unmap memory.
+        let mut ranges = Vec::with_capacity(INVALIDATION_CAPACITY);
+        let overflowed = {
+            let mut invalidations = self.invalidations.lock().unwrap();
+            std::mem::swap(&mut invalidations.ranges, &mut ranges);
+            std::mem::take(&mut invalidations.overflowed)
+        };
+        if overflowed {
+            debug!("too many unmap events, invalidating every registration");
+            let mut regions = self.regions.borrow_mut();
+            for (start, region) in std::mem::take(&mut *regions) {
+                self.pinned.set(self.pinned.get() - (region.end - start));
+            }
+            return;
+        }
+        for (address, len) in ranges {
+            self.invalidate(address as _, len);
+        }
+    }
+}
+
+impl Drop for RegistrationCache {
+    fn drop(&mut self) {
+        if self.hooked {
+            unsafe {
+                ucm_unset_event_handler(
+                    ucm_event_type_t::UCM_EVENT_VM_UNMAPPED as c_int,
+                    Some(on_unmapped),
+                    &*self.invalidations as *const Mutex<Invalidations> as _,
+                )
+            };
+        }
+    }
+}
+
+// Called by UCX memory hooks, on whichever thread unmaps the memory.
+unsafe extern "C" fn on_unmapped(_: ucm_event_type_t, event: *mut ucm_event_t, arg: *mut c_void) {
+    let invalidations = &*(arg as *const Mutex<Invalidations>);
+    let vm = (*event).vm_unmapped;
+    let Ok(mut invalidations) = invalidations.lock() else {
+        return;
+    };
+    if invalidations.ranges.len() < INVALIDATION_CAPACITY {
+        invalidations.ranges.push((vm.address as usize, vm.size));
+    } else {
+        invalidations.overflowed = true;
+    }
+}
+
+fn page_size() -> usize {
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Page `index` of a page aligned buffer of `count` pages.
+    struct Pages(Vec<u8>, usize);
+
+    impl Pages {
+        fn new(count: usize) -> Self {
+            let page = page_size();
+            let buffer = vec![0; (count + 1) * page];
+            let offset = buffer.as_ptr().align_offset(page);
+            Pages(buffer, offset)
+        }
+
+        fn page(&self, index: usize, count: usize) -> &[u8] {
+            let start = self.1 + index * page_size();
+            &self.0[start..start + count * page_size()]
+        }
+    }
+
+    fn same(a: &Registration, b: &Registration) -> bool {
+        Rc::ptr_eq(&a.handle, &b.handle)
+    }
+
+    #[test]
+    fn least_recently_used_registrations_are_evicted_first() {
+        let page = page_size();
+        let context = Context::new().unwrap();
+        let cache = RegistrationCache::new(&context, 2 * page);
+        // Every other page, so that the registrations are not merged.
+        let pages = Pages::new(5);
+
+        let a = cache.register(pages.page(0, 1)).unwrap();
+        drop(cache.register(pages.page(2, 1)).unwrap());
+        assert!(same(&cache.register(&pages.page(0, 1)[1..]).unwrap(), &a));
+        drop(a);
+        assert_eq!(cache.pinned_bytes(), 2 * page);
+
+        // The budget only fits two pages, so the registration of page 2,
+        // used least recently, goes.
+        let a = cache.register(pages.page(0, 1)).unwrap();
+        drop(cache.register(pages.page(4, 1)).unwrap());
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.pinned_bytes(), 2 * page);
+        assert!(same(&cache.register(pages.page(0, 1)).unwrap(), &a));
+    }
+
+    #[test]
+    fn registrations_in_use_count_against_the_budget() {
+        let page = page_size();
+        let context = Context::new().unwrap();
+        let cache = RegistrationCache::new(&context, page);
+        let pages = Pages::new(3);
+
+        assert_eq!(
+            cache.register(pages.page(0, 2)).unwrap_err(),
+            Error::ExceedsLimit
+        );
+        let a = cache.register(pages.page(0, 1)).unwrap();
+        assert_eq!(
+            cache.register(pages.page(2, 1)).unwrap_err(),
+            Error::ExceedsLimit
+        );
+        drop(a);
+        cache.register(pages.page(2, 1)).unwrap();
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.pinned_bytes(), page);
+    }
+
+    #[test]
+    fn touching_registrations_are_merged_and_invalidated() {
+        let page = page_size();
+        let context = Context::new().unwrap();
+        let cache = RegistrationCache::new(&context, 4 * page);
+        let pages = Pages::new(2);
+
+        drop(cache.register(pages.page(0, 1)).unwrap());
+        drop(cache.register(pages.page(1, 1)).unwrap());
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.pinned_bytes(), 2 * page);
+
+        cache.invalidate(pages.page(1, 1).as_ptr(), 1);
+        assert!(cache.is_empty());
+        assert_eq!(cache.pinned_bytes(), 0);
+    }
+}
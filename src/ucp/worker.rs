@@ -1,10 +1,16 @@
 use super::*;
+use super::endpoint::StatusPtr;
+use crate::metrics::{self, Operation};
+use crate::tag::TagSpace;
 use derivative::*;
 use tracing::debug;
 #[cfg(feature = "am")]
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::os::unix::io::AsRawFd;
+use std::any::Any;
+use std::cell::RefCell;
+use std::time::Instant;
 #[cfg(feature = "am")]
 use std::sync::RwLock;
 #[cfg(feature = "event")]
@@ -16,6 +22,10 @@ use tokio::io::unix::AsyncFd;
 pub struct Worker {
     pub(super) handle: ucp_worker_h,
     context: Arc<Context>,
+    tags: TagSpace,
+    // Requests that outlived their deadline, with the buffers they write to.
+    #[derivative(Debug = "ignore")]
+    expired: RefCell<Vec<(StatusPtr, Box<dyn Any>)>>,
     #[cfg(feature = "am")]
     #[derivative(Debug = "ignore")]
     pub(crate) am_streams: RwLock<HashMap<u16, Rc<AmStreamInner>>>,
@@ -23,6 +33,7 @@ pub struct Worker {
 
 impl Drop for Worker {
     fn drop(&mut self) {
+        self.expired.get_mut().clear();
         unsafe { ucp_worker_destroy(self.handle) }
     }
 }
@@ -43,6 +54,8 @@ impl Worker {
         Ok(Rc::new(Worker {
             handle: unsafe { handle.assume_init() },
             context: context.clone(),
+            tags: TagSpace::new(),
+            expired: RefCell::new(Vec::new()),
             #[cfg(feature = "am")]
             am_streams: RwLock::new(HashMap::new()),
         }))
@@ -83,6 +96,34 @@ impl Worker {
         unsafe { ucp_worker_print_info(self.handle, stderr) };
     }
 
+    /// Writes the information printed by [`Worker::print_to_stderr`] to `out`.
+    pub fn print_info(&self, out: &mut impl std::io::Write) -> std::io::Result<()> {
+        info::write_dump(out, |stream| unsafe {
+            ucp_worker_print_info(self.handle, stream)
+        })
+    }
+
+    /// Fetches information about the worker.
+    pub fn query(&self) -> Result<WorkerInfo, Error> {
+        let mut attr = MaybeUninit::<ucp_worker_attr>::uninit();
+        unsafe { &mut *attr.as_mut_ptr() }.field_mask =
+            (ucp_worker_attr_field::UCP_WORKER_ATTR_FIELD_THREAD_MODE
+                | ucp_worker_attr_field::UCP_WORKER_ATTR_FIELD_ADDRESS
+                | ucp_worker_attr_field::UCP_WORKER_ATTR_FIELD_NAME)
+                .0 as u64;
+        let status = unsafe { ucp_worker_query(self.handle, attr.as_mut_ptr()) };
+        Error::from_status(status)?;
+        let attr = unsafe { attr.assume_init() };
+        // The address is only queried for its length.
+        unsafe { ucp_worker_release_address(self.handle, attr.address) };
+
+        Ok(WorkerInfo {
+            name: info::name(&attr.name),
+            thread_mode: attr.thread_mode,
+            address_length: attr.address_length,
+        })
+    }
+
     /// Thread safe level of the context.
     pub fn thread_mode(&self) -> ucs_thread_mode_t {
         let mut attr = MaybeUninit::<ucp_worker_attr>::uninit();
@@ -94,6 +135,16 @@ impl Worker {
         attr.thread_mode
     }
 
+    /// The context the worker was created from.
+    pub fn context(&self) -> &Arc<Context> {
+        &self.context
+    }
+
+    /// The tags reserved by the subsystems sending tag messages on the worker.
+    pub fn tag_space(&self) -> &TagSpace {
+        &self.tags
+    }
+
     /// Get the address of the worker object.
     ///
     /// This address can be passed to remote instances of the UCP library
@@ -154,7 +205,19 @@ impl Worker {
     /// Explicitly progresses all communication operations on a worker.
     pub fn progress(&self) -> u32 {
         // debug!("Worker::progress");
-        unsafe { ucp_worker_progress(self.handle) }
+        let events = unsafe { ucp_worker_progress(self.handle) };
+        metrics::worker_progressed(events);
+        if events != 0 {
+            self.expired
+                .borrow_mut()
+                .retain(|(status, _)| status.status() == ucs_status_t::UCS_INPROGRESS);
+        }
+        events
+    }
+
+    // Keeps `status` and `buffer` until the request completes.
+    pub(super) fn hold_expired(&self, status: StatusPtr, buffer: Box<dyn Any>) {
+        self.expired.borrow_mut().push((status, buffer));
     }
 
     /// Returns a valid file descriptor for polling functions.
@@ -171,6 +234,163 @@ impl Worker {
         let status = unsafe { ucp_worker_flush(self.handle) };
         assert_eq!(status, ucs_status_t::UCS_OK);
     }
+
+    /// Checks for an unexpected message matching `tag` and `tag_mask`.
+    ///
+    /// A matched message is removed from the unexpected queue and must be
+    /// received with [`Worker::tag_msg_recv`].
+    pub fn tag_probe(&self, tag: u64, tag_mask: u64) -> Option<TagMessage> {
+        let mut info = MaybeUninit::<ucp_tag_recv_info>::uninit();
+        let handle = unsafe { ucp_tag_probe_nb(self.handle, tag, tag_mask, 1, info.as_mut_ptr()) };
+        if handle.is_null() {
+            return None;
+        }
+        let info = unsafe { info.assume_init() };
+        Some(TagMessage {
+            handle,
+            sender_tag: info.sender_tag,
+            length: info.length,
+        })
+    }
+
+    /// Receives a message returned by [`Worker::tag_probe`] into `buffer`.
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must stay alive until the returned request completes.
+    pub unsafe fn tag_msg_recv(&self, message: TagMessage, buffer: &mut [u8]) -> StatusPtr {
+        let params_default = MaybeUninit::uninit();
+        let params = ucp_request_param_t {
+            op_attr_mask: ucp_op_attr_t::UCP_OP_ATTR_FIELD_DATATYPE as u32,
+            datatype: ucp_dt_make_contig(1),
+            ..params_default.assume_init()
+        };
+        let ptr = ucp_tag_msg_recv_nbx(
+            self.handle,
+            buffer.as_mut_ptr() as _,
+            buffer.len(),
+            message.handle,
+            &params,
+        );
+        StatusPtr::new(ptr, Operation::TagRecv)
+    }
+
+    /// Receives a message returned by [`Worker::tag_probe`] into a buffer of its length.
+    pub fn recv_probed(&self, message: TagMessage) -> Result<Vec<u8>, Error> {
+        self.recv_probed_until(message, None)
+    }
+
+    /// Like [`Worker::recv_probed`], but cancels the receive at `deadline`.
+    pub fn recv_probed_until(
+        &self,
+        message: TagMessage,
+        deadline: Option<Instant>,
+    ) -> Result<Vec<u8>, Error> {
+        let mut bytes = vec![0u8; message.length];
+        let status = unsafe { self.tag_msg_recv(message, &mut bytes) };
+        status.wait_holding(self, bytes, deadline)
+    }
+
+    /// Like [`Worker::tag_msg_recv`], passing the registration of `buffer` to UCX.
+    ///
+    /// The message is received into the bytes in use of `buffer`.
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must stay alive until the returned request completes.
+    pub unsafe fn tag_msg_recv_buffer(&self, message: TagMessage, buffer: &mut Buffer) -> StatusPtr {
+        let params_default = MaybeUninit::uninit();
+        let params = ucp_request_param_t {
+            op_attr_mask: (ucp_op_attr_t::UCP_OP_ATTR_FIELD_DATATYPE as u32
+                | ucp_op_attr_t::UCP_OP_ATTR_FIELD_MEMH as u32),
+            datatype: ucp_dt_make_contig(1),
+            memh: buffer.memh(),
+            ..params_default.assume_init()
+        };
+        let ptr = ucp_tag_msg_recv_nbx(
+            self.handle,
+            buffer.as_ptr() as _,
+            buffer.len(),
+            message.handle,
+            &params,
+        );
+        StatusPtr::new(ptr, Operation::TagRecv)
+    }
+
+    /// Receives a message matching `tag`/`tag_mask` into a buffer from `pool`,
+    /// blocking until one arrives.
+    ///
+    /// Returns the tag the message was sent with and the buffer holding it. A
+    /// message the pool has no buffer for is received and dropped.
+    pub fn tag_recv_buffer(
+        &self,
+        tag: u64,
+        tag_mask: u64,
+        pool: &Rc<BufferPool>,
+    ) -> Result<(u64, Buffer), Error> {
+        self.tag_recv_buffer_until(tag, tag_mask, pool, None)
+    }
+
+    /// Like [`Worker::tag_recv_buffer`], but fails with [`Error::Timeout`] if
+    /// the message has not been received by `deadline`.
+    pub fn tag_recv_buffer_until(
+        &self,
+        tag: u64,
+        tag_mask: u64,
+        pool: &Rc<BufferPool>,
+        deadline: Option<Instant>,
+    ) -> Result<(u64, Buffer), Error> {
+        let message = loop {
+            if let Some(message) = self.tag_probe(tag, tag_mask) {
+                break message;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(Error::Timeout);
+            }
+            self.progress();
+        };
+        let sender_tag = message.sender_tag;
+        let mut buffer = match pool.get(message.length) {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                // Probed messages stay queued until received, so it is taken
+                // off the queue all the same.
+                let _ = self.recv_probed_until(message, deadline);
+                return Err(e);
+            }
+        };
+        let status = unsafe { self.tag_msg_recv_buffer(message, &mut buffer) };
+        let buffer = status.wait_holding(self, buffer, deadline)?;
+        Ok((sender_tag, buffer))
+    }
+
+    /// Receives a whole message matching `tag`/`tag_mask`, blocking until one arrives.
+    ///
+    /// Returns the tag the message was sent with and its payload.
+    pub fn tag_recv_bytes(&self, tag: u64, tag_mask: u64) -> Result<(u64, Vec<u8>), Error> {
+        self.tag_recv_bytes_until(tag, tag_mask, None)
+    }
+
+    /// Like [`Worker::tag_recv_bytes`], but fails with [`Error::Timeout`] if
+    /// the message has not been received by `deadline`.
+    pub fn tag_recv_bytes_until(
+        &self,
+        tag: u64,
+        tag_mask: u64,
+        deadline: Option<Instant>,
+    ) -> Result<(u64, Vec<u8>), Error> {
+        let message = loop {
+            if let Some(message) = self.tag_probe(tag, tag_mask) {
+                break message;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(Error::Timeout);
+            }
+            self.progress();
+        };
+        let sender_tag = message.sender_tag;
+        Ok((sender_tag, self.recv_probed_until(message, deadline)?))
+    }
 }
 
 impl AsRawFd for Worker {
@@ -179,6 +399,16 @@ impl AsRawFd for Worker {
     }
 }
 
+/// An unexpected tag message removed from the queue by [`Worker::tag_probe`].
+#[derive(Debug)]
+pub struct TagMessage {
+    handle: ucp_tag_message_h,
+    /// The tag the message was sent with.
+    pub sender_tag: u64,
+    /// The length of the message payload in bytes.
+    pub length: usize,
+}
+
 /// The address of the worker object.
 #[derive(Debug)]
 pub struct WorkerAddress<'a> {
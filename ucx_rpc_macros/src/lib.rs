@@ -0,0 +1,423 @@
+//! Procedural macros for `ucx_rpc`.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, FnArg, GenericArgument, Ident, ItemTrait, LitStr, PathArguments, ReturnType,
+    TraitItem, Type,
+};
+
+/// Defines an RPC service from a trait and generates its client and server stubs.
+///
+/// Every method takes `&self` and a single request argument and returns
+/// `Result<Response, E>`, where both types are serde-serializable and
+/// `E: From<ucx_rpc::Error>`. Methods may be `async`.
+///
+/// Streaming methods are declared with the stream types of `ucx_rpc::rpc`
+/// in place of the request or response:
+///
+/// * `fn f(&self, request: A) -> Responses<B, E>` streams responses; the
+///   client method returns a `ResponseStream<B, E>`,
+/// * `async fn f(&self, requests: RequestStream<A>) -> Result<B, E>` streams
+///   requests; the client method takes no request and returns a
+///   `ClientStreaming<A, B, E>`,
+/// * `fn f(&self, requests: RequestStream<A>) -> Responses<B, E>` streams
+///   both ways; the client method returns a `BidiStreaming<A, B, E>`.
+///
+/// The server polls streaming handlers rather than waiting for them, so
+/// methods reading a `RequestStream` await its items, and the `Responses`
+/// streams cannot borrow `self`.
+///
+/// For `trait Foo` this generates:
+///
+/// * `foo_methods`, a module with one `u32` method id constant per method,
+/// * `FooClient`, wrapping a `ucx_rpc::rpc::Client` with one method per RPC,
+/// * `FooServer<T: Foo>`, a `ucx_rpc::rpc::Service` dispatching to `T`.
+///
+/// Method ids are a hash of `"<service>/<method>"`, where the service name
+/// defaults to the trait name and can be set with `#[service(name = "...")]`,
+/// so independently compiled clients and servers agree on them.
+#[proc_macro_attribute]
+pub fn service(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut name: Option<LitStr> = None;
+    let attr_parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            name = Some(meta.value()?.parse()?);
+            Ok(())
+        } else {
+            Err(meta.error("unsupported service attribute"))
+        }
+    });
+    parse_macro_input!(attr with attr_parser);
+    let item = parse_macro_input!(item as ItemTrait);
+
+    match expand(name, item) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Unary,
+    ServerStreaming,
+    ClientStreaming,
+    Bidi,
+}
+
+struct Method {
+    ident: Ident,
+    is_async: bool,
+    kind: Kind,
+    request: Type,
+    response: Type,
+    error: Type,
+}
+
+fn expand(name: Option<LitStr>, item: ItemTrait) -> syn::Result<TokenStream2> {
+    if !item.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &item.generics,
+            "service traits cannot be generic",
+        ));
+    }
+
+    let trait_ident = &item.ident;
+    let vis = &item.vis;
+    let service_name = name
+        .map(|name| name.value())
+        .unwrap_or_else(|| trait_ident.to_string());
+
+    let methods = item
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            TraitItem::Fn(method) => Some(parse_method(method)),
+            _ => None,
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    let ids_mod = format_ident!("{}_methods", snake_case(&trait_ident.to_string()));
+    let client_ident = format_ident!("{}Client", trait_ident);
+    let server_ident = format_ident!("{}Server", trait_ident);
+
+    let consts = methods
+        .iter()
+        .map(|m| Ident::new(&m.ident.to_string().to_uppercase(), m.ident.span()))
+        .collect::<Vec<_>>();
+    let ids = methods
+        .iter()
+        .map(|m| method_id(&service_name, &m.ident.to_string()))
+        .collect::<Vec<_>>();
+    let method_names = methods
+        .iter()
+        .map(|m| LitStr::new(&m.ident.to_string(), m.ident.span()))
+        .collect::<Vec<_>>();
+
+    let client_methods = methods.iter().zip(&consts).map(|(m, id)| {
+        let Method {
+            ident,
+            request,
+            response,
+            error,
+            ..
+        } = m;
+        let asyncness = m.is_async.then(|| quote!(async));
+        let full_name = LitStr::new(&format!("{service_name}/{ident}"), ident.span());
+        let into_error = quote! {
+            <#error as ::std::convert::From<::ucx_rpc::Error>>::from
+        };
+        match m.kind {
+            Kind::Unary => quote! {
+                pub #asyncness fn #ident(&self, request: #request) -> ::std::result::Result<#response, #error> {
+                    let options = ::ucx_rpc::rpc::CallOptions::new().with_method(#full_name);
+                    match self
+                        .inner
+                        .call_with::<#request, ::std::result::Result<#response, #error>>(#ids_mod::#id, &request, &options)
+                    {
+                        ::std::result::Result::Ok(result) => result,
+                        ::std::result::Result::Err(e) => ::std::result::Result::Err(#into_error(e)),
+                    }
+                }
+            },
+            Kind::ServerStreaming => quote! {
+                pub #asyncness fn #ident(
+                    &self,
+                    request: #request,
+                ) -> ::std::result::Result<::ucx_rpc::rpc::ResponseStream<#response, #error>, #error> {
+                    self.inner
+                        .server_streaming::<#request, #response, #error>(#ids_mod::#id, &request)
+                        .map_err(#into_error)
+                }
+            },
+            Kind::ClientStreaming => quote! {
+                pub #asyncness fn #ident(
+                    &self,
+                ) -> ::std::result::Result<::ucx_rpc::rpc::ClientStreaming<#request, #response, #error>, #error> {
+                    self.inner
+                        .client_streaming::<#request, #response, #error>(#ids_mod::#id)
+                        .map_err(#into_error)
+                }
+            },
+            Kind::Bidi => quote! {
+                pub #asyncness fn #ident(
+                    &self,
+                ) -> ::std::result::Result<::ucx_rpc::rpc::BidiStreaming<#request, #response, #error>, #error> {
+                    self.inner
+                        .bidi_streaming::<#request, #response, #error>(#ids_mod::#id)
+                        .map_err(#into_error)
+                }
+            },
+        }
+    });
+
+    let block_on = |m: &Method, call: TokenStream2| {
+        if m.is_async {
+            quote!(::ucx_rpc::rpc::__private::block_on(#call))
+        } else {
+            call
+        }
+    };
+
+    let dispatch_arms = methods
+        .iter()
+        .zip(&consts)
+        .filter(|(m, _)| m.kind == Kind::Unary)
+        .map(|(m, id)| {
+            let Method { ident, request, .. } = m;
+            let call = block_on(m, quote!(self.0.#ident(request)));
+            quote! {
+                #ids_mod::#id => {
+                    let request: #request = <::ucx_rpc::codec::DefaultCodec as ::ucx_rpc::codec::Codec>::decode(payload)?;
+                    let response = #call;
+                    <::ucx_rpc::codec::DefaultCodec as ::ucx_rpc::codec::Codec>::encode(&response)
+                }
+            }
+        });
+
+    let streaming_arms = methods
+        .iter()
+        .zip(&consts)
+        .filter(|(m, _)| m.kind != Kind::Unary)
+        .map(|(m, id)| {
+            let Method { ident, request, .. } = m;
+            let prelude = match m.kind {
+                Kind::ServerStreaming => quote!(let request: #request = call.request()?;),
+                _ => quote!(let request = call.requests();),
+            };
+            // The futures own the service so that the server can keep them.
+            let handler = match (m.kind, m.is_async) {
+                (Kind::ClientStreaming, _) => quote! {
+                    ::ucx_rpc::rpc::StreamHandler::response(async move { self.0.#ident(request).await })
+                },
+                (_, true) => quote! {
+                    ::ucx_rpc::rpc::StreamHandler::responses(::ucx_rpc::rpc::__private::flatten(
+                        async move { self.0.#ident(request).await },
+                    ))
+                },
+                (_, false) => quote! {
+                    ::ucx_rpc::rpc::StreamHandler::responses(self.0.#ident(request))
+                },
+            };
+            quote! {
+                #ids_mod::#id => {
+                    #prelude
+                    ::std::result::Result::Ok(#handler)
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let call_streaming = (!streaming_arms.is_empty()).then(|| {
+        quote! {
+            fn call_streaming(
+                self: ::std::rc::Rc<Self>,
+                method_id: u32,
+                call: ::ucx_rpc::rpc::StreamCall,
+            ) -> ::std::result::Result<::ucx_rpc::rpc::StreamHandler, ::ucx_rpc::Error> {
+                match method_id {
+                    #(#streaming_arms)*
+                    _ => ::std::result::Result::Err(::ucx_rpc::Error::Unsupported),
+                }
+            }
+        }
+    });
+
+    Ok(quote! {
+        #item
+
+        /// Method ids of the service.
+        #vis mod #ids_mod {
+            #(pub const #consts: u32 = #ids;)*
+        }
+
+        /// Client stub of the service.
+        #[derive(Debug)]
+        #vis struct #client_ident {
+            inner: ::ucx_rpc::rpc::Client,
+        }
+
+        impl #client_ident {
+            pub fn new(inner: ::ucx_rpc::rpc::Client) -> Self {
+                Self { inner }
+            }
+
+            pub fn inner(&self) -> &::ucx_rpc::rpc::Client {
+                &self.inner
+            }
+
+            #(#client_methods)*
+        }
+
+        /// Server adapter dispatching requests to an implementation of the service.
+        #[derive(Debug)]
+        #vis struct #server_ident<T>(pub T);
+
+        impl<T: #trait_ident + 'static> ::ucx_rpc::rpc::Service for #server_ident<T> {
+            fn name(&self) -> &'static str {
+                #service_name
+            }
+
+            fn methods(&self) -> &'static [(u32, &'static str)] {
+                &[#((#ids_mod::#consts, #method_names)),*]
+            }
+
+            fn call(&self, method_id: u32, payload: &[u8]) -> ::std::result::Result<::std::vec::Vec<u8>, ::ucx_rpc::Error> {
+                match method_id {
+                    #(#dispatch_arms)*
+                    _ => ::std::result::Result::Err(::ucx_rpc::Error::Unsupported),
+                }
+            }
+
+            #call_streaming
+        }
+    })
+}
+
+const RETURN_TYPES: &str =
+    "RPC methods must return `Result<Response, Error>` or `Responses<Response, Error>`";
+
+fn parse_method(method: &syn::TraitItemFn) -> syn::Result<Method> {
+    let sig = &method.sig;
+    let mut inputs = sig.inputs.iter();
+    match inputs.next() {
+        Some(FnArg::Receiver(receiver))
+            if receiver.reference.is_some() && receiver.mutability.is_none() => {}
+        _ => {
+            return Err(syn::Error::new_spanned(
+                sig,
+                "RPC methods must take `&self` as the first argument",
+            ))
+        }
+    }
+    let args = inputs
+        .filter_map(|arg| match arg {
+            FnArg::Typed(arg) => Some(&*arg.ty),
+            FnArg::Receiver(_) => None,
+        })
+        .collect::<Vec<_>>();
+    let [arg] = args[..] else {
+        return Err(syn::Error::new_spanned(
+            &sig.inputs,
+            "RPC methods must take a single request or request stream",
+        ));
+    };
+    let output = match &sig.output {
+        ReturnType::Type(_, ty) => ty,
+        ReturnType::Default => return Err(syn::Error::new_spanned(sig, RETURN_TYPES)),
+    };
+    let (streams_responses, (response, error)) = match generic_pair(output, "Responses") {
+        Some(types) => (true, types),
+        None => (
+            false,
+            generic_pair(output, "Result")
+                .ok_or_else(|| syn::Error::new_spanned(output, RETURN_TYPES))?,
+        ),
+    };
+    let (kind, request) =
+        match (stream_item(arg, "RequestStream"), streams_responses) {
+            (Some(item), true) => (Kind::Bidi, item),
+            (Some(_), false) if sig.asyncness.is_none() => return Err(syn::Error::new_spanned(
+                sig,
+                "RPC methods reading a `RequestStream` and returning a `Result` must be `async`",
+            )),
+            (Some(item), false) => (Kind::ClientStreaming, item),
+            (None, true) => (Kind::ServerStreaming, arg.clone()),
+            (None, false) => (Kind::Unary, arg.clone()),
+        };
+
+    Ok(Method {
+        ident: sig.ident.clone(),
+        is_async: sig.asyncness.is_some(),
+        kind,
+        request,
+        response,
+        error,
+    })
+}
+
+// Returns `T` if `ty` is `<wrapper><T>`.
+fn stream_item(ty: &Type, wrapper: &str) -> Option<Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != wrapper {
+        return None;
+    }
+    let PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+    match args.args.first()? {
+        GenericArgument::Type(ty) if args.args.len() == 1 => Some(ty.clone()),
+        _ => None,
+    }
+}
+
+// Returns `A` and `B` if `ty` is `<wrapper><A, B>`.
+fn generic_pair(ty: &Type, wrapper: &str) -> Option<(Type, Type)> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != wrapper {
+        return None;
+    }
+    let PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+    let mut types = args.args.iter().filter_map(|arg| match arg {
+        GenericArgument::Type(ty) => Some(ty.clone()),
+        _ => None,
+    });
+    match (types.next(), types.next(), types.next()) {
+        (Some(first), Some(second), None) => Some((first, second)),
+        _ => None,
+    }
+}
+
+fn snake_case(name: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i != 0 {
+                out.push('_');
+            }
+            out.extend(c.to_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+// Must match `ucx_rpc::rpc::method_id`.
+fn method_id(service: &str, method: &str) -> syn::LitInt {
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in service.bytes().chain(Some(b'/')).chain(method.bytes()) {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    syn::LitInt::new(&format!("{hash:#010x}u32"), Span::call_site())
+}